@@ -27,79 +27,139 @@ impl AlertManager {
         
         for m in measurements {
             // Check latency threshold
-            // Check RTT latency
-            if let Some(rtt) = m.rtt_ms {
-                if rtt > self.config.alerts.latency_threshold_ms {
-                    warn!(
-                        "HIGH LATENCY ALERT: {} -> RTT {:.2}ms (threshold: {:.2}ms)",
-                        m.target, rtt, self.config.alerts.latency_threshold_ms
-                    );
-                    
-                    // Store event in database
-                    let _ = self.db.store_event(
-                        "high_latency",
-                        &m.target,
-                        "warning",
-                        &format!("{} RTT {:.2}ms exceeds threshold {:.2}ms", 
-                                m.target, rtt, self.config.alerts.latency_threshold_ms),
-                        Some(rtt),
-                        Some(self.config.alerts.latency_threshold_ms),
-                    );
-                }
+            // Check RTT latency (loaded RTT of bufferbloat tests is graded below)
+            if let Some(rtt) = m.rtt_ms
+                && m.latency_increase_ms.is_none()
+                && rtt > self.config.alerts.latency_threshold_ms {
+                warn!(
+                    "HIGH LATENCY ALERT: {} -> RTT {:.2}ms (threshold: {:.2}ms)",
+                    m.display_target(), rtt, self.config.alerts.latency_threshold_ms
+                );
+                
+                // Store event in database
+                let _ = self.db.store_event(
+                    "high_latency",
+                    &m.target,
+                    "warning",
+                    &format!("{} RTT {:.2}ms exceeds threshold {:.2}ms", 
+                            m.display_target(), rtt, self.config.alerts.latency_threshold_ms),
+                    Some(rtt),
+                    Some(self.config.alerts.latency_threshold_ms),
+                );
             }
             
             // Check upload latency (server tests only)
-            if let Some(upload) = m.upload_latency_ms {
-                if upload > self.config.alerts.latency_threshold_ms {
-                    warn!(
-                        "HIGH UPLOAD LATENCY ALERT: {} -> Upload {:.2}ms (threshold: {:.2}ms)",
-                        m.target, upload, self.config.alerts.latency_threshold_ms
-                    );
-                    
-                    // Store event in database
-                    let _ = self.db.store_event(
-                        "high_latency",
-                        &m.target,
-                        "warning",
-                        &format!("{} Upload {:.2}ms exceeds threshold {:.2}ms", 
-                                m.target, upload, self.config.alerts.latency_threshold_ms),
-                        Some(upload),
-                        Some(self.config.alerts.latency_threshold_ms),
-                    );
-                }
+            if let Some(upload) = m.upload_latency_ms
+                && upload > self.config.alerts.latency_threshold_ms {
+                warn!(
+                    "HIGH UPLOAD LATENCY ALERT: {} -> Upload {:.2}ms (threshold: {:.2}ms)",
+                    m.display_target(), upload, self.config.alerts.latency_threshold_ms
+                );
+                
+                // Store event in database
+                let _ = self.db.store_event(
+                    "high_latency",
+                    &m.target,
+                    "warning",
+                    &format!("{} Upload {:.2}ms exceeds threshold {:.2}ms", 
+                            m.display_target(), upload, self.config.alerts.latency_threshold_ms),
+                    Some(upload),
+                    Some(self.config.alerts.latency_threshold_ms),
+                );
             }
             
             // Check download latency (server tests only)
-            if let Some(download) = m.download_latency_ms {
-                if download > self.config.alerts.latency_threshold_ms {
+            if let Some(download) = m.download_latency_ms
+                && download > self.config.alerts.latency_threshold_ms {
+                warn!(
+                    "HIGH DOWNLOAD LATENCY ALERT: {} -> Download {:.2}ms (threshold: {:.2}ms)",
+                    m.display_target(), download, self.config.alerts.latency_threshold_ms
+                );
+                
+                // Store event in database
+                let _ = self.db.store_event(
+                    "high_latency",
+                    &m.target,
+                    "warning",
+                    &format!("{} Download {:.2}ms exceeds threshold {:.2}ms", 
+                            m.display_target(), download, self.config.alerts.latency_threshold_ms),
+                    Some(download),
+                    Some(self.config.alerts.latency_threshold_ms),
+                );
+            }
+            
+            // Check jitter (RTT jitter, upload/download jitter for server tests only)
+            let jitters = [
+                ("Jitter", m.jitter_ms),
+                ("Upload jitter", m.upload_jitter_ms),
+                ("Download jitter", m.download_jitter_ms),
+            ];
+            for (label, jitter) in jitters {
+                if let Some(jitter) = jitter
+                    && jitter > self.config.alerts.jitter_threshold_ms {
                     warn!(
-                        "HIGH DOWNLOAD LATENCY ALERT: {} -> Download {:.2}ms (threshold: {:.2}ms)",
-                        m.target, download, self.config.alerts.latency_threshold_ms
+                        "HIGH JITTER ALERT: {} -> {} {:.2}ms (threshold: {:.2}ms)",
+                        m.display_target(), label, jitter, self.config.alerts.jitter_threshold_ms
                     );
                     
                     // Store event in database
                     let _ = self.db.store_event(
-                        "high_latency",
+                        "high_jitter",
                         &m.target,
                         "warning",
-                        &format!("{} Download {:.2}ms exceeds threshold {:.2}ms", 
-                                m.target, download, self.config.alerts.latency_threshold_ms),
-                        Some(download),
-                        Some(self.config.alerts.latency_threshold_ms),
+                        &format!("{} {} {:.2}ms exceeds threshold {:.2}ms",
+                                m.display_target(), label, jitter, self.config.alerts.jitter_threshold_ms),
+                        Some(jitter),
+                        Some(self.config.alerts.jitter_threshold_ms),
                     );
                 }
             }
             
+            // Check bufferbloat (latency increase under load)
+            if let Some(increase) = m.latency_increase_ms
+                && let Some((severity, threshold)) = self.config.thresholds.grade_bufferbloat(increase) {
+                warn!(
+                    "BUFFERBLOAT ALERT: {} -> {} latency +{:.2}ms over idle ({} threshold: {:.2}ms)",
+                    m.display_target(), m.test_type, increase, severity, threshold
+                );
+                
+                // Store event in database
+                let _ = self.db.store_event(
+                    "bufferbloat",
+                    &m.target,
+                    severity,
+                    &format!("{} {} latency +{:.2}ms over idle exceeds {} threshold {:.2}ms",
+                            m.display_target(), m.test_type, increase, severity, threshold),
+                    Some(increase),
+                    Some(threshold),
+                );
+            }
+            
+            // Check for DNS timeouts (the resolver, not necessarily the line)
+            if m.status == "timeout" && m.test_type == "dns" {
+                warn!("DNS TIMEOUT: {}", m.display_target());
+                
+                // Store event in database
+                let _ = self.db.store_event(
+                    "dns_timeout",
+                    &m.target,
+                    "warning",
+                    &format!("{} DNS query timed out", m.display_target()),
+                    None,
+                    None,
+                );
+            }
+            
             // Check for timeouts (packet loss)
-            if m.status == "timeout" {
-                warn!("PACKET LOSS: {} -> timeout", m.target);
+            if m.status == "timeout" && m.test_type != "dns" {
+                warn!("PACKET LOSS: {} -> timeout", m.display_target());
                 
                 // Store event in database
                 let _ = self.db.store_event(
                     "packet_loss",
                     &m.target,
                     "warning",
-                    &format!("{} Packet loss (timeout)", m.target),
+                    &format!("{} Packet loss (timeout)", m.display_target()),
                     None,
                     None,
                 );
@@ -107,7 +167,7 @@ impl AlertManager {
             
             // Check for errors
             if m.status == "error" {
-                warn!("ERROR: {} -> {:?}", m.target, m.error_detail);
+                warn!("ERROR: {} -> {:?}", m.display_target(), m.error_detail);
                 
                 // Store event in database
                 let _ = self.db.store_event(
@@ -22,18 +22,18 @@ pub fn generate_latency_chart(
         anyhow::bail!("No measurements to chart");
     }
     
-    // Group measurements by target
+    // Group measurements by target (and connection type)
     let mut by_target: HashMap<String, Vec<(i64, f64)>> = HashMap::new();
+    let split_by_type = has_several_connection_types(measurements);
     
     for m in measurements {
         // Include both ICMP and server-based tests
-        if (m.test_type == "icmp" || m.test_type == "server_echo") && m.status == "success" {
-            if let Some(rtt) = m.rtt_ms {
-                by_target
-                    .entry(m.target.clone())
-                    .or_insert_with(Vec::new)
-                    .push((m.timestamp, rtt));
-            }
+        if (m.test_type == "icmp" || m.test_type == "server_echo") && m.status == "success"
+            && let Some(rtt) = m.rtt_ms {
+            by_target
+                .entry(series_name(m, split_by_type))
+                .or_default()
+                .push((m.timestamp, rtt));
         }
     }
     
@@ -93,7 +93,7 @@ pub fn generate_latency_chart(
         .draw()?;
     
     // Draw each target
-    let colors = vec![
+    let colors = [
         &BLUE,
         &GREEN,
         &RED,
@@ -197,49 +197,48 @@ pub fn generate_latency_chart(
     
     chart
         .configure_series_labels()
-        .background_style(&WHITE.mix(0.8))
-        .border_style(&BLACK)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
         .label_font(("sans-serif", 18))  // Larger legend font
         .draw()?;
     
     // Draw events if database is available
-    if let Some(database) = db {
-        if let Ok(events) = database.query_events(min_time, max_time) {
-            // Draw event markers
-            for event in events {
-                let event_x = event.timestamp;
-                
-                // Determine color based on event type
-                let color = match event.event_type.as_str() {
-                    "high_latency" => RGBColor(255, 165, 0),      // Orange
-                    "packet_loss" => RGBColor(255, 0, 0),         // Red
-                    "error" => RGBColor(139, 0, 0),               // Dark Red
-                    "ip_change" => RGBColor(46, 134, 222),        // Blue
-                    "gateway_change" => RGBColor(155, 89, 182),   // Purple
-                    _ => RGBColor(136, 136, 136),                 // Gray
-                };
-                
-                // Draw vertical line from top to bottom
-                chart.draw_series(std::iter::once(PathElement::new(
-                    vec![(event_x, y_min), (event_x, y_max)],
-                    ShapeStyle {
-                        color: color.mix(0.3).to_rgba(),
-                        filled: false,
-                        stroke_width: 1,
-                    },
-                )))?;
-                
-                // Draw marker at top
-                chart.draw_series(std::iter::once(Circle::new(
-                    (event_x, y_max - (y_max - y_min) * 0.02),
-                    5,
-                    ShapeStyle {
-                        color: color.mix(0.8).to_rgba(),
-                        filled: true,
-                        stroke_width: 2,
-                    },
-                )))?;
-            }
+    if let Some(database) = db
+        && let Ok(events) = database.query_events(min_time, max_time) {
+        // Draw event markers
+        for event in events {
+            let event_x = event.timestamp;
+            
+            // Determine color based on event type
+            let color = match event.event_type.as_str() {
+                "high_latency" => RGBColor(255, 165, 0),      // Orange
+                "packet_loss" => RGBColor(255, 0, 0),         // Red
+                "error" => RGBColor(139, 0, 0),               // Dark Red
+                "ip_change" => RGBColor(46, 134, 222),        // Blue
+                "gateway_change" => RGBColor(155, 89, 182),   // Purple
+                _ => RGBColor(136, 136, 136),                 // Gray
+            };
+            
+            // Draw vertical line from top to bottom
+            chart.draw_series(std::iter::once(PathElement::new(
+                vec![(event_x, y_min), (event_x, y_max)],
+                ShapeStyle {
+                    color: color.mix(0.3).to_rgba(),
+                    filled: false,
+                    stroke_width: 1,
+                },
+            )))?;
+            
+            // Draw marker at top
+            chart.draw_series(std::iter::once(Circle::new(
+                (event_x, y_max - (y_max - y_min) * 0.02),
+                5,
+                ShapeStyle {
+                    color: color.mix(0.8).to_rgba(),
+                    filled: true,
+                    stroke_width: 2,
+                },
+            )))?;
         }
     }
     
@@ -272,11 +271,10 @@ fn split_into_segments(points: &[(i64, f64)], max_gap_seconds: i64) -> Vec<Vec<(
             let gap = curr_time - prev_time;
             
             // If gap is too large, start a new segment
-            if gap > max_gap_seconds {
-                if !current_segment.is_empty() {
-                    segments.push(current_segment.clone());
-                    current_segment.clear();
-                }
+            if gap > max_gap_seconds
+                && !current_segment.is_empty() {
+                segments.push(current_segment.clone());
+                current_segment.clear();
             }
         }
         
@@ -336,6 +334,23 @@ pub fn generate_latency_chart(
     )
 }
 
+/// Whether measurements were taken over different kinds of connections
+fn has_several_connection_types(measurements: &[Measurement]) -> bool {
+    measurements
+        .iter()
+        .any(|m| m.connection_type != measurements[0].connection_type)
+}
+
+/// Series a measurement belongs to: its target, and its connection type if
+/// the chart compares several (e.g. "8.8.8.8 [wifi]" and "8.8.8.8 [wired]")
+fn series_name(m: &Measurement, split_by_type: bool) -> String {
+    if split_by_type {
+        format!("{} [{}]", m.target, m.connection_type)
+    } else {
+        m.target.clone()
+    }
+}
+
 /// Load and process HTML template for interactive charts
 const INTERACTIVE_TEMPLATE: &str = include_str!("../templates/interactive_chart.html");
 
@@ -365,11 +380,12 @@ pub fn generate_interactive_chart(
     
     let mut series: Vec<SeriesData> = Vec::new();
     
-    // Group measurements by target and collect all metrics
+    // Group measurements by target (and connection type) and collect all metrics
     let mut by_target: HashMap<String, Vec<&Measurement>> = HashMap::new();
+    let split_by_type = has_several_connection_types(measurements);
     for m in measurements {
         if (m.test_type == "icmp" || m.test_type == "server_echo") && m.status == "success" {
-            by_target.entry(m.target.clone()).or_insert_with(Vec::new).push(m);
+            by_target.entry(series_name(m, split_by_type)).or_default().push(m);
         }
     }
     
@@ -531,7 +547,7 @@ pub fn generate_interactive_chart(
                     }
                     json.push('\n');
                 }
-                json.push_str("]");
+                json.push(']');
                 json
             }
             Err(e) => {
@@ -561,7 +577,7 @@ pub fn generate_interactive_chart(
         }
         data_json.push('\n');
     }
-    data_json.push_str("}");
+    data_json.push('}');
     
     // Extract colors from series data
     let colors_json: Vec<String> = series.iter()
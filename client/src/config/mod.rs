@@ -1,8 +1,11 @@
 //! Configuration management
 
 use anyhow::{Context, Result};
+use protocol::packets::BufferbloatDirection;
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
@@ -10,6 +13,10 @@ pub struct Config {
     pub targets: TargetsConfig,
     #[serde(default)]
     pub server: Option<ServerConfig>,
+    #[serde(default)]
+    pub thresholds: ThresholdsConfig,
+    #[serde(default)]
+    pub tests: TestsConfig,
     pub alerts: AlertsConfig,
     pub retention: RetentionConfig,
     pub output: OutputConfig,
@@ -24,6 +31,9 @@ pub struct ServerConfig {
     pub enabled: bool,
     pub host: String,
     pub port: u16,
+    /// Which address of `host` to use if it resolves to IPv4 and IPv6
+    #[serde(default)]
+    pub address_family: AddressFamily,
     pub shared_secret: String,
     #[serde(default)]
     pub client_id: u64,
@@ -31,6 +41,10 @@ pub struct ServerConfig {
     pub knock_retry_attempts: u32,
     #[serde(default = "default_knock_timeout_ms")]
     pub knock_timeout_ms: u64,
+    #[serde(default)]
+    pub port_knocking: bool,
+    #[serde(default = "default_knock_ports")]
+    pub knock_ports: Vec<u16>,
     #[serde(default = "default_true")]
     pub enable_echo_test: bool,
     #[serde(default)]
@@ -41,9 +55,220 @@ pub struct ServerConfig {
     pub enable_bufferbloat_test: bool,
 }
 
+/// IP version used to reach the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    /// First address the resolver returns
+    #[default]
+    Auto,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Auto => true,
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TestsConfig {
+    #[serde(default)]
+    pub throughput: ThroughputTestConfig,
+    #[serde(default)]
+    pub download: DownloadTestConfig,
+    #[serde(default)]
+    pub bufferbloat: BufferbloatTestConfig,
+    #[serde(default)]
+    pub dns: DnsTestConfig,
+}
+
+/// Upload throughput test schedule (requires server.enable_throughput_test)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ThroughputTestConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_small_test_kb")]
+    pub small_test_kb: u64,
+    #[serde(default = "default_small_interval_s")]
+    pub small_interval_s: u64,
+    #[serde(default = "default_medium_test_kb")]
+    pub medium_test_kb: u64,
+    #[serde(default = "default_medium_interval_s")]
+    pub medium_interval_s: u64,
+    #[serde(default = "default_large_test_kb")]
+    pub large_test_kb: u64,
+    #[serde(default = "default_large_interval_s")]
+    pub large_interval_s: u64,  // 0 = disabled
+}
+
+impl Default for ThroughputTestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_true(),
+            small_test_kb: default_small_test_kb(),
+            small_interval_s: default_small_interval_s(),
+            medium_test_kb: default_medium_test_kb(),
+            medium_interval_s: default_medium_interval_s(),
+            large_test_kb: default_large_test_kb(),
+            large_interval_s: default_large_interval_s(),
+        }
+    }
+}
+
+/// Download throughput test (requires server.enable_download_test)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DownloadTestConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_download_test_kb")]
+    pub test_kb: u64,
+    #[serde(default = "default_download_interval_s")]
+    pub interval_s: u64,
+    #[serde(default)]
+    pub rate_kbps: u32,  // 0 = as fast as the server sends
+}
+
+impl Default for DownloadTestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_true(),
+            test_kb: default_download_test_kb(),
+            interval_s: default_download_interval_s(),
+            rate_kbps: 0,
+        }
+    }
+}
+
+/// Bufferbloat test schedule (requires server.enable_bufferbloat_test)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BufferbloatTestConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_bufferbloat_interval_s")]
+    pub interval_s: u64,
+    #[serde(default = "default_bufferbloat_duration_s")]
+    pub test_duration_s: u64,  // per load phase
+    #[serde(default = "default_bufferbloat_test_type")]
+    pub test_type: String,  // "upload", "download" or "both"
+    #[serde(default = "default_bufferbloat_idle_s")]
+    pub idle_duration_s: u64,  // idle baseline and recovery phases
+    #[serde(default = "default_bufferbloat_probe_interval_ms")]
+    pub probe_interval_ms: u64,
+    #[serde(default)]
+    pub rate_kbps: u32,  // 0 = saturate as fast as possible
+}
+
+impl BufferbloatTestConfig {
+    /// Load directions to test, in order
+    pub fn directions(&self) -> Vec<BufferbloatDirection> {
+        match self.test_type.as_str() {
+            "download" => vec![BufferbloatDirection::Download],
+            "both" => vec![BufferbloatDirection::Upload, BufferbloatDirection::Download],
+            _ => vec![BufferbloatDirection::Upload],  // validated in Config::load
+        }
+    }
+}
+
+impl Default for BufferbloatTestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_true(),
+            interval_s: default_bufferbloat_interval_s(),
+            test_duration_s: default_bufferbloat_duration_s(),
+            test_type: default_bufferbloat_test_type(),
+            idle_duration_s: default_bufferbloat_idle_s(),
+            probe_interval_ms: default_bufferbloat_probe_interval_ms(),
+            rate_kbps: 0,
+        }
+    }
+}
+
+/// DNS resolution test schedule
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsTestConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_dns_interval_s")]
+    pub interval_s: u64,
+    /// Resolvers as "ip" or "ip:port", empty = nameservers of /etc/resolv.conf
+    #[serde(default)]
+    pub dns_servers: Vec<String>,
+    #[serde(default = "default_dns_test_domains")]
+    pub test_domains: Vec<String>,
+}
+
+impl DnsTestConfig {
+    /// Addresses of the configured resolvers (port 53 unless given)
+    pub fn servers(&self) -> Result<Vec<SocketAddr>> {
+        self.dns_servers
+            .iter()
+            .map(|server| {
+                server
+                    .parse::<SocketAddr>()
+                    .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .with_context(|| format!("Invalid DNS server address \"{}\"", server))
+            })
+            .collect()
+    }
+}
+
+impl Default for DnsTestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_s: default_dns_interval_s(),
+            dns_servers: Vec::new(),
+            test_domains: default_dns_test_domains(),
+        }
+    }
+}
+
+/// Alert thresholds ([thresholds] section)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ThresholdsConfig {
+    #[serde(default = "default_bufferbloat_warning_ms")]
+    pub bufferbloat_warning_ms: f64,
+    #[serde(default = "default_bufferbloat_critical_ms")]
+    pub bufferbloat_critical_ms: f64,
+}
+
+impl ThresholdsConfig {
+    /// Grade a latency increase under load
+    ///
+    /// Returns the severity and the exceeded threshold, or None if the
+    /// increase is below the warning threshold.
+    pub fn grade_bufferbloat(&self, increase_ms: f64) -> Option<(&'static str, f64)> {
+        if increase_ms > self.bufferbloat_critical_ms {
+            Some(("critical", self.bufferbloat_critical_ms))
+        } else if increase_ms > self.bufferbloat_warning_ms {
+            Some(("warning", self.bufferbloat_warning_ms))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for ThresholdsConfig {
+    fn default() -> Self {
+        Self {
+            bufferbloat_warning_ms: default_bufferbloat_warning_ms(),
+            bufferbloat_critical_ms: default_bufferbloat_critical_ms(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct GeneralConfig {
     pub test_interval_ms: u64,
+    /// Timeout of one ICMP probe, 0 = derived from test_interval_ms
+    #[serde(default)]
+    pub icmp_timeout_ms: u64,
     pub database_path: String,
     pub client_id: String,
     #[serde(default)]
@@ -52,6 +277,21 @@ pub struct GeneralConfig {
     pub connection_type: String,
 }
 
+impl GeneralConfig {
+    /// Timeout of one ICMP probe
+    ///
+    /// Probes must end before the next tick; derived timeouts leave a tenth
+    /// of the interval to spare and stay at most 5 seconds.
+    pub fn icmp_timeout(&self) -> Duration {
+        let timeout_ms = if self.icmp_timeout_ms > 0 {
+            self.icmp_timeout_ms
+        } else {
+            (self.test_interval_ms * 9 / 10).min(5000)
+        };
+        Duration::from_millis(timeout_ms)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TargetsConfig {
     pub isp_gateway: String,
@@ -144,6 +384,10 @@ fn default_knock_timeout_ms() -> u64 {
     2000
 }
 
+fn default_knock_ports() -> Vec<u16> {
+    protocol::KNOCK_SEQUENCE.to_vec()
+}
+
 fn default_true() -> bool {
     true
 }
@@ -160,6 +404,74 @@ fn default_public_ip_service() -> String {
     "https://api.ipify.org".to_string()
 }
 
+fn default_small_test_kb() -> u64 {
+    100
+}
+
+fn default_small_interval_s() -> u64 {
+    10
+}
+
+fn default_medium_test_kb() -> u64 {
+    1000 // 1 MB
+}
+
+fn default_medium_interval_s() -> u64 {
+    60 // 1 minute
+}
+
+fn default_large_test_kb() -> u64 {
+    10000 // 10 MB
+}
+
+fn default_large_interval_s() -> u64 {
+    300 // 5 minutes
+}
+
+fn default_download_test_kb() -> u64 {
+    1000 // 1 MB
+}
+
+fn default_download_interval_s() -> u64 {
+    60 // 1 minute
+}
+
+fn default_bufferbloat_interval_s() -> u64 {
+    300 // 5 minutes
+}
+
+fn default_bufferbloat_duration_s() -> u64 {
+    60
+}
+
+fn default_bufferbloat_test_type() -> String {
+    "upload".to_string()
+}
+
+fn default_bufferbloat_idle_s() -> u64 {
+    5
+}
+
+fn default_bufferbloat_probe_interval_ms() -> u64 {
+    100
+}
+
+fn default_bufferbloat_warning_ms() -> f64 {
+    100.0
+}
+
+fn default_bufferbloat_critical_ms() -> f64 {
+    500.0
+}
+
+fn default_dns_interval_s() -> u64 {
+    10
+}
+
+fn default_dns_test_domains() -> Vec<String> {
+    vec!["google.com".to_string(), "cloudflare.com".to_string(), "github.com".to_string()]
+}
+
 fn default_aggregation_time() -> String {
     "03:00".to_string()
 }
@@ -172,14 +484,38 @@ impl Config {
         let mut config: Config = toml::from_str(&contents)
             .with_context(|| "Failed to parse config file")?;
         
+        if config.general.test_interval_ms == 0 {
+            anyhow::bail!("general.test_interval_ms must be greater than 0");
+        }
+        if config.general.icmp_timeout_ms > config.general.test_interval_ms {
+            anyhow::bail!(
+                "general.icmp_timeout_ms ({}) must not exceed test_interval_ms ({})",
+                config.general.icmp_timeout_ms,
+                config.general.test_interval_ms
+            );
+        }
+        
         // Auto-generate client ID if needed
         if config.general.client_id == "auto" {
             config.general.client_id = generate_client_id();
         }
         
-        // Auto-detect connection type if needed
-        if config.general.connection_type == "auto" && config.general.interfaces.is_empty() {
-            config.general.connection_type = detect_connection_type();
+        let test_type = &config.tests.bufferbloat.test_type;
+        if !matches!(test_type.as_str(), "upload" | "download" | "both") {
+            anyhow::bail!(
+                "Invalid tests.bufferbloat.test_type \"{}\" (expected \"upload\", \"download\" or \"both\")",
+                test_type
+            );
+        }
+        
+        if config.tests.dns.enabled {
+            config.tests.dns.servers().context("Invalid tests.dns.dns_servers")?;
+            if config.tests.dns.test_domains.is_empty() {
+                anyhow::bail!("tests.dns.test_domains must not be empty");
+            }
+            if config.tests.dns.interval_s == 0 {
+                anyhow::bail!("tests.dns.interval_s must be greater than 0");
+            }
         }
         
         Ok(config)
@@ -195,11 +531,3 @@ fn generate_client_id() -> String {
     
     format!("{:016x}", now.as_nanos() & 0xFFFFFFFFFFFFFFFF)
 }
-
-fn detect_connection_type() -> String {
-    // Try to detect interface type based on default route
-    // For Phase 1, we'll just return "unknown"
-    // This will be enhanced in Phase 4
-    "unknown".to_string()
-}
-
@@ -9,6 +9,7 @@ mod analysis;
 mod output;
 mod charts;
 mod network_monitor;
+mod scheduler;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
@@ -207,23 +208,16 @@ async fn run_monitoring(config: &config::Config, quiet: bool, verbose: bool) ->
         info!("Normal mode: Logging 5-minute summaries (starting after 5 seconds)");
     }
     
-    // Initialize database
+    // Initialize database (shared on this task only, so Send/Sync is not required)
+    #[allow(clippy::arc_with_non_send_sync)]
     let db = std::sync::Arc::new(storage::Database::new(&config.general.database_path)?);
     db.initialize()?;
     info!("Database initialized");
     
-    // Initialize gateway monitor if enabled
-    let mut gateway_monitor = if config.monitoring.auto_detect_gateway {
-        let mut monitor = network_monitor::GatewayMonitor::new();
-        // Check immediately on startup
-        let _detected_gateway = monitor.check();
+    let interfaces = testing::TestInterface::from_config(&config.general);
+    if config.monitoring.auto_detect_gateway {
         info!("Gateway monitoring enabled (check interval: {}s)", config.monitoring.gateway_check_interval_sec);
-        Some(monitor)
-    } else {
-        None
-    };
-    
-    let detected_gateway = gateway_monitor.as_ref().and_then(|m| m.get_current_gateway());
+    }
     
     // Initialize public IP monitor
     let mut ip_monitor = if config.monitoring.monitor_public_ip {
@@ -237,52 +231,15 @@ async fn run_monitoring(config: &config::Config, quiet: bool, verbose: bool) ->
         None
     };
     
-    // Initialize ICMP tester with optional gateway
+    // Initialize testers for every interface
     let config_arc = std::sync::Arc::new(config.clone());
-    let mut tester = if let Some(gateway) = detected_gateway {
-        info!("Adding detected gateway {} to test targets", gateway);
-        testing::IcmpTester::new_with_additional_targets(config_arc.clone(), vec![gateway])?
-    } else {
-        testing::IcmpTester::new(config_arc.clone())?
-    };
-    info!("ICMP tester initialized");
-    
-    // Initialize server tester (Phase 2) if enabled
-    let server_tester = if let Some(ref server_config) = config.server {
-        if server_config.enabled {
-            let server_config_arc = std::sync::Arc::new(server_config.clone());
-            match testing::ServerTester::new(
-                server_config_arc,
-                config.general.interfaces.first().cloned().unwrap_or_else(|| "default".to_string()),
-                config.general.connection_type.clone(),
-            ) {
-                Ok(mut st) => {
-                    // Authenticate with server
-                    match st.authenticate() {
-                        Ok(_) => {
-                            info!("Server tester initialized and authenticated");
-                            Some(st)
-                        }
-                        Err(e) => {
-                            error!("Failed to authenticate with server: {}", e);
-                            warn!("Continuing with ICMP-only mode");
-                            None
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to initialize server tester: {}", e);
-                    warn!("Continuing with ICMP-only mode");
-                    None
-                }
-            }
-        } else {
-            info!("Server testing disabled in configuration");
-            None
-        }
-    } else {
-        None
-    };
+    if config.server.as_ref().is_some_and(|server| !server.enabled) {
+        info!("Server testing disabled in configuration");
+    }
+    let mut interface_testers = Vec::new();
+    for interface in interfaces {
+        interface_testers.push(InterfaceTesters::new(config, &config_arc, interface)?);
+    }
     
     // Initialize output
     let output_handle = output::OutputManager::new(config.clone());
@@ -293,9 +250,8 @@ async fn run_monitoring(config: &config::Config, quiet: bool, verbose: bool) ->
     // Start monitoring loop
     info!("Starting monitoring loop (Press Ctrl+C to stop)");
     
-    let mut interval = tokio::time::interval(
-        tokio::time::Duration::from_millis(config.general.test_interval_ms)
-    );
+    let period = std::time::Duration::from_millis(config.general.test_interval_ms);
+    let mut schedule = scheduler::TickSchedule::new(period, std::time::Instant::now());
     
     // For statistics in quiet and normal modes
     let mut hourly_stats = HourlyStats::new();
@@ -310,35 +266,47 @@ async fn run_monitoring(config: &config::Config, quiet: bool, verbose: bool) ->
     let mut last_gateway_check = chrono::Local::now();
     
     // Check public IP immediately on startup
-    if let Some(ref mut monitor) = ip_monitor {
-        if let Some((old_ip, new_ip)) = monitor.check().await {
-            // Store initial public IP detection
-            let message = if let Some(old) = old_ip {
-                format!("Public IP changed: {} -> {}", old, new_ip)
-            } else {
-                format!("Public IP detected: {}", new_ip)
-            };
-            let _ = db.store_event(
-                "ip_change",
-                &new_ip.to_string(),
-                "info",
-                &message,
-                None,
-                None,
-            );
-        }
+    if let Some(ref mut monitor) = ip_monitor
+        && let Some((old_ip, new_ip)) = monitor.check().await {
+        // Store initial public IP detection
+        let message = if let Some(old) = old_ip {
+            format!("Public IP changed: {} -> {}", old, new_ip)
+        } else {
+            format!("Public IP detected: {}", new_ip)
+        };
+        let _ = db.store_event(
+            "ip_change",
+            &new_ip.to_string(),
+            "info",
+            &message,
+            None,
+            None,
+        );
     }
     
-    // Make server_tester mutable for running tests
-    let mut server_tester = server_tester;
-    
     // Calculate next aggregation time
     let mut next_aggregation_time = calculate_next_aggregation_time(&config.retention.aggregation_time);
     
     info!("Next aggregation scheduled for: {}", next_aggregation_time.format("%Y-%m-%d %H:%M:%S"));
     
     loop {
-        interval.tick().await;
+        tokio::time::sleep_until(schedule.deadline().into()).await;
+        let missed_ticks = schedule.start_tick(std::time::Instant::now());
+        if missed_ticks > 0 {
+            let message = format!(
+                "Missed {} test tick(s): tests took longer than test_interval_ms ({}ms)",
+                missed_ticks, config.general.test_interval_ms
+            );
+            warn!("{}", message);
+            let _ = db.store_event(
+                "missed_ticks",
+                "scheduler",
+                "warning",
+                &message,
+                Some(missed_ticks as f64),
+                None,
+            );
+        }
         
         // Check if it's time to run aggregation
         let now = chrono::Local::now();
@@ -358,15 +326,35 @@ async fn run_monitoring(config: &config::Config, quiet: bool, verbose: bool) ->
             info!("Next aggregation scheduled for: {}", next_aggregation_time.format("%Y-%m-%d %H:%M:%S"));
         }
         
-        // Check gateway periodically
-        if let Some(ref mut monitor) = gateway_monitor {
-            let now = chrono::Local::now();
-            let elapsed = (now - last_gateway_check).num_seconds() as u64;
-            
-            if elapsed >= config.monitoring.gateway_check_interval_sec {
-                if let Some((old_gateway, new_gateway)) = monitor.check() {
+        // Check gateways and connection types periodically
+        let now = chrono::Local::now();
+        let elapsed = (now - last_gateway_check).num_seconds() as u64;
+        if elapsed >= config.monitoring.gateway_check_interval_sec {
+            for testers in &mut interface_testers {
+                if let Some(old_type) = testers.refresh_connection_type() {
+                    let label = testers.interface.label();
+                    let message = format!(
+                        "Connection type of {} changed: {} -> {}",
+                        label, old_type, testers.interface.connection_type
+                    );
+                    info!("{}", message);
+                    let _ = db.store_event(
+                        "connection_type_change",
+                        &label,
+                        "info",
+                        &message,
+                        None,
+                        None,
+                    );
+                }
+                
+                let Some(ref mut monitor) = testers.gateway_monitor else {
+                    continue;
+                };
+                
+                for (old_gateway, new_gateway) in monitor.check() {
                     // Update ICMP tester with new gateway
-                    tester.update_gateway(new_gateway);
+                    testers.icmp.update_gateway(&new_gateway);
                     
                     let message = if let Some(old) = old_gateway {
                         format!("Gateway changed: {} -> {} (ISP failover?)", old, new_gateway)
@@ -382,8 +370,8 @@ async fn run_monitoring(config: &config::Config, quiet: bool, verbose: bool) ->
                         None,
                     );
                 }
-                last_gateway_check = now;
             }
+            last_gateway_check = now;
         }
         
         // Check public IP periodically
@@ -413,31 +401,34 @@ async fn run_monitoring(config: &config::Config, quiet: bool, verbose: bool) ->
         
         let mut all_measurements = Vec::new();
         
-        // Run ICMP tests
-        match tester.run_tests().await {
-            Ok(measurements) => {
-                all_measurements.extend(measurements);
-            }
-            Err(e) => {
-                error!("ICMP test failed: {}", e);
-            }
-        }
-        
-        // Run server tests (Phase 2) if available
-        if let Some(ref mut st) = server_tester {
-            match st.run_test() {
+        // DNS tests that are due run alongside the ICMP probes
+        let client_results = futures_util::future::join_all(
+            interface_testers.iter_mut().map(|testers| async move {
+                tokio::join!(testers.icmp.run_tests(), run_dns_test(testers.dns.as_mut()))
+            })
+        ).await;
+        for (icmp_result, dns_measurements) in client_results {
+            match icmp_result {
                 Ok(measurements) => {
                     all_measurements.extend(measurements);
                 }
                 Err(e) => {
-                    error!("Server test failed: {}", e);
-                    // Try to re-authenticate on next iteration
-                    if let Err(auth_err) = st.authenticate() {
-                        error!("Re-authentication failed: {}", auth_err);
-                        // Disable server testing for this session
-                        server_tester = None;
-                        warn!("Server testing disabled due to authentication failure");
-                    }
+                    error!("ICMP test failed: {}", e);
+                }
+            }
+            all_measurements.extend(dns_measurements);
+        }
+        
+        // Collect what the server test threads measured since the last tick
+        for testers in &mut interface_testers {
+            let Some(ref server) = testers.server else {
+                continue;
+            };
+            match server.take_measurements() {
+                Some(measurements) => all_measurements.extend(measurements),
+                None => {
+                    warn!("Server testing disabled on {}", testers.interface.label());
+                    testers.server = None;
                 }
             }
         }
@@ -622,7 +613,7 @@ fn calculate_next_aggregation_time(aggregation_time_str: &str) -> chrono::DateTi
     
     // If target time has already passed today, schedule for tomorrow
     if target_dt <= now {
-        target_dt = target_dt + chrono::Duration::days(1);
+        target_dt += chrono::Duration::days(1);
     }
     
     target_dt
@@ -818,20 +809,230 @@ fn parse_time_range_params(
 
 fn parse_duration(s: &str) -> Result<chrono::Duration> {
     let s = s.trim();
-    if s.ends_with('h') {
-        let hours: i64 = s[..s.len()-1].parse()?;
+    if let Some(hours) = s.strip_suffix('h') {
+        let hours: i64 = hours.parse()?;
         Ok(chrono::Duration::hours(hours))
-    } else if s.ends_with('d') {
-        let days: i64 = s[..s.len()-1].parse()?;
+    } else if let Some(days) = s.strip_suffix('d') {
+        let days: i64 = days.parse()?;
         Ok(chrono::Duration::days(days))
-    } else if s.ends_with('m') {
-        let minutes: i64 = s[..s.len()-1].parse()?;
+    } else if let Some(minutes) = s.strip_suffix('m') {
+        let minutes: i64 = minutes.parse()?;
         Ok(chrono::Duration::minutes(minutes))
     } else {
         anyhow::bail!("Invalid duration format. Use: 24h, 7d, 30m, etc.")
     }
 }
 
+/// Testers of one network interface
+struct InterfaceTesters {
+    interface: testing::TestInterface,
+    icmp: testing::IcmpTester,
+    server: Option<ServerTestThread>,
+    dns: Option<testing::DnsTester>,
+    gateway_monitor: Option<network_monitor::GatewayMonitor>,
+}
+
+impl InterfaceTesters {
+    fn new(
+        config: &config::Config,
+        config_arc: &std::sync::Arc<config::Config>,
+        interface: testing::TestInterface,
+    ) -> Result<Self> {
+        let label = interface.label();
+        info!("Testing on {} (connection type: {})", label, interface.connection_type);
+        
+        // Initialize gateway monitor if enabled, checking immediately
+        let mut gateway_monitor = config.monitoring.auto_detect_gateway
+            .then(|| network_monitor::GatewayMonitor::new(interface.name.clone()));
+        let detected_gateways = gateway_monitor
+            .as_mut()
+            .map(|monitor| {
+                monitor.check();
+                monitor.current_gateways()
+            })
+            .unwrap_or_default();
+        
+        // Initialize ICMP tester with the detected gateways
+        for gateway in &detected_gateways {
+            info!("Adding detected gateway {} to test targets of {}", gateway, label);
+        }
+        let icmp = testing::IcmpTester::new_with_gateways(config_arc.clone(), interface.clone(), detected_gateways)?;
+        info!("ICMP tester initialized for {}", label);
+        
+        // Initialize server tester (Phase 2) if enabled
+        let server = match config.server {
+            Some(ref server_config) if server_config.enabled => {
+                let server_config_arc = std::sync::Arc::new(server_config.clone());
+                match testing::ServerTester::new(server_config_arc, config.tests.clone(), &interface) {
+                    Ok(mut st) => {
+                        // Authenticate with server
+                        match st.authenticate() {
+                            Ok(_) => {
+                                info!("Server tester initialized and authenticated for {}", label);
+                                let period = std::time::Duration::from_millis(config.general.test_interval_ms);
+                                match ServerTestThread::spawn(st, label.clone(), period) {
+                                    Ok(thread) => Some(thread),
+                                    Err(e) => {
+                                        error!("Failed to start server tests on {}: {}", label, e);
+                                        None
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                error!("Failed to authenticate with server on {}: {}", label, e);
+                                warn!("Continuing with ICMP-only mode on {}", label);
+                                None
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to initialize server tester on {}: {}", label, e);
+                        warn!("Continuing with ICMP-only mode on {}", label);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        
+        // Initialize DNS tester if enabled
+        let dns = if config.tests.dns.enabled {
+            match testing::DnsTester::new(config, interface.clone()) {
+                Ok(dns) => {
+                    info!("DNS tester initialized for {}", label);
+                    Some(dns)
+                }
+                Err(e) => {
+                    error!("Failed to initialize DNS tester on {}: {:#}", label, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        
+        Ok(Self {
+            interface,
+            icmp,
+            server,
+            dns,
+            gateway_monitor,
+        })
+    }
+    
+    /// Detect the connection type again, e.g. after the default route moved
+    ///
+    /// Returns the previous type if it changed.
+    fn refresh_connection_type(&mut self) -> Option<String> {
+        let old_type = self.interface.refresh_connection_type()?;
+        let connection_type = self.interface.connection_type.clone();
+        self.icmp.set_connection_type(connection_type.clone());
+        if let Some(ref server) = self.server {
+            server.set_connection_type(connection_type.clone());
+        }
+        if let Some(ref mut dns) = self.dns {
+            dns.set_connection_type(connection_type);
+        }
+        Some(old_type)
+    }
+}
+
+/// Server tests of one interface, on a thread of their own
+///
+/// Bufferbloat tests and large uploads take much longer than a test tick;
+/// this way they don't hold up the ICMP and DNS tests.
+struct ServerTestThread {
+    measurements: std::sync::mpsc::Receiver<Vec<testing::Measurement>>,
+    connection_types: std::sync::mpsc::Sender<String>,
+}
+
+impl ServerTestThread {
+    /// Run the tests of `server` every `period` until it gets disabled
+    fn spawn(server: testing::ServerTester, label: String, period: std::time::Duration) -> Result<Self> {
+        let (measurement_tx, measurements) = std::sync::mpsc::channel();
+        let (connection_types, connection_type_rx) = std::sync::mpsc::channel::<String>();
+        
+        std::thread::Builder::new()
+            .name(format!("server-tests-{}", label))
+            .spawn(move || {
+                let mut server = Some(server);
+                let mut schedule = scheduler::TickSchedule::new(period, std::time::Instant::now());
+                loop {
+                    // Overruns are expected (bufferbloat tests), so missed
+                    // ticks are not reported
+                    std::thread::sleep(schedule.deadline().saturating_duration_since(std::time::Instant::now()));
+                    schedule.start_tick(std::time::Instant::now());
+                    
+                    for connection_type in connection_type_rx.try_iter() {
+                        if let Some(ref mut st) = server {
+                            st.set_connection_type(connection_type);
+                        }
+                    }
+                    
+                    let measurements = run_server_test(&mut server);
+                    // Stop once the monitoring loop is gone or testing got disabled
+                    if measurement_tx.send(measurements).is_err() || server.is_none() {
+                        break;
+                    }
+                }
+            })
+            .context("Failed to spawn server test thread")?;
+        
+        Ok(Self {
+            measurements,
+            connection_types,
+        })
+    }
+    
+    fn set_connection_type(&self, connection_type: String) {
+        let _ = self.connection_types.send(connection_type);
+    }
+    
+    /// Measurements finished since the last call, None once the thread ended
+    fn take_measurements(&self) -> Option<Vec<testing::Measurement>> {
+        let mut measurements = Vec::new();
+        loop {
+            match self.measurements.try_recv() {
+                Ok(batch) => measurements.extend(batch),
+                Err(std::sync::mpsc::TryRecvError::Empty) => return Some(measurements),
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    return (!measurements.is_empty()).then_some(measurements);
+                }
+            }
+        }
+    }
+}
+
+/// Run the server tests of one interface
+fn run_server_test(server: &mut Option<testing::ServerTester>) -> Vec<testing::Measurement> {
+    let Some(st) = server else {
+        return Vec::new();
+    };
+    
+    match st.run_test() {
+        Ok(measurements) => measurements,
+        Err(e) => {
+            error!("Server test failed: {}", e);
+            // Try to re-authenticate on next iteration
+            if let Err(auth_err) = st.authenticate() {
+                error!("Re-authentication failed: {}", auth_err);
+                // Disable server testing for this session
+                *server = None;
+                warn!("Server testing disabled due to authentication failure");
+            }
+            Vec::new()
+        }
+    }
+}
+
+/// Run the DNS tests of one interface if they are due
+async fn run_dns_test(dns: Option<&mut testing::DnsTester>) -> Vec<testing::Measurement> {
+    match dns {
+        Some(dns) if dns.is_due() => dns.run_tests().await,
+        _ => Vec::new(),
+    }
+}
+
 /// Accumulates measurements for hourly statistics in quiet mode
 struct HourlyStats {
     measurements_per_target: std::collections::HashMap<String, TargetStats>,
@@ -840,6 +1041,8 @@ struct HourlyStats {
 }
 
 struct TargetStats {
+    /// Target without interface, as stored with events
+    target: String,
     rtts: Vec<f64>,
     jitters: Vec<f64>,
     packet_loss_count: usize,
@@ -859,9 +1062,16 @@ impl HourlyStats {
         for m in measurements {
             self.total_measurements += 1;
             
+            // DNS tests of a resolver are kept apart from its ICMP probes
+            let key = if m.test_type == "dns" {
+                format!("{} [dns]", m.display_target())
+            } else {
+                m.display_target()
+            };
             let target_stats = self.measurements_per_target
-                .entry(m.target.clone())
+                .entry(key)
                 .or_insert_with(|| TargetStats {
+                    target: m.target.clone(),
                     rtts: Vec::new(),
                     jitters: Vec::new(),
                     packet_loss_count: 0,
@@ -969,7 +1179,7 @@ impl HourlyStats {
         
         for (target, stats) in &self.measurements_per_target {
             let total_tests = stats.success_count + stats.packet_loss_count;
-            let events = event_counts.get(target).copied().unwrap_or(0);
+            let events = event_counts.get(&stats.target).copied().unwrap_or(0);
             
             let line = if !stats.rtts.is_empty() {
                 let mut sorted = stats.rtts.clone();
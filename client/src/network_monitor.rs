@@ -1,15 +1,66 @@
 //! Network monitoring - gateway detection and public IP tracking
 
 use anyhow::{Context, Result};
+use std::fmt;
 use std::net::IpAddr;
+use std::path::Path;
 use std::process::Command;
 use std::str::FromStr;
 use tracing::{debug, info, warn};
 
-/// Detect the default gateway using `ip route` command
-pub fn detect_default_gateway() -> Result<IpAddr> {
+/// A default gateway and the interface it is reached through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gateway {
+    pub ip: IpAddr,
+    pub interface: Option<String>,
+}
+
+impl Gateway {
+    /// Interface index to reach a link-local IPv6 gateway with (0 = none)
+    ///
+    /// IPv6 gateways are usually link-local (fe80::/10) addresses, which
+    /// can't be pinged without knowing the interface.
+    pub fn scope_id(&self) -> u32 {
+        match (&self.ip, &self.interface) {
+            (IpAddr::V6(ip), Some(interface)) if ip.is_unicast_link_local() => {
+                std::fs::read_to_string(format!("/sys/class/net/{}/ifindex", interface))
+                    .ok()
+                    .and_then(|index| index.trim().parse().ok())
+                    .unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.ip, &self.interface) {
+            (IpAddr::V6(ip), Some(interface)) if ip.is_unicast_link_local() => {
+                write!(f, "{}%{}", ip, interface)
+            }
+            _ => write!(f, "{}", self.ip),
+        }
+    }
+}
+
+/// Detect the IPv4 default gateway (of `interface`) using `ip route` command
+pub fn detect_default_gateway(interface: Option<&str>) -> Result<Gateway> {
+    detect_gateway("-4", interface)
+}
+
+/// Detect the IPv6 default gateway (of `interface`) using `ip -6 route` command
+pub fn detect_default_gateway_v6(interface: Option<&str>) -> Result<Gateway> {
+    detect_gateway("-6", interface)
+}
+
+fn detect_gateway(family: &str, interface: Option<&str>) -> Result<Gateway> {
+    let mut args = vec![family, "route", "show", "default"];
+    if let Some(interface) = interface {
+        args.extend(["dev", interface]);
+    }
     let output = Command::new("ip")
-        .args(&["route", "show", "default"])
+        .args(&args)
         .output()
         .context("Failed to execute 'ip route' command")?;
     
@@ -18,24 +69,125 @@ pub fn detect_default_gateway() -> Result<IpAddr> {
     }
     
     let stdout = String::from_utf8_lossy(&output.stdout);
-    debug!("Default route output: {}", stdout);
+    debug!("Default route output ({}): {}", args.join(" "), stdout);
     
-    // Parse output like: "default via 192.168.1.1 dev eth0 proto dhcp metric 100"
-    for line in stdout.lines() {
-        if line.starts_with("default") {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if let Some(via_idx) = parts.iter().position(|&p| p == "via") {
-                if let Some(&gateway_str) = parts.get(via_idx + 1) {
-                    if let Ok(gateway) = IpAddr::from_str(gateway_str) {
-                        debug!("Detected default gateway: {}", gateway);
-                        return Ok(gateway);
-                    }
-                }
-            }
+    let mut gateway = parse_default_route(&stdout)
+        .context("Could not parse default gateway from 'ip route' output")?;
+    // With "dev" given, ip leaves it out of the output
+    if gateway.interface.is_none() {
+        gateway.interface = interface.map(str::to_string);
+    }
+    debug!("Detected default gateway: {}", gateway);
+    Ok(gateway)
+}
+
+/// Find the gateway in `ip route show default` output
+///
+/// Handles lines like "default via 192.168.1.1 dev eth0 proto dhcp metric 100"
+/// and the "nexthop via fe80::1 dev eth0 weight 1" lines of multipath routes.
+fn parse_default_route(output: &str) -> Option<Gateway> {
+    for line in output.lines() {
+        let line = line.trim_start();
+        if !line.starts_with("default") && !line.starts_with("nexthop") {
+            continue;
+        }
+        
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let value_of = |key: &str| {
+            parts
+                .iter()
+                .position(|&p| p == key)
+                .and_then(|idx| parts.get(idx + 1))
+                .copied()
+        };
+        if let Some(gateway_str) = value_of("via")
+            && let Ok(ip) = IpAddr::from_str(gateway_str) {
+            return Some(Gateway {
+                ip,
+                interface: value_of("dev").map(str::to_string),
+            });
         }
     }
     
-    anyhow::bail!("Could not parse default gateway from 'ip route' output")
+    None
+}
+
+/// Detect the connection type of `interface` from sysfs
+///
+/// Returns "wifi", "cellular", "vpn", "wired", "ppp", "loopback" or
+/// "unknown".
+pub fn detect_connection_type(interface: &str) -> String {
+    connection_type_in(Path::new("/sys/class/net"), interface).to_string()
+}
+
+fn connection_type_in(sysfs: &Path, interface: &str) -> &'static str {
+    let dir = sysfs.join(interface);
+    if !dir.exists() {
+        return "unknown";
+    }
+    
+    let uevent = std::fs::read_to_string(dir.join("uevent")).unwrap_or_default();
+    let devtype = uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("DEVTYPE="))
+        .unwrap_or_default();
+    // ARPHRD_* hardware type
+    let arp_type: u32 = std::fs::read_to_string(dir.join("type"))
+        .ok()
+        .and_then(|arp_type| arp_type.trim().parse().ok())
+        .unwrap_or(0);
+    
+    if dir.join("wireless").exists() || dir.join("phy80211").exists() || devtype == "wlan" {
+        "wifi"
+    } else if devtype == "wwan" || interface.starts_with("ww") || interface.starts_with("usb") {
+        // wwan0, wwp0s20u4 (systemd naming), usb0 (USB tethering)
+        "cellular"
+    } else if dir.join("tun_flags").exists() || devtype == "wireguard" {
+        // tun/tap devices have tun_flags
+        "vpn"
+    } else {
+        match arp_type {
+            1 => "wired",
+            512 => "ppp",
+            772 => "loopback",
+            // ARPHRD_NONE: layer 3 tunnels (tun, wireguard)
+            65534 => "vpn",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Find an address of `interface` to use as source address
+///
+/// Global addresses are preferred over link-local ones.
+pub fn interface_address(interface: &str, ipv6: bool) -> Result<IpAddr> {
+    let family = if ipv6 { "-6" } else { "-4" };
+    let output = Command::new("ip")
+        .args(["-o", family, "addr", "show", "dev", interface])
+        .output()
+        .context("Failed to execute 'ip addr' command")?;
+    
+    if !output.status.success() {
+        anyhow::bail!("Failed to get addresses of {}", interface);
+    }
+    
+    // Parse lines like: "2: eth0    inet 192.168.1.20/24 brd 192.168.1.255 scope global eth0 ..."
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut addresses: Vec<IpAddr> = stdout
+        .lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let idx = parts.iter().position(|&p| p == "inet" || p == "inet6")?;
+            let (address, _prefix) = parts.get(idx + 1)?.split_once('/')?;
+            IpAddr::from_str(address).ok()
+        })
+        .collect();
+    addresses.sort_by_key(|ip| matches!(ip, IpAddr::V6(ip) if ip.is_unicast_link_local()));
+    
+    addresses
+        .first()
+        .copied()
+        .with_context(|| format!("{} has no {} address", interface, if ipv6 { "IPv6" } else { "IPv4" }))
 }
 
 /// Get public IP address from external service
@@ -70,46 +222,64 @@ pub async fn get_public_ip(service_url: &str) -> Result<IpAddr> {
     Ok(ip)
 }
 
-/// Gateway monitor that tracks changes
+/// Gateway monitor that tracks changes of the IPv4 and IPv6 default gateway
 pub struct GatewayMonitor {
-    current_gateway: Option<IpAddr>,
+    /// Only watch default routes through this interface
+    interface: Option<String>,
+    current_gateway: Option<Gateway>,
+    current_gateway_v6: Option<Gateway>,
 }
 
 impl GatewayMonitor {
-    pub fn new() -> Self {
+    pub fn new(interface: Option<String>) -> Self {
         Self {
+            interface,
             current_gateway: None,
+            current_gateway_v6: None,
         }
     }
     
-    /// Check gateway and detect changes
-    /// Returns Some(old_gateway, new_gateway) if gateway changed, None if unchanged or error
-    pub fn check(&mut self) -> Option<(Option<IpAddr>, IpAddr)> {
-        match detect_default_gateway() {
-            Ok(new_gateway) => {
-                if self.current_gateway.as_ref() != Some(&new_gateway) {
-                    let old_gateway = self.current_gateway;
-                    self.current_gateway = Some(new_gateway);
-                    
-                    if let Some(old) = old_gateway {
-                        info!("Gateway changed: {} -> {} (ISP failover?)", old, new_gateway);
-                    } else {
-                        info!("Initial gateway detected: {}", new_gateway);
-                    }
-                    
-                    return Some((old_gateway, new_gateway));
-                }
-                None
-            }
-            Err(e) => {
-                warn!("Failed to check gateway: {}", e);
-                None
-            }
+    /// Check gateways and detect changes
+    /// Returns (old_gateway, new_gateway) for each address family whose gateway changed
+    pub fn check(&mut self) -> Vec<(Option<Gateway>, Gateway)> {
+        let mut changes = Vec::new();
+        let interface = self.interface.as_deref();
+        
+        match detect_default_gateway(interface) {
+            Ok(new_gateway) => changes.extend(Self::update(&mut self.current_gateway, new_gateway)),
+            Err(e) => warn!("Failed to check gateway: {}", e),
+        }
+        
+        // Many networks have no IPv6 at all, so a missing route is no warning
+        match detect_default_gateway_v6(interface) {
+            Ok(new_gateway) => changes.extend(Self::update(&mut self.current_gateway_v6, new_gateway)),
+            Err(e) => debug!("No IPv6 gateway: {}", e),
+        }
+        
+        changes
+    }
+    
+    fn update(current: &mut Option<Gateway>, new_gateway: Gateway) -> Option<(Option<Gateway>, Gateway)> {
+        if current.as_ref() == Some(&new_gateway) {
+            return None;
         }
+        
+        let old_gateway = current.replace(new_gateway.clone());
+        if let Some(old) = &old_gateway {
+            info!("Gateway changed: {} -> {} (ISP failover?)", old, new_gateway);
+        } else {
+            info!("Initial gateway detected: {}", new_gateway);
+        }
+        Some((old_gateway, new_gateway))
     }
     
-    pub fn get_current_gateway(&self) -> Option<IpAddr> {
+    /// Current IPv4 and IPv6 gateways, as far as detected
+    pub fn current_gateways(&self) -> Vec<Gateway> {
         self.current_gateway
+            .iter()
+            .chain(self.current_gateway_v6.iter())
+            .cloned()
+            .collect()
     }
 }
 
@@ -167,12 +337,63 @@ mod tests {
     #[test]
     fn test_detect_gateway() {
         // This test only runs on systems with ip route
-        if let Ok(gateway) = detect_default_gateway() {
+        if let Ok(gateway) = detect_default_gateway(None) {
             println!("Detected gateway: {}", gateway);
-            assert!(gateway.is_ipv4() || gateway.is_ipv6());
+            assert!(gateway.ip.is_ipv4());
         }
     }
     
+    #[test]
+    fn test_parse_default_route_v6() {
+        let gateway = parse_default_route(
+            "default via fe80::1 dev eth0 proto ra metric 1024 expires 1798sec hoplimit 64 pref medium\n",
+        )
+        .unwrap();
+        assert_eq!(gateway.ip, "fe80::1".parse::<IpAddr>().unwrap());
+        assert_eq!(gateway.interface.as_deref(), Some("eth0"));
+        assert_eq!(gateway.to_string(), "fe80::1%eth0");
+        
+        // Multipath route
+        let gateway = parse_default_route(
+            "default proto ra metric 1024 pref medium\n\tnexthop via fe80::1 dev wan0 weight 1\n",
+        )
+        .unwrap();
+        assert_eq!(gateway.interface.as_deref(), Some("wan0"));
+        
+        assert!(parse_default_route("default dev ppp0 scope link\n").is_none());
+    }
+    
+    #[test]
+    fn test_connection_type_from_sysfs() {
+        let sysfs = std::env::temp_dir().join(format!("bufferbane-sysfs-{}", std::process::id()));
+        let interface = |name: &str, arp_type: &str, devtype: &str, extra: &[&str]| {
+            let dir = sysfs.join(name);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("type"), format!("{}\n", arp_type)).unwrap();
+            std::fs::write(dir.join("uevent"), format!("DEVTYPE={}\nINTERFACE={}\n", devtype, name)).unwrap();
+            for entry in extra {
+                std::fs::create_dir_all(dir.join(entry)).unwrap();
+            }
+        };
+        interface("wlp3s0", "1", "wlan", &["wireless", "phy80211"]);
+        interface("enp2s0", "1", "", &[]);
+        interface("wwan0", "65534", "wwan", &[]);
+        interface("usb0", "1", "", &[]);
+        interface("tun0", "65534", "", &["tun_flags"]);
+        interface("wg0", "65534", "wireguard", &[]);
+        interface("lo", "772", "", &[]);
+        
+        let detected: Vec<&str> = ["wlp3s0", "enp2s0", "wwan0", "usb0", "tun0", "wg0", "lo", "missing0"]
+            .iter()
+            .map(|name| connection_type_in(&sysfs, name))
+            .collect();
+        std::fs::remove_dir_all(&sysfs).unwrap();
+        assert_eq!(
+            detected,
+            ["wifi", "wired", "cellular", "cellular", "vpn", "vpn", "loopback", "unknown"]
+        );
+    }
+    
     #[tokio::test]
     async fn test_public_ip() {
         // This test requires internet connection
@@ -20,24 +20,47 @@ impl OutputManager {
         for m in measurements {
             match &m.status[..] {
                 "success" => {
-                    if let Some(rtt) = m.rtt_ms {
+                    if let (Some(rtt), Some(increase)) = (m.rtt_ms, m.latency_increase_ms) {
+                        println!("[{}] {} -> {} {:.2}ms ({:+.2}ms vs idle)", 
+                            chrono::Local::now().format("%H:%M:%S"),
+                            m.display_target(),
+                            m.test_type,
+                            rtt,
+                            increase
+                        );
+                    } else if let Some(rtt) = m.rtt_ms {
                         println!("[{}] {} -> {:.2}ms", 
                             chrono::Local::now().format("%H:%M:%S"),
-                            m.target, 
+                            m.display_target(), 
                             rtt
                         );
+                    } else if let Some(dns_ms) = m.dns_time_ms {
+                        println!("[{}] {} -> dns {:.2}ms ({})", 
+                            chrono::Local::now().format("%H:%M:%S"),
+                            m.display_target(),
+                            dns_ms,
+                            m.dns_rcode.as_deref().unwrap_or("?")
+                        );
+                    } else if let Some(kbps) = m.throughput_kbps {
+                        println!("[{}] {} -> {} {:.0} kbps ({:.2}% loss)", 
+                            chrono::Local::now().format("%H:%M:%S"),
+                            m.display_target(),
+                            m.test_type,
+                            kbps,
+                            m.packet_loss_pct.unwrap_or(0.0)
+                        );
                     }
                 }
                 "timeout" => {
                     println!("[{}] {} -> TIMEOUT", 
                         chrono::Local::now().format("%H:%M:%S"),
-                        m.target
+                        m.display_target()
                     );
                 }
                 "error" => {
                     println!("[{}] {} -> ERROR: {:?}", 
                         chrono::Local::now().format("%H:%M:%S"),
-                        m.target,
+                        m.display_target(),
                         m.error_detail
                     );
                 }
@@ -54,7 +77,7 @@ pub fn export_csv(measurements: &[Measurement], output_path: &Path) -> Result<()
     let mut writer = csv::Writer::from_path(output_path)?;
     
     // Write header
-    writer.write_record(&[
+    writer.write_record([
         "timestamp",
         "interface",
         "connection_type",
@@ -62,6 +85,11 @@ pub fn export_csv(measurements: &[Measurement], output_path: &Path) -> Result<()
         "target",
         "rtt_ms",
         "jitter_ms",
+        "rtt_delta_ms",
+        "upload_jitter_ms",
+        "download_jitter_ms",
+        "dns_time_ms",
+        "dns_rcode",
         "packet_loss_pct",
         "status",
         "error",
@@ -77,6 +105,11 @@ pub fn export_csv(measurements: &[Measurement], output_path: &Path) -> Result<()
             m.target.clone(),
             m.rtt_ms.map(|v| format!("{:.2}", v)).unwrap_or_default(),
             m.jitter_ms.map(|v| format!("{:.2}", v)).unwrap_or_default(),
+            m.rtt_delta_ms.map(|v| format!("{:.2}", v)).unwrap_or_default(),
+            m.upload_jitter_ms.map(|v| format!("{:.2}", v)).unwrap_or_default(),
+            m.download_jitter_ms.map(|v| format!("{:.2}", v)).unwrap_or_default(),
+            m.dns_time_ms.map(|v| format!("{:.2}", v)).unwrap_or_default(),
+            m.dns_rcode.clone().unwrap_or_default(),
             m.packet_loss_pct.map(|v| format!("{:.2}", v)).unwrap_or_default(),
             m.status.clone(),
             m.error_detail.clone().unwrap_or_default(),
@@ -0,0 +1,80 @@
+//! Test tick schedule
+//!
+//! Ticks stay on a grid of one period. A tick that starts late because
+//! earlier tests were still running skips the grid points that passed in
+//! the meantime rather than running them late, one after the other.
+
+use std::time::{Duration, Instant};
+
+/// Deadlines of a periodic test loop
+#[derive(Debug, Clone)]
+pub struct TickSchedule {
+    period: Duration,
+    deadline: Instant,
+}
+
+impl TickSchedule {
+    /// First tick due at `start`
+    pub fn new(period: Duration, start: Instant) -> Self {
+        Self {
+            period,
+            deadline: start,
+        }
+    }
+    
+    /// When the next tick is due
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+    
+    /// Start the tick that was due, at `now`
+    ///
+    /// Moves the deadline to the next grid point after `now` and returns how
+    /// many grid points passed while the tick was overdue (0 if it is on time).
+    pub fn start_tick(&mut self, now: Instant) -> u32 {
+        let late = now.saturating_duration_since(self.deadline);
+        let skipped = (late.as_nanos() / self.period.as_nanos().max(1)) as u32;
+        self.deadline += self.period * (skipped + 1);
+        skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_ticks_on_time() {
+        let start = Instant::now();
+        let period = Duration::from_millis(1000);
+        let mut schedule = TickSchedule::new(period, start);
+        
+        assert_eq!(schedule.start_tick(start), 0);
+        assert_eq!(schedule.deadline(), start + period);
+        
+        // Starting slightly late stays on the grid
+        assert_eq!(schedule.start_tick(start + Duration::from_millis(1010)), 0);
+        assert_eq!(schedule.deadline(), start + period * 2);
+        
+        // Woken up early, e.g. by a coarse timer
+        assert_eq!(schedule.start_tick(start + Duration::from_millis(1990)), 0);
+        assert_eq!(schedule.deadline(), start + period * 3);
+    }
+    
+    #[test]
+    fn test_overrun_skips_missed_ticks() {
+        let start = Instant::now();
+        let period = Duration::from_millis(1000);
+        let mut schedule = TickSchedule::new(period, start);
+        assert_eq!(schedule.start_tick(start), 0);
+        
+        // The first tick ran for 3.5 periods: the tick due at 1s starts at
+        // 3.5s, the ones due at 2s and 3s are skipped
+        assert_eq!(schedule.start_tick(start + Duration::from_millis(3500)), 2);
+        assert_eq!(schedule.deadline(), start + period * 4);
+        
+        // Caught up, back to one tick per period
+        assert_eq!(schedule.start_tick(start + period * 4), 0);
+        assert_eq!(schedule.deadline(), start + period * 5);
+    }
+}
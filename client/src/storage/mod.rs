@@ -6,6 +6,9 @@ use rusqlite::{Connection, params};
 use std::path::Path;
 use tracing::info;
 
+/// (min, max, avg, P50, P95, P99) - all None if there were no values
+type Statistics = (Option<f64>, Option<f64>, Option<f64>, Option<f64>, Option<f64>, Option<f64>);
+
 pub struct Database {
     conn: Connection,
 }
@@ -49,7 +52,12 @@ impl Database {
                 error_detail TEXT,
                 upload_latency_ms REAL,
                 download_latency_ms REAL,
-                server_processing_us INTEGER
+                server_processing_us INTEGER,
+                latency_increase_ms REAL,
+                rtt_delta_ms REAL,
+                upload_jitter_ms REAL,
+                download_jitter_ms REAL,
+                dns_rcode TEXT
             )",
             [],
         )?;
@@ -68,6 +76,26 @@ impl Database {
             "ALTER TABLE measurements ADD COLUMN server_processing_us INTEGER",
             [],
         );
+        let _ = self.conn.execute(
+            "ALTER TABLE measurements ADD COLUMN latency_increase_ms REAL",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE measurements ADD COLUMN rtt_delta_ms REAL",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE measurements ADD COLUMN upload_jitter_ms REAL",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE measurements ADD COLUMN download_jitter_ms REAL",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE measurements ADD COLUMN dns_rcode TEXT",
+            [],
+        );
         
         // Create indices for common queries
         self.conn.execute(
@@ -164,8 +192,10 @@ impl Database {
                 timestamp, monotonic_ns, interface, connection_type, test_type, target,
                 server_name, rtt_ms, jitter_ms, packet_loss_pct, throughput_kbps,
                 dns_time_ms, status, error_detail, upload_latency_ms, download_latency_ms,
-                server_processing_us
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+                server_processing_us, latency_increase_ms, rtt_delta_ms, upload_jitter_ms,
+                download_jitter_ms, dns_rcode
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18,
+                ?19, ?20, ?21, ?22)",
             params![
                 m.timestamp,
                 m.monotonic_ns as i64,
@@ -184,6 +214,11 @@ impl Database {
                 m.upload_latency_ms,
                 m.download_latency_ms,
                 m.server_processing_us,
+                m.latency_increase_ms,
+                m.rtt_delta_ms,
+                m.upload_jitter_ms,
+                m.download_jitter_ms,
+                &m.dns_rcode,
             ],
         )?;
         
@@ -196,7 +231,8 @@ impl Database {
                 timestamp, monotonic_ns, interface, connection_type, test_type, target,
                 server_name, rtt_ms, jitter_ms, packet_loss_pct, throughput_kbps,
                 dns_time_ms, status, error_detail, upload_latency_ms, download_latency_ms,
-                server_processing_us
+                server_processing_us, latency_increase_ms, rtt_delta_ms, upload_jitter_ms,
+                download_jitter_ms, dns_rcode
             FROM measurements
             WHERE timestamp >= ?1 AND timestamp <= ?2
             ORDER BY timestamp ASC"
@@ -221,6 +257,11 @@ impl Database {
                 upload_latency_ms: row.get(14)?,
                 download_latency_ms: row.get(15)?,
                 server_processing_us: row.get(16)?,
+                latency_increase_ms: row.get(17)?,
+                rtt_delta_ms: row.get(18)?,
+                upload_jitter_ms: row.get(19)?,
+                download_jitter_ms: row.get(20)?,
+                dns_rcode: row.get(21)?,
                 sync_event: None,  // Events are not persisted to database, only logged
             })
         })?
@@ -385,7 +426,7 @@ impl Database {
     }
     
     /// Calculate min, max, avg, P50, P95, P99 from comma-separated values
-    fn calculate_statistics(values_str: &str) -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>, Option<f64>, Option<f64>) {
+    fn calculate_statistics(values_str: &str) -> Statistics {
         let mut values: Vec<f64> = values_str
             .split(',')
             .filter_map(|s| s.parse().ok())
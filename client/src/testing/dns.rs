@@ -0,0 +1,352 @@
+//! DNS resolution testing
+//!
+//! Sends an A query for one of the test domains to every resolver, over UDP
+//! and again over TCP if the UDP response is truncated. The measured time
+//! covers the whole resolution, so compared with the ICMP RTT of the line it
+//! tells a slow resolver apart from a slow connection.
+
+use super::{Measurement, TestInterface};
+use crate::config::Config;
+use anyhow::{Context, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+/// QTYPE A (IPv4 address)
+const TYPE_A: u16 = 1;
+/// QCLASS IN
+const CLASS_IN: u16 = 1;
+
+/// Header flags: QR (response), TC (truncated), RD (recursion desired)
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Header of a DNS response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DnsResponse {
+    rcode: u8,
+    truncated: bool,
+    answers: u16,
+}
+
+pub struct DnsTester {
+    servers: Vec<SocketAddr>,
+    domains: Vec<String>,
+    interval: Duration,
+    /// Timeout of one resolution, UDP and TCP together
+    timeout: Duration,
+    interface: TestInterface,
+    /// Test domains are queried in turn
+    next_domain: usize,
+    last_run: Option<Instant>,
+}
+
+impl DnsTester {
+    pub fn new(config: &Config, interface: TestInterface) -> Result<Self> {
+        let dns = &config.tests.dns;
+        let mut servers = dns.servers()?;
+        if servers.is_empty() {
+            servers = system_resolvers().context("Failed to read /etc/resolv.conf")?;
+        }
+        if servers.is_empty() {
+            anyhow::bail!("No tests.dns.dns_servers configured and no nameserver in /etc/resolv.conf");
+        }
+        
+        for domain in &dns.test_domains {
+            build_query(0, domain)?;
+        }
+        
+        debug!(
+            "Initialized DNS tester with {} servers on interface {}",
+            servers.len(),
+            interface.label()
+        );
+        
+        Ok(Self {
+            servers,
+            domains: dns.test_domains.clone(),
+            interval: Duration::from_secs(dns.interval_s),
+            // Fits into one test tick like the ICMP probes
+            timeout: config.general.icmp_timeout(),
+            interface,
+            next_domain: 0,
+            last_run: None,
+        })
+    }
+    
+    /// Tag later measurements with a new connection type
+    pub fn set_connection_type(&mut self, connection_type: String) {
+        self.interface.connection_type = connection_type;
+    }
+    
+    /// Whether interval_s passed since the last run
+    pub fn is_due(&self) -> bool {
+        self.last_run.is_none_or(|last_run| last_run.elapsed() >= self.interval)
+    }
+    
+    /// Resolve the next test domain with all servers at once
+    pub async fn run_tests(&mut self) -> Vec<Measurement> {
+        self.last_run = Some(Instant::now());
+        let domain = self.domains[self.next_domain % self.domains.len()].clone();
+        self.next_domain = self.next_domain.wrapping_add(1);
+        
+        let probes = self.servers.iter().map(|server| self.probe(*server, &domain));
+        futures_util::future::join_all(probes).await
+    }
+    
+    async fn probe(&self, server: SocketAddr, domain: &str) -> Measurement {
+        let target = if server.port() == 53 {
+            server.ip().to_string()
+        } else {
+            server.to_string()
+        };
+        let mut measurement = Measurement::new_dns(
+            target,
+            self.interface.label(),
+            self.interface.connection_type.clone(),
+        );
+        
+        let start = Instant::now();
+        match tokio::time::timeout(self.timeout, self.resolve(server, domain)).await {
+            Ok(Ok(response)) => {
+                let dns_ms = start.elapsed().as_secs_f64() * 1000.0;
+                let rcode = rcode_name(response.rcode);
+                measurement.dns_time_ms = Some(dns_ms);
+                
+                // NXDOMAIN is a proper answer, only failing resolvers are errors
+                if matches!(response.rcode, RCODE_NOERROR | RCODE_NXDOMAIN) {
+                    measurement.status = "success".to_string();
+                } else {
+                    measurement.set_error(format!("{} for {}", rcode, domain));
+                }
+                debug!(
+                    "DNS {} {} -> {:.2}ms {} ({} answers)",
+                    server, domain, dns_ms, rcode, response.answers
+                );
+                measurement.dns_rcode = Some(rcode);
+            }
+            Ok(Err(e)) => {
+                measurement.set_error(format!("{:#}", e));
+                debug!("DNS {} {} -> error: {:#}", server, domain, e);
+            }
+            Err(_) => {
+                measurement.set_timeout();
+                debug!("DNS {} {} -> timeout", server, domain);
+            }
+        }
+        
+        measurement
+    }
+    
+    /// Query over UDP, and over TCP if the UDP response is truncated
+    async fn resolve(&self, server: SocketAddr, domain: &str) -> Result<DnsResponse> {
+        let id = rand::random();
+        let query = build_query(id, domain)?;
+        
+        let response = self.query_udp(server, &query, id).await?;
+        if !response.truncated {
+            return Ok(response);
+        }
+        
+        debug!("DNS {} {} -> truncated, retrying over TCP", server, domain);
+        self.query_tcp(server, &query, id).await
+    }
+    
+    async fn query_udp(&self, server: SocketAddr, query: &[u8], id: u16) -> Result<DnsResponse> {
+        let socket = self.interface.udp_socket(server)?;
+        socket.set_nonblocking(true).context("Failed to set UDP socket non-blocking")?;
+        let socket = UdpSocket::from_std(socket).context("Failed to register UDP socket")?;
+        
+        // Connected, so only packets of this server arrive
+        socket.connect(server).await.context("Failed to connect UDP socket")?;
+        socket.send(query).await.context("Failed to send DNS query")?;
+        
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = socket.recv(&mut buf).await.context("Failed to receive DNS response")?;
+            match parse_response(&buf[..len], id) {
+                Ok(response) => return Ok(response),
+                Err(e) => debug!("Ignoring DNS packet from {}: {:#}", server, e),
+            }
+        }
+    }
+    
+    async fn query_tcp(&self, server: SocketAddr, query: &[u8], id: u16) -> Result<DnsResponse> {
+        let mut stream = self.interface.tcp_socket(server)?
+            .connect(server)
+            .await
+            .context("Failed to connect to DNS server over TCP")?;
+        
+        // Messages are prefixed with their length over TCP
+        let mut message = Vec::with_capacity(query.len() + 2);
+        message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        message.extend_from_slice(query);
+        stream.write_all(&message).await.context("Failed to send DNS query over TCP")?;
+        
+        let len = stream.read_u16().await.context("Failed to receive DNS response over TCP")?;
+        let mut response = vec![0u8; len as usize];
+        stream
+            .read_exact(&mut response)
+            .await
+            .context("Failed to receive DNS response over TCP")?;
+        parse_response(&response, id)
+    }
+}
+
+/// Nameservers of /etc/resolv.conf (scoped IPv6 addresses are skipped)
+fn system_resolvers() -> Result<Vec<SocketAddr>> {
+    let contents = std::fs::read_to_string("/etc/resolv.conf")?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["nameserver", address, ..] => address.parse::<IpAddr>().ok(),
+            _ => None,
+        })
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect())
+}
+
+/// Build a recursive A query for `domain`
+fn build_query(id: u16, domain: &str) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + domain.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RD.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    query.extend_from_slice(&[0; 6]); // ANCOUNT, NSCOUNT, ARCOUNT
+    
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("Invalid test domain \"{}\"", domain);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    if query.len() - 12 > 255 {
+        anyhow::bail!("Test domain \"{}\" is too long", domain);
+    }
+    
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Parse the header of the response to query `id`
+fn parse_response(packet: &[u8], id: u16) -> Result<DnsResponse> {
+    if packet.len() < 12 {
+        anyhow::bail!("Response too short ({} bytes)", packet.len());
+    }
+    if u16::from_be_bytes([packet[0], packet[1]]) != id {
+        anyhow::bail!("Response to another query");
+    }
+    let flags = u16::from_be_bytes([packet[2], packet[3]]);
+    if flags & FLAG_QR == 0 {
+        anyhow::bail!("Not a response");
+    }
+    
+    Ok(DnsResponse {
+        rcode: (flags & 0x000f) as u8,
+        truncated: flags & FLAG_TC != 0,
+        answers: u16::from_be_bytes([packet[6], packet[7]]),
+    })
+}
+
+fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        other => format!("RCODE{}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    
+    /// Reply to `query` with `flags` (QR is added)
+    fn stub_response(query: &[u8], flags: u16) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2..4].copy_from_slice(&(flags | FLAG_QR | FLAG_RD).to_be_bytes());
+        response
+    }
+    
+    #[tokio::test]
+    async fn test_truncated_udp_response_falls_back_to_tcp() {
+        // Stub resolver: truncated over UDP, NXDOMAIN over TCP
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(server).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, client) = udp.recv_from(&mut buf).await.unwrap();
+            udp.send_to(&stub_response(&buf[..len], FLAG_TC), client).await.unwrap();
+            
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut query = vec![0u8; len as usize];
+            stream.read_exact(&mut query).await.unwrap();
+            let response = stub_response(&query, RCODE_NXDOMAIN as u16);
+            stream.write_u16(response.len() as u16).await.unwrap();
+            stream.write_all(&response).await.unwrap();
+            
+            // Swallow the next query
+            let _ = udp.recv_from(&mut buf).await;
+        });
+        
+        let mut tester = DnsTester {
+            servers: vec![server],
+            domains: vec!["nonexistent.example".to_string()],
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            interface: TestInterface {
+                name: None,
+                connection_type: "loopback".to_string(),
+                detect_type: false,
+            },
+            next_domain: 0,
+            last_run: None,
+        };
+        assert!(tester.is_due());
+        
+        let measurements = tester.run_tests().await;
+        assert_eq!(measurements.len(), 1);
+        let m = &measurements[0];
+        assert_eq!(m.test_type, "dns");
+        assert_eq!(m.target, server.to_string());
+        assert_eq!(m.status, "success");
+        assert_eq!(m.dns_rcode.as_deref(), Some("NXDOMAIN"));
+        assert!(m.dns_time_ms.is_some());
+        assert!(!tester.is_due());
+        
+        // Nothing answers anymore
+        tester.timeout = Duration::from_millis(100);
+        let measurements = tester.run_tests().await;
+        assert_eq!(measurements[0].status, "timeout");
+        assert_eq!(measurements[0].dns_time_ms, None);
+    }
+    
+    #[test]
+    fn test_build_query() {
+        let query = build_query(0x1234, "example.com.").unwrap();
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[12..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+        assert!(build_query(1, "bad..domain").is_err());
+        
+        let response = parse_response(&stub_response(&query, 2), 0x1234).unwrap();
+        assert_eq!(response.rcode, 2);
+        assert!(!response.truncated);
+        assert!(parse_response(&stub_response(&query, 0), 0x4321).is_err());
+        assert!(parse_response(&query, 0x1234).is_err());
+    }
+}
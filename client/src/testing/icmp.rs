@@ -1,44 +1,83 @@
 //! ICMP ping testing
+//!
+//! IPv4 targets are pinged with ICMP, IPv6 targets with ICMPv6 through a
+//! second socket. Both are bound to the tester's interface, if it has one.
 
-use super::Measurement;
+use super::jitter::JitterTracker;
+use super::{Measurement, TestInterface};
 use crate::config::Config;
+use crate::network_monitor::Gateway;
 use anyhow::{Context, Result};
-use std::net::IpAddr;
-use std::sync::Arc;
-use std::time::Duration;
-use surge_ping::{Client, Config as PingConfig, PingIdentifier, PingSequence};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use surge_ping::{Client, Config as PingConfig, ICMP, PingIdentifier, PingSequence};
 use tracing::{debug, warn};
 
+/// Address to ping
+#[derive(Debug, Clone, Copy)]
+struct Target {
+    ip: IpAddr,
+    /// Interface index for link-local IPv6 addresses, 0 otherwise
+    scope_id: u32,
+}
+
+impl From<IpAddr> for Target {
+    fn from(ip: IpAddr) -> Self {
+        Self { ip, scope_id: 0 }
+    }
+}
+
+impl From<&Gateway> for Target {
+    fn from(gateway: &Gateway) -> Self {
+        Self {
+            ip: gateway.ip,
+            scope_id: gateway.scope_id(),
+        }
+    }
+}
+
 pub struct IcmpTester {
-    #[allow(dead_code)]
     config: Arc<Config>,
     client: Client,
-    targets: Vec<IpAddr>,
-    interface: String,
-    connection_type: String,
+    /// None if the system has no ICMPv6 support
+    client_v6: Option<Client>,
+    targets: Vec<Target>,
+    interface: TestInterface,
+    /// Jitter state per target
+    jitter: Mutex<HashMap<IpAddr, JitterTracker>>,
 }
 
 impl IcmpTester {
     pub fn new(config: Arc<Config>) -> Result<Self> {
-        Self::new_with_additional_targets(config, Vec::new())
+        let interface = TestInterface::from_config(&config.general).remove(0);
+        Self::new_with_gateways(config, interface, Vec::new())
     }
     
-    pub fn new_with_additional_targets(config: Arc<Config>, additional_targets: Vec<IpAddr>) -> Result<Self> {
-        // Create ICMP client
-        let ping_config = PingConfig::default();
-        let client = Client::new(&ping_config)
+    pub fn new_with_gateways(config: Arc<Config>, interface: TestInterface, gateways: Vec<Gateway>) -> Result<Self> {
+        // Create ICMP clients
+        let client = ping_client(&interface, ICMP::V4)
             .context("Failed to create ICMP client (CAP_NET_RAW required)")?;
         
+        let client_v6 = match ping_client(&interface, ICMP::V6) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("Failed to create ICMPv6 client, IPv6 targets can't be pinged: {}", e);
+                None
+            }
+        };
+        
         // Resolve target IPs
-        let mut targets = Vec::new();
+        let mut targets: Vec<Target> = Vec::new();
         
-        // Add additional targets first (e.g., auto-detected gateway)
-        targets.extend(additional_targets);
+        // Add auto-detected gateways first
+        targets.extend(gateways.iter().map(Target::from));
         
         // Add public DNS servers
         for dns in &config.targets.public_dns {
             match dns.parse::<IpAddr>() {
-                Ok(ip) => targets.push(ip),
+                Ok(ip) => targets.push(ip.into()),
                 Err(e) => warn!("Failed to parse DNS address {}: {}", dns, e),
             }
         }
@@ -46,11 +85,11 @@ impl IcmpTester {
         // Add custom targets
         for custom in &config.targets.custom {
             match custom.parse::<IpAddr>() {
-                Ok(ip) => targets.push(ip),
+                Ok(ip) => targets.push(ip.into()),
                 Err(e) => {
                     // Try to resolve as hostname
                     match resolve_hostname(custom) {
-                        Ok(ip) => targets.push(ip),
+                        Ok(ip) => targets.push(ip.into()),
                         Err(e2) => warn!("Failed to resolve {}: {} (parse: {})", custom, e2, e),
                     }
                 }
@@ -61,82 +100,101 @@ impl IcmpTester {
             anyhow::bail!("No valid targets configured");
         }
         
-        debug!("Initialized ICMP tester with {} targets", targets.len());
-        
-        // Determine interface and connection type
-        let interface = if config.general.interfaces.is_empty() {
-            "default".to_string()
-        } else {
-            config.general.interfaces[0].clone()
-        };
-        
-        let connection_type = config.general.connection_type.clone();
+        debug!(
+            "Initialized ICMP tester with {} targets on interface {}",
+            targets.len(),
+            interface.label()
+        );
         
         Ok(Self {
             config,
             client,
+            client_v6,
             targets,
             interface,
-            connection_type,
+            jitter: Mutex::new(HashMap::new()),
         })
     }
     
+    /// Tag later measurements with a new connection type
+    pub fn set_connection_type(&mut self, connection_type: String) {
+        self.interface.connection_type = connection_type;
+    }
+    
     /// Update gateway target (for dynamic gateway changes)
-    /// Replaces first target if it's a private IP (assumed to be gateway)
-    /// Or adds as first target if no private IP exists
-    pub fn update_gateway(&mut self, new_gateway: IpAddr) {
-        // Check if first target is a private IP (likely gateway)
-        if let Some(first) = self.targets.first() {
-            if is_private_ip(first) {
-                debug!("Updating gateway target: {} -> {}", first, new_gateway);
-                self.targets[0] = new_gateway;
-                return;
-            }
+    /// Replaces the leading private IP (assumed to be gateway) of the same address family
+    /// Or adds as first target if there is none
+    pub fn update_gateway(&mut self, new_gateway: &Gateway) {
+        // Gateways are the private IPs at the start of the list
+        if let Some(index) = self
+            .targets
+            .iter()
+            .take_while(|target| is_private_ip(&target.ip))
+            .position(|target| target.ip.is_ipv4() == new_gateway.ip.is_ipv4())
+        {
+            debug!("Updating gateway target: {} -> {}", self.targets[index].ip, new_gateway);
+            self.jitter.get_mut().unwrap().remove(&self.targets[index].ip);
+            self.targets[index] = new_gateway.into();
+            return;
         }
         
-        // No private IP found, add gateway as first target
+        // No gateway of this family yet, add gateway as first target
         debug!("Adding new gateway target: {}", new_gateway);
-        self.targets.insert(0, new_gateway);
+        self.targets.insert(0, new_gateway.into());
     }
     
+    /// Ping all targets at once
+    ///
+    /// Takes at most the ICMP timeout, however many targets don't answer.
     pub async fn run_tests(&self) -> Result<Vec<Measurement>> {
-        let mut measurements = Vec::new();
+        let probes = self.targets.iter().map(|target| self.probe(*target));
+        Ok(futures_util::future::join_all(probes).await)
+    }
+    
+    async fn probe(&self, target: Target) -> Measurement {
+        let target_ip = &target.ip;
+        let mut measurement = Measurement::new_icmp(
+            target_ip.to_string(),
+            self.interface.label(),
+            self.interface.connection_type.clone(),
+        );
         
-        for target_ip in &self.targets {
-            let mut measurement = Measurement::new_icmp(
-                target_ip.to_string(),
-                self.interface.clone(),
-                self.connection_type.clone(),
-            );
-            
-            // Ping with 5 second timeout
-            match self.ping(*target_ip).await {
-                Ok(rtt_ms) => {
-                    measurement.set_success(rtt_ms);
-                    debug!("ICMP {} -> {:.2}ms", target_ip, rtt_ms);
+        match self.ping(target).await {
+            Ok(rtt_ms) => {
+                measurement.set_success(rtt_ms);
+                if let Some(sample) = self.jitter.lock().unwrap().entry(*target_ip).or_default().update(rtt_ms) {
+                    measurement.jitter_ms = Some(sample.jitter_ms);
+                    measurement.rtt_delta_ms = Some(sample.delta_ms);
                 }
-                Err(e) => {
-                    if e.to_string().contains("timeout") {
-                        measurement.set_timeout();
-                        debug!("ICMP {} -> timeout", target_ip);
-                    } else {
-                        measurement.set_error(e.to_string());
-                        debug!("ICMP {} -> error: {}", target_ip, e);
-                    }
+                debug!("ICMP {} -> {:.2}ms", target_ip, rtt_ms);
+            }
+            Err(e) => {
+                if e.to_string().contains("timeout") {
+                    measurement.set_timeout();
+                    debug!("ICMP {} -> timeout", target_ip);
+                } else {
+                    measurement.set_error(e.to_string());
+                    debug!("ICMP {} -> error: {}", target_ip, e);
                 }
             }
-            
-            measurements.push(measurement);
         }
         
-        Ok(measurements)
+        measurement
     }
     
-    async fn ping(&self, target: IpAddr) -> Result<f64> {
+    async fn ping(&self, target: Target) -> Result<f64> {
         let payload = [0u8; 56]; // Standard ping payload size
-        let timeout = Duration::from_secs(5);
+        let timeout = self.config.general.icmp_timeout();
         
-        let mut pinger = self.client.pinger(target, PingIdentifier(rand::random())).await;
+        let client = match target.ip {
+            IpAddr::V4(_) => &self.client,
+            IpAddr::V6(_) => self
+                .client_v6
+                .as_ref()
+                .context("ICMPv6 is not available")?,
+        };
+        let mut pinger = client.pinger(target.ip, PingIdentifier(rand::random())).await;
+        pinger.scope_id(target.scope_id);
         
         match tokio::time::timeout(
             timeout,
@@ -155,6 +213,26 @@ impl IcmpTester {
     }
 }
 
+/// Create an ICMP client bound to `interface`
+///
+/// Falls back to the interface's address as source if binding to the
+/// device is not permitted.
+fn ping_client(interface: &TestInterface, kind: ICMP) -> Result<Client> {
+    let Some(name) = &interface.name else {
+        return Ok(Client::new(&PingConfig::builder().kind(kind).build())?);
+    };
+    
+    match Client::new(&PingConfig::builder().kind(kind).interface(name).build()) {
+        Ok(client) => Ok(client),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let address = interface.fallback_address(matches!(kind, ICMP::V6), &e)?;
+            let ping_config = PingConfig::builder().kind(kind).bind(SocketAddr::new(address, 0)).build();
+            Ok(Client::new(&ping_config)?)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to bind to interface {}", name)),
+    }
+}
+
 /// Check if an IP address is private/local
 fn is_private_ip(ip: &IpAddr) -> bool {
     match ip {
@@ -162,7 +240,7 @@ fn is_private_ip(ip: &IpAddr) -> bool {
             ipv4.is_private() || ipv4.is_loopback() || ipv4.is_link_local()
         }
         IpAddr::V6(ipv6) => {
-            ipv6.is_loopback() || ipv6.is_unique_local()
+            ipv6.is_loopback() || ipv6.is_unique_local() || ipv6.is_unicast_link_local()
         }
     }
 }
@@ -0,0 +1,84 @@
+//! Jitter of a stream of probes
+//!
+//! Implements the interarrival jitter estimator of RFC 3550 (section 6.4.1):
+//! for every answered probe, D is the change of its transit time against the
+//! previous answered probe and the jitter moves 1/16 of the way towards |D|.
+//! The transit time is the RTT for round-trip streams or a one-way latency,
+//! whose constant clock offset cancels out in D.
+
+/// Jitter state of one stream (one target, one direction)
+#[derive(Debug, Default, Clone)]
+pub struct JitterTracker {
+    /// Transit time of the previous answered probe (ms)
+    last_transit_ms: Option<f64>,
+    /// Smoothed interarrival jitter (ms)
+    jitter_ms: f64,
+}
+
+/// Jitter after one probe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JitterSample {
+    /// RFC 3550 smoothed interarrival jitter (ms)
+    pub jitter_ms: f64,
+    /// Absolute transit time change against the previous probe (ms)
+    pub delta_ms: f64,
+}
+
+impl JitterTracker {
+    /// Add the transit time of the next answered probe
+    ///
+    /// Returns None for the first probe of the stream, which has nothing to
+    /// be compared with. Lost probes are simply not added.
+    pub fn update(&mut self, transit_ms: f64) -> Option<JitterSample> {
+        let last_transit_ms = self.last_transit_ms.replace(transit_ms)?;
+        let delta_ms = (transit_ms - last_transit_ms).abs();
+        self.jitter_ms += (delta_ms - self.jitter_ms) / 16.0;
+        Some(JitterSample {
+            jitter_ms: self.jitter_ms,
+            delta_ms,
+        })
+    }
+    
+    /// Jitter of a complete stream of transit times
+    pub fn of(transits_ms: &[f64]) -> Option<f64> {
+        let mut tracker = Self::default();
+        transits_ms
+            .iter()
+            .filter_map(|transit_ms| tracker.update(*transit_ms))
+            .last()
+            .map(|sample| sample.jitter_ms)
+    }
+    
+    /// Start over, e.g. when the transit times are no longer comparable
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_rfc3550_jitter() {
+        let mut tracker = JitterTracker::default();
+        assert_eq!(tracker.update(10.0), None);
+        
+        let sample = tracker.update(26.0).unwrap();
+        assert_eq!(sample.delta_ms, 16.0);
+        assert_eq!(sample.jitter_ms, 1.0);
+        
+        // J += (|D| - J) / 16 with D = -16
+        let sample = tracker.update(10.0).unwrap();
+        assert_eq!(sample.delta_ms, 16.0);
+        assert_eq!(sample.jitter_ms, 1.0 + 15.0 / 16.0);
+        
+        // A constant transit time lets the jitter decay
+        let sample = tracker.update(10.0).unwrap();
+        assert_eq!(sample.delta_ms, 0.0);
+        assert!(sample.jitter_ms < 1.0 + 15.0 / 16.0);
+        
+        assert_eq!(JitterTracker::of(&[10.0, 26.0]), Some(1.0));
+        assert_eq!(JitterTracker::of(&[10.0]), None);
+    }
+}
@@ -28,9 +28,12 @@ pub struct Measurement {
     /// Round-trip time in milliseconds (None if packet lost)
     pub rtt_ms: Option<f64>,
     
-    /// Jitter in milliseconds (calculated from previous measurements)
+    /// RFC 3550 interarrival jitter of this target's probes in milliseconds
     pub jitter_ms: Option<f64>,
     
+    /// Absolute RTT change against the previous probe of this target in milliseconds
+    pub rtt_delta_ms: Option<f64>,
+    
     /// Packet loss percentage (for batch tests)
     pub packet_loss_pct: Option<f64>,
     
@@ -40,6 +43,9 @@ pub struct Measurement {
     /// DNS resolution time in milliseconds
     pub dns_time_ms: Option<f64>,
     
+    /// DNS response code, e.g. "NOERROR" or "NXDOMAIN" (DNS tests only)
+    pub dns_rcode: Option<String>,
+    
     /// Status: "success", "timeout", "error"
     pub status: String,
     
@@ -52,9 +58,18 @@ pub struct Measurement {
     /// Download latency in milliseconds (server → client, for server tests only)
     pub download_latency_ms: Option<f64>,
     
+    /// Jitter of the upload latency in milliseconds (for server tests only)
+    pub upload_jitter_ms: Option<f64>,
+    
+    /// Jitter of the download latency in milliseconds (for server tests only)
+    pub download_jitter_ms: Option<f64>,
+    
     /// Server processing time in microseconds (for server tests only)
     pub server_processing_us: Option<i64>,
     
+    /// Latency increase over the idle baseline in milliseconds (bufferbloat tests only)
+    pub latency_increase_ms: Option<f64>,
+    
     /// Sync event information (if a sync state change occurred)
     pub sync_event: Option<SyncEvent>,
 }
@@ -67,7 +82,9 @@ pub struct SyncEvent {
 }
 
 impl Measurement {
-    pub fn new_icmp(
+    /// Empty measurement of `test_type`, timestamped now
+    fn new(
+        test_type: &str,
         target: String,
         interface: String,
         connection_type: String,
@@ -86,56 +103,80 @@ impl Measurement {
             monotonic_ns,
             interface,
             connection_type,
-            test_type: "icmp".to_string(),
+            test_type: test_type.to_string(),
             target,
             server_name: None,
             rtt_ms: None,
             jitter_ms: None,
+            rtt_delta_ms: None,
             packet_loss_pct: None,
             throughput_kbps: None,
             dns_time_ms: None,
+            dns_rcode: None,
             status: "pending".to_string(),
             error_detail: None,
             upload_latency_ms: None,
             download_latency_ms: None,
+            upload_jitter_ms: None,
+            download_jitter_ms: None,
             server_processing_us: None,
+            latency_increase_ms: None,
             sync_event: None,
         }
     }
     
+    pub fn new_icmp(
+        target: String,
+        interface: String,
+        connection_type: String,
+    ) -> Self {
+        Self::new("icmp", target, interface, connection_type)
+    }
+    
     pub fn new_server_echo(
         target: String,
         interface: String,
         connection_type: String,
     ) -> Self {
-        use std::time::{SystemTime, UNIX_EPOCH};
-        
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-        
-        let monotonic_ns = std::time::Instant::now().elapsed().as_nanos();
-        
-        Self {
-            timestamp,
-            monotonic_ns,
-            interface,
-            connection_type,
-            test_type: "server_echo".to_string(),
-            target,
-            server_name: None,
-            rtt_ms: None,
-            jitter_ms: None,
-            packet_loss_pct: None,
-            throughput_kbps: None,
-            dns_time_ms: None,
-            status: "pending".to_string(),
-            error_detail: None,
-            upload_latency_ms: None,
-            download_latency_ms: None,
-            server_processing_us: None,
-            sync_event: None,
+        Self::new("server_echo", target, interface, connection_type)
+    }
+    
+    /// Create a throughput measurement ("throughput_up" or "throughput_down")
+    pub fn new_throughput(
+        test_type: &str,
+        target: String,
+        interface: String,
+        connection_type: String,
+    ) -> Self {
+        Self::new(test_type, target, interface, connection_type)
+    }
+    
+    /// Create a bufferbloat measurement ("bufferbloat_up", "bufferbloat_down"
+    /// or "bufferbloat_recovery")
+    pub fn new_bufferbloat(
+        test_type: &str,
+        target: String,
+        interface: String,
+        connection_type: String,
+    ) -> Self {
+        Self::new(test_type, target, interface, connection_type)
+    }
+    
+    /// Create a DNS resolution measurement (target = resolver)
+    pub fn new_dns(
+        target: String,
+        interface: String,
+        connection_type: String,
+    ) -> Self {
+        Self::new("dns", target, interface, connection_type)
+    }
+    
+    /// Target for log and console output, with the interface if tests are bound to one
+    pub fn display_target(&self) -> String {
+        if self.interface == "default" {
+            self.target.clone()
+        } else {
+            format!("{} ({})", self.target, self.interface)
         }
     }
     
@@ -1,16 +1,155 @@
 //! Network testing implementation
+//!
+//! Every interface in general.interfaces gets its own testers whose sockets
+//! are bound to it, so all interfaces are measured at the same time.
 
+mod dns;
 mod icmp;
+mod jitter;
 mod measurement;
 pub mod server;
 
+pub use dns::DnsTester;
 pub use icmp::IcmpTester;
 pub use measurement::{Measurement, SyncEvent};
 pub use server::ServerTester;
 
-use crate::config::Config;
-use anyhow::Result;
+use crate::config::{Config, GeneralConfig};
+use crate::network_monitor;
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::sync::Arc;
+use tracing::warn;
+
+/// Network interface one set of testers runs on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestInterface {
+    /// Interface to bind sockets to, None = follow the routing table
+    pub name: Option<String>,
+    /// Tag stored with the measurements
+    pub connection_type: String,
+    /// connection_type is detected rather than configured
+    detect_type: bool,
+}
+
+impl TestInterface {
+    /// One per configured interface, or an unbound one if none are configured
+    ///
+    /// connection_type applies to a single interface; with several, or if
+    /// it is "auto", each interface's type is detected.
+    pub fn from_config(config: &GeneralConfig) -> Vec<Self> {
+        let detect_type = config.connection_type == "auto" || config.interfaces.len() > 1;
+        let names: Vec<Option<String>> = if config.interfaces.is_empty() {
+            vec![None]
+        } else {
+            config.interfaces.iter().cloned().map(Some).collect()
+        };
+        
+        names
+            .into_iter()
+            .map(|name| {
+                let mut interface = Self {
+                    name,
+                    connection_type: config.connection_type.clone(),
+                    detect_type,
+                };
+                interface.refresh_connection_type();
+                interface
+            })
+            .collect()
+    }
+    
+    /// Detect the connection type again, unless it is configured
+    ///
+    /// Unbound tests use the interface of the default route. Returns the
+    /// previous type if it changed.
+    pub fn refresh_connection_type(&mut self) -> Option<String> {
+        if !self.detect_type {
+            return None;
+        }
+        
+        let interface = match &self.name {
+            Some(name) => Some(name.clone()),
+            None => network_monitor::detect_default_gateway(None)
+                .ok()
+                .and_then(|gateway| gateway.interface),
+        };
+        let detected = interface
+            .map(|interface| network_monitor::detect_connection_type(&interface))
+            .unwrap_or_else(|| "unknown".to_string());
+        
+        if detected == self.connection_type {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.connection_type, detected))
+    }
+    
+    /// Interface name stored with the measurements
+    pub fn label(&self) -> String {
+        self.name.clone().unwrap_or_else(|| "default".to_string())
+    }
+    
+    /// Source address to bind to if binding to the device is not permitted
+    ///
+    /// SO_BINDTODEVICE needs CAP_NET_RAW. Without it, packets only leave
+    /// through the interface if the routing table (or a source routing
+    /// rule) sends its address there.
+    fn fallback_address(&self, ipv6: bool, error: &std::io::Error) -> Result<IpAddr> {
+        let name = self.name.as_deref().context("No interface to bind to")?;
+        let address = network_monitor::interface_address(name, ipv6)?;
+        warn!(
+            "Can't bind to interface {} ({}), using its address {} as source instead",
+            name, error, address
+        );
+        Ok(address)
+    }
+    
+    /// Source address needed after binding to the device, None if that worked
+    fn bind_fallback(&self, bound: std::io::Result<()>, ipv6: bool) -> Result<Option<IpAddr>> {
+        match bound {
+            Ok(()) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => self.fallback_address(ipv6, &e).map(Some),
+            Err(e) => Err(e).with_context(|| format!("Failed to bind to interface {}", self.label())),
+        }
+    }
+    
+    /// Create a UDP socket for talking to `remote` through this interface
+    pub fn udp_socket(&self, remote: SocketAddr) -> Result<UdpSocket> {
+        Ok(self.bound_socket(remote, Type::DGRAM, Protocol::UDP)?.into())
+    }
+    
+    /// Create an unconnected TCP socket for talking to `remote` through this interface
+    pub fn tcp_socket(&self, remote: SocketAddr) -> Result<tokio::net::TcpSocket> {
+        let socket = self.bound_socket(remote, Type::STREAM, Protocol::TCP)?;
+        socket.set_nonblocking(true).context("Failed to set TCP socket non-blocking")?;
+        Ok(tokio::net::TcpSocket::from_std_stream(socket.into()))
+    }
+    
+    fn bound_socket(&self, remote: SocketAddr, kind: Type, protocol: Protocol) -> Result<Socket> {
+        let unspecified: IpAddr = if remote.is_ipv6() {
+            Ipv6Addr::UNSPECIFIED.into()
+        } else {
+            Ipv4Addr::UNSPECIFIED.into()
+        };
+        let name = if protocol == Protocol::TCP { "TCP" } else { "UDP" };
+        let socket = Socket::new(Domain::for_address(remote), kind, Some(protocol))
+            .with_context(|| format!("Failed to create {} socket", name))?;
+        
+        let mut source = unspecified;
+        if let Some(name) = &self.name
+            && let Some(address) = self.bind_fallback(socket.bind_device(Some(name.as_bytes())), remote.is_ipv6())?
+        {
+            source = address;
+        }
+        
+        socket
+            .bind(&SocketAddr::new(source, 0).into())
+            .with_context(|| format!("Failed to bind {} socket", name))?;
+        Ok(socket)
+    }
+}
 
 /// Test runner that coordinates all network tests
 #[allow(dead_code)]
@@ -36,3 +175,66 @@ impl TestRunner {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn general(interfaces: &[&str], connection_type: &str) -> GeneralConfig {
+        GeneralConfig {
+            test_interval_ms: 1000,
+            icmp_timeout_ms: 0,
+            database_path: String::new(),
+            client_id: String::new(),
+            interfaces: interfaces.iter().map(|name| name.to_string()).collect(),
+            connection_type: connection_type.to_string(),
+        }
+    }
+    
+    #[test]
+    fn test_one_tester_set_per_interface() {
+        // Without interfaces the tests follow the routing table
+        let interfaces = TestInterface::from_config(&general(&[], "fiber"));
+        assert_eq!(interfaces.len(), 1);
+        assert_eq!(interfaces[0].name, None);
+        assert_eq!(interfaces[0].label(), "default");
+        assert_eq!(interfaces[0].connection_type, "fiber");
+        
+        // A single interface keeps the configured type
+        let interfaces = TestInterface::from_config(&general(&["lo"], "fiber"));
+        assert_eq!(interfaces.len(), 1);
+        assert_eq!(interfaces[0].label(), "lo");
+        assert_eq!(interfaces[0].connection_type, "fiber");
+        
+        // With several, each interface's type is detected
+        let interfaces = TestInterface::from_config(&general(&["lo", "bb-missing0"], "fiber"));
+        let labels: Vec<String> = interfaces.iter().map(TestInterface::label).collect();
+        assert_eq!(labels, ["lo", "bb-missing0"]);
+        assert_eq!(interfaces[0].connection_type, "loopback");
+        assert_eq!(interfaces[1].connection_type, "unknown");
+    }
+    
+    #[test]
+    fn test_bind_fallback_uses_interface_address() {
+        let lo = TestInterface {
+            name: Some("lo".to_string()),
+            connection_type: "loopback".to_string(),
+            detect_type: false,
+        };
+        assert_eq!(lo.bind_fallback(Ok(()), false).unwrap(), None);
+        
+        // Without CAP_NET_RAW the interface's address becomes the source
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+        assert_eq!(
+            lo.bind_fallback(Err(denied), false).unwrap(),
+            Some(IpAddr::from(Ipv4Addr::LOCALHOST))
+        );
+        
+        // Other errors are not papered over
+        let missing = std::io::Error::from(ErrorKind::NotFound);
+        assert!(lo.bind_fallback(Err(missing), false).is_err());
+        
+        let socket = lo.udp_socket(SocketAddr::from((Ipv4Addr::LOCALHOST, 9))).unwrap();
+        assert_ne!(socket.local_addr().unwrap().port(), 0);
+    }
+}
@@ -3,23 +3,32 @@
 //! Handles encrypted communication with Bufferbane server for:
 //! - Enhanced latency testing (ECHO requests)
 //! - Authentication via port knocking
-//! - Future: Throughput and bufferbloat testing
+//! - Upload throughput testing (THROUGHPUT_* packets)
+//! - Download throughput testing (DOWNLOAD_* packets)
+//! - Bufferbloat testing (echo latency under upload/download load)
 
-use crate::config::ServerConfig;
-use crate::testing::{Measurement, SyncEvent};
+use crate::config::{ServerConfig, TestsConfig};
+use crate::testing::jitter::JitterTracker;
+use crate::testing::{Measurement, SyncEvent, TestInterface};
 use anyhow::{Context, Result};
 use protocol::{
-    crypto,
+    codec::{Decode, Encode, Packet, PacketIds},
+    crypto::{self, Direction, KeyExchange},
     packets::{
-        EchoReplyPayload, EchoRequestPayload, KnockAckPayload, KnockPayload,
-        PacketHeader, PacketType,
+        BufferbloatDirection, BufferbloatEndPayload, BufferbloatStartPayload, Capabilities,
+        DownloadDataPayload, DownloadEndPayload, DownloadRequestPayload,
+        EchoReplyPayload, EchoRequestPayload, ErrorCode, ErrorPayload, KnockAckPayload,
+        KnockPayload, PacketType, ServerLimits, ThroughputDataPayload, ThroughputEndPayload,
+        ThroughputStartPayload, ThroughputStatsPayload,
     },
+    THROUGHPUT_CHUNK_SIZE,
 };
+use std::cell::Cell;
 use std::collections::VecDeque;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
 /// Sample of clock offset measurement
 #[derive(Clone)]
@@ -62,43 +71,192 @@ impl TimeSyncState {
     }
 }
 
+/// One size tier of the upload test schedule (small/medium/large)
+struct ThroughputTier {
+    name: &'static str,
+    size_bytes: u64,
+    interval: Duration,
+    last_run: Instant,
+}
+
+/// Result of an upload test
+struct UploadResult {
+    /// The server's view of the transfer
+    stats: ThroughputStatsPayload,
+    /// The server reported dropping data to stay within its bandwidth limit,
+    /// so its loss figure is not (only) loss on the path
+    rate_limited: bool,
+}
+
+/// Client-side result of a download test
+struct DownloadResult {
+    bytes_received: u64,
+    packets_received: u32,
+    /// Packets the server reports as sent (estimated if DOWNLOAD_END was lost)
+    packets_sent: u32,
+    /// Time between first and last DOWNLOAD_DATA arrival
+    duration: Duration,
+}
+
+impl DownloadResult {
+    fn throughput_kbps(&self) -> f64 {
+        // bits per millisecond == kilobits per second
+        let duration_ms = (self.duration.as_secs_f64() * 1000.0).max(1.0);
+        self.bytes_received as f64 * 8.0 / duration_ms
+    }
+    
+    fn packet_loss_pct(&self) -> f64 {
+        if self.packets_sent == 0 {
+            return 0.0;
+        }
+        let lost = self.packets_sent.saturating_sub(self.packets_received);
+        lost as f64 / self.packets_sent as f64 * 100.0
+    }
+}
+
+/// Load data chunks sent per loop iteration of an upload load phase,
+/// between checks for due probes and incoming replies
+const UPLOAD_BURST: usize = 16;
+
+/// Echo probes and load counters of one bufferbloat test phase
+#[derive(Default)]
+struct ProbePhase {
+    /// Sequence number of the first probe of this phase
+    first_sequence: u32,
+    /// Probes sent
+    sent: u32,
+    /// RTTs of answered probes (ms)
+    rtts_ms: Vec<f64>,
+    /// Load bytes sent (upload) or received (download) by the client
+    load_bytes: u64,
+    /// Load throughput in kbps, if known
+    load_kbps: Option<f64>,
+    /// BUFFERBLOAT_END reply received (upload phases only)
+    end_received: bool,
+    /// The server reported dropping upload load to stay within its
+    /// bandwidth limit
+    rate_limited: bool,
+}
+
+impl ProbePhase {
+    fn median_rtt_ms(&self) -> Option<f64> {
+        if self.rtts_ms.is_empty() {
+            return None;
+        }
+        let mut sorted = self.rtts_ms.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Some(sorted[sorted.len() / 2])
+    }
+    
+    /// RFC 3550 jitter of the answered probes
+    fn jitter_ms(&self) -> Option<f64> {
+        JitterTracker::of(&self.rtts_ms)
+    }
+    
+    fn probe_loss_pct(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        let lost = self.sent.saturating_sub(self.rtts_ms.len() as u32);
+        lost as f64 / self.sent as f64 * 100.0
+    }
+}
+
+/// Client-side result of a bufferbloat test
+struct BufferbloatResult {
+    idle: ProbePhase,
+    loaded: Vec<(BufferbloatDirection, ProbePhase)>,
+    recovery: ProbePhase,
+}
+
+/// Echo timeouts in a row after which the client knocks again
+///
+/// The server silently drops packets of sessions it doesn't know (e.g.
+/// after a restart), so timeouts are the only hint.
+const RE_KNOCK_AFTER_TIMEOUTS: u32 = 3;
+
+/// Pause between the datagrams of the port knock sequence, so they arrive
+/// in order
+const KNOCK_PORT_GAP: Duration = Duration::from_millis(20);
+
+/// Back-off after RATE_LIMITED or SERVER_OVERLOADED without a retry-after hint
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Session established by KNOCK/KNOCK_ACK
+struct ServerSession {
+    id: u64,
+    /// Key derived from the X25519 exchange, used for all packets but KNOCK
+    key: [u8; 32],
+    /// Protocol version the server chose
+    version: u8,
+    /// Tests the server offers
+    capabilities: Capabilities,
+    limits: ServerLimits,
+}
+
 /// Server tester for Phase 2 features
 pub struct ServerTester {
     config: Arc<ServerConfig>,
+    tests: TestsConfig,
     socket: UdpSocket,
     server_addr: SocketAddr,
     shared_secret: [u8; 32],
     client_id: u64,
-    session_id: Option<u64>,
+    session: Option<ServerSession>,
+    /// ERROR received from the server, handled by the next `run_test`
+    server_error: Cell<Option<ErrorPayload>>,
+    /// No server tests until then (server asked us to back off)
+    backoff_until: Option<Instant>,
+    /// Requests the server said it doesn't offer
+    unsupported: Vec<PacketType>,
+    /// Server doesn't speak our protocol version
+    disabled: bool,
     interface: String,
     connection_type: String,
     sequence: u32,
+    /// Echo timeouts since the last reply
+    echo_timeouts: u32,
     /// Time synchronization state
     time_sync: TimeSyncState,
+    /// Jitter of the echo RTT
+    rtt_jitter: JitterTracker,
+    /// Jitter of the echo upload latency (while time sync holds)
+    upload_jitter: JitterTracker,
+    /// Jitter of the echo download latency (while time sync holds)
+    download_jitter: JitterTracker,
+    /// Upload test schedule, largest tier first
+    throughput_tiers: Vec<ThroughputTier>,
+    /// Last download test run
+    last_download: Instant,
+    /// Last bufferbloat test run
+    last_bufferbloat: Instant,
+    next_test_id: u32,
 }
 
 impl ServerTester {
     /// Create a new server tester
     pub fn new(
         config: Arc<ServerConfig>,
-        interface: String,
-        connection_type: String,
+        tests: TestsConfig,
+        interface: &TestInterface,
     ) -> Result<Self> {
         // Parse shared secret
         let shared_secret = crypto::parse_shared_secret(&config.shared_secret)
             .map_err(|e| anyhow::anyhow!("Invalid shared secret: {}", e))?;
         
-        // Resolve server address (supports both IP and hostname)
-        let server_addr_str = format!("{}:{}", config.host, config.port);
-        let server_addr: SocketAddr = server_addr_str
+        // Resolve server address (supports IPv4, IPv6 and hostnames)
+        let host = config.host.trim_start_matches('[').trim_end_matches(']');
+        let server_addr: SocketAddr = (host, config.port)
             .to_socket_addrs()
-            .with_context(|| format!("Failed to resolve server address: {}", server_addr_str))?
-            .next()
-            .with_context(|| format!("No IP address found for: {}", server_addr_str))?;
+            .with_context(|| format!("Failed to resolve server address: {}", config.host))?
+            .find(|addr| config.address_family.matches(addr))
+            .with_context(|| {
+                format!("No {:?} address found for: {}", config.address_family, config.host)
+            })?;
+        debug!("Using server address {}", server_addr);
         
-        // Create UDP socket
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .context("Failed to bind UDP socket")?;
+        // Create UDP socket of the server's address family, on the test interface
+        let socket = interface.udp_socket(server_addr)?;
         
         // Set timeouts
         socket
@@ -115,40 +273,75 @@ impl ServerTester {
         
         info!(
             "Server tester initialized for {}:{} (interface: {})",
-            host, port, interface
+            host, port, interface.label()
         );
         
+        // First run of each tier happens one interval after startup
+        let throughput = &tests.throughput;
+        let throughput_tiers = [
+            ("large", throughput.large_test_kb, throughput.large_interval_s),
+            ("medium", throughput.medium_test_kb, throughput.medium_interval_s),
+            ("small", throughput.small_test_kb, throughput.small_interval_s),
+        ]
+        .into_iter()
+        .filter(|(_, size_kb, interval_s)| *size_kb > 0 && *interval_s > 0)
+        .map(|(name, size_kb, interval_s)| ThroughputTier {
+            name,
+            size_bytes: size_kb * 1024,
+            interval: Duration::from_secs(interval_s),
+            last_run: Instant::now(),
+        })
+        .collect();
+        
         Ok(Self {
             config,
+            tests,
             socket,
             server_addr,
             shared_secret,
             client_id,
-            session_id: None,
-            interface,
-            connection_type,
+            session: None,
+            server_error: Cell::new(None),
+            backoff_until: None,
+            unsupported: Vec::new(),
+            disabled: false,
+            interface: interface.label(),
+            connection_type: interface.connection_type.clone(),
             sequence: 0,
+            echo_timeouts: 0,
+            rtt_jitter: JitterTracker::default(),
+            upload_jitter: JitterTracker::default(),
+            download_jitter: JitterTracker::default(),
             time_sync: TimeSyncState::new(),
+            throughput_tiers,
+            last_download: Instant::now(),
+            last_bufferbloat: Instant::now(),
+            next_test_id: rand::random(),
         })
     }
     
+    /// Tag later measurements with a new connection type
+    pub fn set_connection_type(&mut self, connection_type: String) {
+        self.connection_type = connection_type;
+    }
+    
     /// Authenticate with server (port knocking)
     pub fn authenticate(&mut self) -> Result<()> {
         for attempt in 1..=self.config.knock_retry_attempts {
             debug!("Authentication attempt {}/{}", attempt, self.config.knock_retry_attempts);
             
-            match self.send_knock() {
-                Ok(session_id) => {
-                    self.session_id = Some(session_id);
-                    
-                    // Reset time sync on new session
-                    self.time_sync = TimeSyncState::new();
-                    
-                    info!("Authenticated with server {} (session_id: {})", self.server_addr, session_id);
+            match self.knock() {
+                Ok(session) => {
+                    info!("Authenticated with server {} (session_id: {})", self.server_addr, session.id);
+                    self.start_session(session);
                     return Ok(());
                 }
                 Err(e) => {
                     warn!("Authentication attempt {} failed: {}", attempt, e);
+                    // Knocking again won't change the server's versions
+                    if self.server_error.get().is_some_and(|error| error.code == ErrorCode::UnsupportedVersion) {
+                        break;
+                    }
                     if attempt < self.config.knock_retry_attempts {
                         std::thread::sleep(Duration::from_millis(500));
                     }
@@ -159,29 +352,109 @@ impl ServerTester {
         anyhow::bail!("Failed to authenticate after {} attempts", self.config.knock_retry_attempts)
     }
     
-    /// Send KNOCK packet and wait for KNOCK_ACK
-    fn send_knock(&mut self) -> Result<u64> {
-        // Create knock payload
-        let knock = KnockPayload::new();
-        let knock_bytes = knock.to_bytes();
-        
-        // Create packet header
-        let header = PacketHeader::new(
-            PacketType::Knock,
-            (knock_bytes.len() + crypto::TAG_SIZE) as u16,
-            self.client_id,
-        );
+    /// Send the knock port sequence (if enabled) and a KNOCK
+    fn knock(&mut self) -> Result<ServerSession> {
+        if self.config.port_knocking {
+            self.send_knock_sequence()?;
+        }
+        self.send_knock()
+    }
+    
+    /// Send one datagram to each of the knock ports, in order
+    ///
+    /// The server only answers KNOCKs from addresses that did this shortly
+    /// before.
+    fn send_knock_sequence(&self) -> Result<()> {
+        for &port in &self.config.knock_ports {
+            let addr = SocketAddr::new(self.server_addr.ip(), port);
+            self.socket
+                .send_to(&rand::random::<[u8; 8]>(), addr)
+                .with_context(|| format!("Failed to send knock to port {}", port))?;
+            std::thread::sleep(KNOCK_PORT_GAP);
+        }
         
-        // Encrypt payload
-        let nonce = header.nonce();
-        let header_bytes = header.to_bytes();
-        let encrypted = crypto::encrypt(&knock_bytes, &self.shared_secret, &nonce, &header_bytes)
-            .context("Failed to encrypt KNOCK packet")?;
+        debug!("Sent knock sequence {:?} to {}", self.config.knock_ports, self.server_addr.ip());
+        Ok(())
+    }
+    
+    /// Apply an ERROR the server sent
+    fn handle_server_error(&mut self, error: ErrorPayload) {
+        match error.code {
+            ErrorCode::InvalidSession => {
+                // Session expired on the server - knock again
+                info!("Server {} rejected session, re-authenticating", self.server_addr);
+                self.session = None;
+            }
+            ErrorCode::RateLimited | ErrorCode::ServerOverloaded => {
+                let backoff = error.retry_after.unwrap_or(DEFAULT_BACKOFF);
+                warn!(
+                    "Server {} reported {:?} for {:?}, backing off for {:?}",
+                    self.server_addr, error.code, error.request, backoff
+                );
+                self.backoff_until = Some(Instant::now() + backoff);
+            }
+            ErrorCode::UnsupportedVersion => {
+                error!(
+                    "Server {} does not support protocol version {}, disabling server tests",
+                    self.server_addr, protocol::PROTOCOL_VERSION
+                );
+                self.disabled = true;
+            }
+            ErrorCode::UnsupportedRequest => {
+                warn!(
+                    "Server {} does not support {:?}, disabling the test using it",
+                    self.server_addr, error.request
+                );
+                if !self.unsupported.contains(&error.request) {
+                    self.unsupported.push(error.request);
+                }
+            }
+            ErrorCode::TestRejected => {
+                warn!("Server {} rejected {:?}", self.server_addr, error.request);
+            }
+        }
+    }
+    
+    /// Check that the server offers a test and hasn't refused any of its
+    /// requests as unsupported
+    ///
+    /// Before the first KNOCK_ACK everything counts as offered.
+    fn supports(&self, capability: Capabilities, requests: &[PacketType]) -> bool {
+        self.session.as_ref().is_none_or(|session| session.capabilities.contains(capability))
+            && !requests.iter().any(|request| self.unsupported.contains(request))
+    }
+    
+    /// Switch to a new session
+    fn start_session(&mut self, session: ServerSession) {
+        self.session = Some(session);
+        self.echo_timeouts = 0;
+        
+        // Reset time sync on new session
+        self.time_sync = TimeSyncState::new();
+    }
+    
+    /// Key and ID of the current session
+    fn session(&self) -> Result<&ServerSession> {
+        self.session.as_ref().context("Not authenticated")
+    }
+    
+    /// Send KNOCK packet and wait for KNOCK_ACK
+    ///
+    /// KNOCK and KNOCK_ACK are encrypted with the long-term secret; the
+    /// session key comes from the X25519 keys they carry. KNOCK offers our
+    /// protocol versions, KNOCK_ACK tells which one the server picked and
+    /// which tests it offers.
+    fn send_knock(&mut self) -> Result<ServerSession> {
+        // Create knock payload with a fresh ephemeral key
+        let exchange = KeyExchange::new();
+        let client_public = exchange.public_key();
+        let knock = KnockPayload::new(client_public);
         
-        // Build packet
-        let mut packet = Vec::with_capacity(PacketHeader::SIZE + encrypted.len());
-        packet.extend_from_slice(&header_bytes);
-        packet.extend_from_slice(&encrypted);
+        // No session yet; the lowest version we speak so any server can
+        // read it
+        let ids = PacketIds::new(self.client_id, 0).with_version(protocol::MIN_PROTOCOL_VERSION);
+        let packet = Packet::seal(&knock, ids, Direction::ClientToServer, &self.shared_secret)
+            .context("Failed to seal KNOCK packet")?;
         
         // Send packet
         self.socket
@@ -197,34 +470,49 @@ impl ServerTester {
             .recv_from(&mut buf)
             .context("Failed to receive KNOCK_ACK")?;
         
-        // Parse response header
-        let response_header = PacketHeader::from_bytes(&buf[..len])
-            .context("Invalid KNOCK_ACK header")?;
+        let response = Packet::open(&buf[..len], Direction::ServerToClient, &self.shared_secret)
+            .context("Failed to open KNOCK_ACK")?;
         
-        if response_header.packet_type != PacketType::KnockAck {
-            anyhow::bail!("Expected KNOCK_ACK, got {:?}", response_header.packet_type);
+        if response.header.packet_type == PacketType::Error {
+            let error: ErrorPayload = response.decode().context("Invalid ERROR payload")?;
+            self.server_error.set(Some(error));
+            anyhow::bail!("Server replied {:?} to KNOCK", error.code);
         }
         
-        // Decrypt response
-        let response_nonce = response_header.nonce();
-        let response_header_bytes = response_header.to_bytes();
-        let encrypted_payload = &buf[PacketHeader::SIZE..len];
+        let ack: KnockAckPayload = response.decode().context("Invalid KNOCK_ACK payload")?;
+        let response_header = response.header;
         
-        let decrypted = crypto::decrypt(
-            encrypted_payload,
-            &self.shared_secret,
-            &response_nonce,
-            &response_header_bytes,
-        )
-        .context("Failed to decrypt KNOCK_ACK")?;
+        if ack.version != response_header.version {
+            anyhow::bail!(
+                "KNOCK_ACK picks protocol version {} but was sent as version {}",
+                ack.version,
+                response_header.version
+            );
+        }
         
-        // Parse KNOCK_ACK payload
-        let ack = KnockAckPayload::from_bytes(&decrypted)
-            .context("Invalid KNOCK_ACK payload")?;
+        debug!(
+            "Received KNOCK_ACK: session_id={}, protocol v{}, capabilities {:#x}, limits {:?}",
+            ack.session_id, ack.version, ack.capabilities.0, ack.limits
+        );
         
-        debug!("Received KNOCK_ACK: session_id={}", ack.session_id);
+        let dh_result = exchange
+            .diffie_hellman(&ack.public_key)
+            .context("Key exchange with server failed")?;
+        let key = crypto::derive_session_key(
+            &self.shared_secret,
+            &dh_result,
+            &client_public,
+            &ack.public_key,
+            ack.session_id,
+        );
         
-        Ok(ack.session_id)
+        Ok(ServerSession {
+            id: ack.session_id,
+            key,
+            version: ack.version,
+            capabilities: ack.capabilities.intersection(Capabilities::ALL),
+            limits: ack.limits,
+        })
     }
     
     /// Update time synchronization state with a new measurement
@@ -296,7 +584,7 @@ impl ServerTester {
                 "Time sync quality low for {}: {}% (std_dev={:.2}ms, samples={})",
                 self.config.host, self.time_sync.quality, std_dev_ms, self.time_sync.offset_samples.len()
             );
-        } else if self.sequence <= 10 || self.sequence % 100 == 0 {
+        } else if self.sequence <= 10 || self.sequence.is_multiple_of(100) {
             debug!(
                 "Time sync for {}: offset={:.2}ms, quality={}%, samples={}",
                 self.config.host,
@@ -307,26 +595,91 @@ impl ServerTester {
         }
     }
     
-    /// Run echo test (send ECHO_REQUEST, wait for ECHO_REPLY)
+    /// Run all enabled server tests that are due
     pub fn run_test(&mut self) -> Result<Vec<Measurement>> {
-        if !self.config.enable_echo_test {
+        // React to what the server complained about since the last run
+        if let Some(error) = self.server_error.take() {
+            self.handle_server_error(error);
+        }
+        
+        let echo_enabled = self.config.enable_echo_test
+            && self.supports(Capabilities::ECHO, &[PacketType::EchoRequest]);
+        let throughput_enabled = self.config.enable_throughput_test
+            && self.tests.throughput.enabled
+            && self.supports(Capabilities::UPLOAD, &[PacketType::ThroughputStart, PacketType::ThroughputEnd]);
+        let download_enabled = self.config.enable_download_test
+            && self.tests.download.enabled
+            && self.supports(Capabilities::DOWNLOAD, &[PacketType::DownloadRequest]);
+        let bufferbloat_enabled = self.config.enable_bufferbloat_test
+            && self.tests.bufferbloat.enabled
+            && self.supports(
+                Capabilities::BUFFERBLOAT | Capabilities::ECHO,
+                &[PacketType::BufferbloatStart, PacketType::BufferbloatEnd],
+            );
+        if self.disabled || (!echo_enabled && !throughput_enabled && !download_enabled && !bufferbloat_enabled) {
             return Ok(Vec::new());
         }
         
-        // Ensure we're authenticated
-        if self.session_id.is_none() {
-            if let Err(e) = self.authenticate() {
-                // Authentication failed - create error measurement
-                let mut measurement = Measurement::new_server_echo(
-                    self.config.host.clone(),
-                    self.interface.clone(),
-                    self.connection_type.clone(),
-                );
-                measurement.set_error(format!("Authentication failed: {}", e));
-                return Ok(vec![measurement]);
+        // Server asked us to slow down
+        if let Some(until) = self.backoff_until {
+            if Instant::now() < until {
+                return Ok(Vec::new());
+            }
+            self.backoff_until = None;
+        }
+        
+        // Server may have lost our session - try a new one, but keep the old
+        // one if the knock fails too (the network may just be down)
+        if self.echo_timeouts >= RE_KNOCK_AFTER_TIMEOUTS && self.session.is_some() {
+            self.echo_timeouts = 0;
+            match self.knock() {
+                Ok(session) => {
+                    info!(
+                        "Re-authenticated with server {} after {} echo timeouts (session_id: {})",
+                        self.server_addr, RE_KNOCK_AFTER_TIMEOUTS, session.id
+                    );
+                    self.start_session(session);
+                }
+                Err(e) => debug!("Re-knock to {} failed: {:#}", self.server_addr, e),
             }
         }
         
+        // Ensure we're authenticated
+        if self.session.is_none()
+            && let Err(e) = self.authenticate() {
+            // Authentication failed - create error measurement
+            let mut measurement = Measurement::new_server_echo(
+                self.config.host.clone(),
+                self.interface.clone(),
+                self.connection_type.clone(),
+            );
+            measurement.set_error(format!("Authentication failed: {}", e));
+            return Ok(vec![measurement]);
+        }
+        
+        let mut measurements = Vec::new();
+        
+        if echo_enabled {
+            measurements.extend(self.run_echo_test()?);
+        }
+        
+        if throughput_enabled {
+            measurements.extend(self.run_due_throughput_test());
+        }
+        
+        if download_enabled {
+            measurements.extend(self.run_due_download_test());
+        }
+        
+        if bufferbloat_enabled {
+            measurements.extend(self.run_due_bufferbloat_test());
+        }
+        
+        Ok(measurements)
+    }
+    
+    /// Run echo test (send ECHO_REQUEST, wait for ECHO_REPLY)
+    fn run_echo_test(&mut self) -> Result<Vec<Measurement>> {
         // Increment sequence number
         self.sequence += 1;
         
@@ -351,8 +704,9 @@ impl ServerTester {
             Ok(r) => r,
             Err(e) => {
                 // Check if it's a timeout or other error
-                let error_msg = e.to_string();
-                if error_msg.contains("timeout") || error_msg.contains("timed out") {
+                let error_msg = format!("{:#}", e);
+                if is_timeout(&e) {
+                    self.echo_timeouts += 1;
                     measurement.set_timeout();
                     debug!("Server {} -> timeout", self.config.host);
                 } else {
@@ -364,6 +718,7 @@ impl ServerTester {
         };
         
         let end_instant = Instant::now();
+        self.echo_timeouts = 0;
         
         // Calculate RTT using monotonic clock
         let rtt = end_instant
@@ -400,6 +755,10 @@ impl ServerTester {
         measurement.rtt_ms = Some(rtt);
         measurement.packet_loss_pct = Some(0.0); // Successful = 0% loss
         measurement.status = "success".to_string();
+        if let Some(sample) = self.rtt_jitter.update(rtt) {
+            measurement.jitter_ms = Some(sample.jitter_ms);
+            measurement.rtt_delta_ms = Some(sample.delta_ms);
+        }
         
         // Track previous sync state for event detection
         let prev_synced = self.time_sync.was_synced;
@@ -421,6 +780,12 @@ impl ServerTester {
                 measurement.upload_latency_ms = Some(upload_latency_ns / 1_000_000.0);
                 measurement.download_latency_ms = Some(download_latency_ns / 1_000_000.0);
                 measurement.server_processing_us = Some((server_processing_ns / 1_000.0) as i64);
+                measurement.upload_jitter_ms = self.upload_jitter
+                    .update(upload_latency_ns / 1_000_000.0)
+                    .map(|sample| sample.jitter_ms);
+                measurement.download_jitter_ms = self.download_jitter
+                    .update(download_latency_ns / 1_000_000.0)
+                    .map(|sample| sample.jitter_ms);
                 
                 debug!(
                     "Server {} -> rtt={:.2}ms, upload={:.2}ms, download={:.2}ms, processing={:.0}μs, sync_quality={}%",
@@ -443,6 +808,8 @@ impl ServerTester {
                 
                 self.time_sync.is_synced = false;
                 self.time_sync.quality = 0;
+                self.upload_jitter.reset();
+                self.download_jitter.reset();
                 measurement.upload_latency_ms = None;
                 measurement.download_latency_ms = None;
                 measurement.server_processing_us = None;
@@ -456,11 +823,14 @@ impl ServerTester {
             }
         } else {
             // Not synced - only store RTT
+            // One-way latencies from before a sync loss aren't comparable with later ones
+            self.upload_jitter.reset();
+            self.download_jitter.reset();
             measurement.upload_latency_ms = None;
             measurement.download_latency_ms = None;
             measurement.server_processing_us = None;
             
-            if self.sequence % 10 == 0 {
+            if self.sequence.is_multiple_of(10) {
                 debug!(
                     "Server {} -> rtt={:.2}ms, time sync not ready ({}/8 samples, quality={}%)",
                     self.config.host,
@@ -504,64 +874,1034 @@ impl ServerTester {
     
     /// Send ECHO_REQUEST and wait for ECHO_REPLY
     fn send_echo_request(&self, request: &EchoRequestPayload) -> Result<EchoReplyPayload> {
-        let request_bytes = request.to_bytes();
+        self.send_packet(request)
+            .context("Failed to send ECHO_REQUEST")?;
         
-        // Create packet header
-        let header = PacketHeader::new(
-            PacketType::EchoRequest,
-            (request_bytes.len() + crypto::TAG_SIZE) as u16,
-            self.client_id,
+        // Wait for ECHO_REPLY (skipping leftovers from throughput tests)
+        self.recv_packet::<EchoReplyPayload>()
+            .context("Failed to receive ECHO_REPLY")
+    }
+    
+    /// Run the largest upload test tier whose interval has elapsed
+    ///
+    /// At most one tier runs per call so a large test never piles up with
+    /// smaller ones in the same tick; running a tier also resets the timers
+    /// of the smaller tiers since that test covered them.
+    fn run_due_throughput_test(&mut self) -> Option<Measurement> {
+        let now = Instant::now();
+        let due = self.throughput_tiers
+            .iter()
+            .position(|tier| now.duration_since(tier.last_run) >= tier.interval)?;
+        
+        for tier in &mut self.throughput_tiers[due..] {
+            tier.last_run = now;
+        }
+        
+        let tier_name = self.throughput_tiers[due].name;
+        let size_bytes = self.throughput_tiers[due].size_bytes;
+        
+        let mut measurement = Measurement::new_throughput(
+            "throughput_up",
+            self.config.host.clone(),
+            self.interface.clone(),
+            self.connection_type.clone(),
         );
+        measurement.server_name = Some(self.config.host.clone());
         
-        // Encrypt payload
-        let nonce = header.nonce();
-        let header_bytes = header.to_bytes();
-        let encrypted = crypto::encrypt(&request_bytes, &self.shared_secret, &nonce, &header_bytes)
-            .context("Failed to encrypt ECHO_REQUEST")?;
+        match self.run_upload_test(size_bytes) {
+            Ok(UploadResult { stats, rate_limited }) => {
+                measurement.throughput_kbps = Some(stats.throughput_kbps as f64);
+                measurement.status = "success".to_string();
+                if rate_limited {
+                    // Dropped by the server on purpose, not lost on the path
+                    measurement.error_detail = Some(format!(
+                        "Server rate limit dropped data ({:.2}% loss not attributable to the path)",
+                        stats.packet_loss_pct
+                    ));
+                    warn!(
+                        "Upload test ({}) to {} exceeded the server's rate limit, packet loss not recorded",
+                        tier_name, self.config.host
+                    );
+                } else {
+                    measurement.packet_loss_pct = Some(stats.packet_loss_pct as f64);
+                }
+                
+                info!(
+                    "Upload test ({}, {} KB) to {}: {} kbps, {:.2}% loss, {} bytes in {}ms",
+                    tier_name,
+                    size_bytes / 1024,
+                    self.config.host,
+                    stats.throughput_kbps,
+                    stats.packet_loss_pct,
+                    stats.total_bytes,
+                    stats.duration_ms
+                );
+            }
+            Err(e) => {
+                if is_timeout(&e) {
+                    measurement.set_timeout();
+                } else {
+                    measurement.set_error(format!("{:#}", e));
+                }
+                warn!("Upload test ({}) to {} failed: {:#}", tier_name, self.config.host, e);
+            }
+        }
         
-        // Build packet
-        let mut packet = Vec::with_capacity(PacketHeader::SIZE + encrypted.len());
-        packet.extend_from_slice(&header_bytes);
-        packet.extend_from_slice(&encrypted);
+        Some(measurement)
+    }
+    
+    /// Upload `total_size` bytes and return the server's view of the transfer
+    ///
+    /// Data is paced to the bandwidth limit the server announced, which it
+    /// enforces by dropping the excess.
+    fn run_upload_test(&mut self, total_size: u64) -> Result<UploadResult> {
+        let test_id = self.next_test_id;
+        self.next_test_id = self.next_test_id.wrapping_add(1);
         
-        // Send packet
-        self.socket
-            .send_to(&packet, self.server_addr)
-            .context("Failed to send ECHO_REQUEST")?;
+        let rate_kbps = self.session()?.limits.clamp_rate_kbps(0);
+        let bytes_per_sec = rate_kbps as f64 * 1000.0 / 8.0;
+        
+        let start = ThroughputStartPayload { test_id, total_size };
+        self.send_packet(&start)
+            .context("Failed to send THROUGHPUT_START")?;
+        
+        let started_at = Instant::now();
+        let mut wire_bytes: u64 = 0;
+        let mut sent: u64 = 0;
+        let mut sequence: u32 = 0;
+        while sent < total_size {
+            // The server charges whole datagrams against its limit
+            if rate_kbps > 0 {
+                let due = started_at + Duration::from_secs_f64(wire_bytes as f64 / bytes_per_sec);
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+            
+            let chunk_len = (total_size - sent).min(THROUGHPUT_CHUNK_SIZE as u64) as usize;
+            let data = ThroughputDataPayload {
+                test_id,
+                sequence,
+                data: vec![0u8; chunk_len],
+            };
+            wire_bytes += self.send_packet(&data)
+                .context("Failed to send THROUGHPUT_DATA")? as u64;
+            
+            sent += chunk_len as u64;
+            sequence = sequence.wrapping_add(1);
+        }
+        
+        debug!(
+            "Sent {} bytes in {} chunks for test_id={} (paced to {} kbps, 0 = unpaced)",
+            sent, sequence, test_id, rate_kbps
+        );
+        
+        // THROUGHPUT_END may get lost just like data, so retry a few times;
+        // the server answers repeated ENDs with the same stats
+        let end = ThroughputEndPayload { test_id, total_bytes: sent };
+        let mut rate_limited = false;
+        let mut last_error = None;
+        for attempt in 1..=3 {
+            self.send_packet(&end)
+                .context("Failed to send THROUGHPUT_END")?;
+            
+            match self.recv_throughput_stats(test_id, &mut rate_limited) {
+                Ok(stats) => return Ok(UploadResult { stats, rate_limited }),
+                Err(e) => {
+                    debug!("No THROUGHPUT_STATS for test_id={} (attempt {}): {}", test_id, attempt, e);
+                    last_error = Some(e);
+                }
+            }
+        }
         
-        // Wait for ECHO_REPLY
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("No matching THROUGHPUT_STATS received"))
+            .context("Failed to receive THROUGHPUT_STATS"))
+    }
+    
+    /// Wait for the THROUGHPUT_STATS of `test_id`
+    ///
+    /// RATE_LIMITED notices about THROUGHPUT_DATA, queued up while sending,
+    /// only set `rate_limited`: they neither end the wait nor make the
+    /// other tests back off.
+    fn recv_throughput_stats(&self, test_id: u32, rate_limited: &mut bool) -> Result<ThroughputStatsPayload> {
+        loop {
+            match self.recv_packet::<ThroughputStatsPayload>() {
+                Ok(stats) if stats.test_id == test_id => return Ok(stats),
+                Ok(stats) => debug!("Ignoring THROUGHPUT_STATS for stale test_id={}", stats.test_id),
+                Err(e) => match self.server_error.get() {
+                    Some(error)
+                        if error.code == ErrorCode::RateLimited
+                            && error.request == PacketType::ThroughputData =>
+                    {
+                        self.server_error.set(None);
+                        *rate_limited = true;
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+    
+    /// Run the download test if its interval has elapsed
+    fn run_due_download_test(&mut self) -> Option<Measurement> {
+        let interval = Duration::from_secs(self.tests.download.interval_s);
+        if self.tests.download.test_kb == 0 || self.last_download.elapsed() < interval {
+            return None;
+        }
+        self.last_download = Instant::now();
+        
+        // Stay within what the server announced
+        let mut size_bytes = self.tests.download.test_kb * 1024;
+        let mut rate_kbps = self.tests.download.rate_kbps;
+        if let Some(session) = &self.session {
+            size_bytes = size_bytes.min(session.limits.max_download_bytes);
+            rate_kbps = session.limits.clamp_rate_kbps(rate_kbps);
+        }
+        
+        let mut measurement = Measurement::new_throughput(
+            "throughput_down",
+            self.config.host.clone(),
+            self.interface.clone(),
+            self.connection_type.clone(),
+        );
+        measurement.server_name = Some(self.config.host.clone());
+        
+        match self.run_download_test(size_bytes, rate_kbps) {
+            Ok(result) => {
+                measurement.throughput_kbps = Some(result.throughput_kbps());
+                measurement.packet_loss_pct = Some(result.packet_loss_pct());
+                measurement.status = "success".to_string();
+                
+                info!(
+                    "Download test ({} KB) from {}: {:.0} kbps, {:.2}% loss, {} bytes in {}ms",
+                    size_bytes / 1024,
+                    self.config.host,
+                    result.throughput_kbps(),
+                    result.packet_loss_pct(),
+                    result.bytes_received,
+                    result.duration.as_millis()
+                );
+            }
+            Err(e) => {
+                if is_timeout(&e) {
+                    measurement.set_timeout();
+                } else {
+                    measurement.set_error(format!("{:#}", e));
+                }
+                warn!("Download test from {} failed: {:#}", self.config.host, e);
+            }
+        }
+        
+        Some(measurement)
+    }
+    
+    /// Ask the server to stream `total_size` bytes and measure what arrives
+    ///
+    /// The test ends on DOWNLOAD_END or when no packet arrived within the
+    /// socket read timeout.
+    fn run_download_test(&mut self, total_size: u64, rate_kbps: u32) -> Result<DownloadResult> {
+        let test_id = self.next_test_id;
+        self.next_test_id = self.next_test_id.wrapping_add(1);
+        
+        let request = DownloadRequestPayload { test_id, total_size, rate_kbps };
+        self.send_packet(&request)
+            .context("Failed to send DOWNLOAD_REQUEST")?;
+        
+        let mut bytes_received: u64 = 0;
+        let mut packets_received: u32 = 0;
+        let mut first_arrival: Option<Instant> = None;
+        let mut last_arrival: Option<Instant> = None;
+        let mut packets_sent: Option<u32> = None;
         let mut buf = vec![0u8; 4096];
-        let (len, _) = self
-            .socket
-            .recv_from(&mut buf)
-            .context("Failed to receive ECHO_REPLY")?;
         
-        // Parse response header
-        let response_header = PacketHeader::from_bytes(&buf[..len])
-            .context("Invalid ECHO_REPLY header")?;
+        loop {
+            let packet = match self.recv_any(&mut buf) {
+                Ok(packet) => packet,
+                Err(e) if packets_received > 0 => {
+                    // Stream stalled or DOWNLOAD_END got lost - use what we have
+                    debug!("Download test_id={} ended without DOWNLOAD_END: {}", test_id, e);
+                    break;
+                }
+                Err(e) => return Err(e.context("No DOWNLOAD_DATA received")),
+            };
+            
+            match packet.header.packet_type {
+                PacketType::DownloadData => {
+                    let data: DownloadDataPayload = packet.decode()
+                        .context("Invalid DOWNLOAD_DATA payload")?;
+                    if data.test_id != test_id {
+                        continue;
+                    }
+                    
+                    let now = Instant::now();
+                    first_arrival.get_or_insert(now);
+                    last_arrival = Some(now);
+                    bytes_received += data.data.len() as u64;
+                    packets_received += 1;
+                }
+                PacketType::DownloadEnd => {
+                    let end: DownloadEndPayload = packet.decode()
+                        .context("Invalid DOWNLOAD_END payload")?;
+                    if end.test_id == test_id {
+                        packets_sent = Some(end.packets_sent);
+                        break;
+                    }
+                }
+                other => debug!("Skipping {:?} during download test", other),
+            }
+        }
+        
+        let (Some(first), Some(last)) = (first_arrival, last_arrival) else {
+            anyhow::bail!("Download test ended without any DOWNLOAD_DATA");
+        };
+        
+        let packets_sent = packets_sent.unwrap_or_else(|| {
+            total_size.div_ceil(THROUGHPUT_CHUNK_SIZE as u64) as u32
+        });
+        
+        Ok(DownloadResult {
+            bytes_received,
+            packets_received,
+            packets_sent,
+            duration: last.duration_since(first),
+        })
+    }
+    
+    /// Run the bufferbloat test if its interval has elapsed
+    fn run_due_bufferbloat_test(&mut self) -> Vec<Measurement> {
+        let interval = Duration::from_secs(self.tests.bufferbloat.interval_s);
+        if self.tests.bufferbloat.test_duration_s == 0 || self.last_bufferbloat.elapsed() < interval {
+            return Vec::new();
+        }
+        
+        info!(
+            "Starting bufferbloat test ({}, {}s per load phase) with {}",
+            self.tests.bufferbloat.test_type, self.tests.bufferbloat.test_duration_s, self.config.host
+        );
+        
+        let result = self.run_bufferbloat_test();
+        self.last_bufferbloat = Instant::now();
         
-        if response_header.packet_type != PacketType::EchoReply {
-            anyhow::bail!("Expected ECHO_REPLY, got {:?}", response_header.packet_type);
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                let mut measurement = self.new_bufferbloat_measurement(bufferbloat_test_type(
+                    self.tests.bufferbloat.directions()[0],
+                ));
+                if is_timeout(&e) {
+                    measurement.set_timeout();
+                } else {
+                    measurement.set_error(format!("{:#}", e));
+                }
+                warn!("Bufferbloat test with {} failed: {:#}", self.config.host, e);
+                return vec![measurement];
+            }
+        };
+        
+        let Some(idle_ms) = result.idle.median_rtt_ms() else {
+            let mut measurement = self.new_bufferbloat_measurement(bufferbloat_test_type(
+                self.tests.bufferbloat.directions()[0],
+            ));
+            measurement.set_timeout();
+            warn!("Bufferbloat test with {} failed: no echo replies while idle", self.config.host);
+            return vec![measurement];
+        };
+        
+        let mut measurements = Vec::new();
+        let phases = result.loaded.iter()
+            .map(|(direction, phase)| (bufferbloat_test_type(*direction), phase))
+            .chain(std::iter::once(("bufferbloat_recovery", &result.recovery)));
+        
+        for (test_type, phase) in phases {
+            let mut measurement = self.new_bufferbloat_measurement(test_type);
+            measurement.packet_loss_pct = Some(phase.probe_loss_pct());
+            measurement.throughput_kbps = phase.load_kbps;
+            if phase.rate_limited {
+                // Dropped by the server on purpose, not by the loaded link
+                measurement.error_detail = Some("Server rate limit dropped upload load".to_string());
+                warn!(
+                    "Bufferbloat {} with {} exceeded the server's rate limit",
+                    test_type, self.config.host
+                );
+            }
+            
+            match phase.median_rtt_ms() {
+                Some(rtt_ms) => {
+                    let increase_ms = rtt_ms - idle_ms;
+                    measurement.rtt_ms = Some(rtt_ms);
+                    measurement.latency_increase_ms = Some(increase_ms);
+                    measurement.jitter_ms = phase.jitter_ms();
+                    measurement.status = "success".to_string();
+                    
+                    info!(
+                        "Bufferbloat {} with {}: {:.2}ms -> {:.2}ms ({:+.2}ms, {:.1}% probe loss{})",
+                        test_type,
+                        self.config.host,
+                        idle_ms,
+                        rtt_ms,
+                        increase_ms,
+                        phase.probe_loss_pct(),
+                        phase.load_kbps.map(|kbps| format!(", load {:.0} kbps", kbps)).unwrap_or_default()
+                    );
+                }
+                None => {
+                    // Every probe was lost - that's as bad as it gets
+                    measurement.set_timeout();
+                    warn!("Bufferbloat {} with {}: no echo replies", test_type, self.config.host);
+                }
+            }
+            
+            measurements.push(measurement);
         }
         
-        // Decrypt response
-        let response_nonce = response_header.nonce();
-        let response_header_bytes = response_header.to_bytes();
-        let encrypted_payload = &buf[PacketHeader::SIZE..len];
+        measurements
+    }
+    
+    fn new_bufferbloat_measurement(&self, test_type: &str) -> Measurement {
+        let mut measurement = Measurement::new_bufferbloat(
+            test_type,
+            self.config.host.clone(),
+            self.interface.clone(),
+            self.connection_type.clone(),
+        );
+        measurement.server_name = Some(self.config.host.clone());
+        measurement
+    }
+    
+    /// Measure echo latency while idle, under load for each configured
+    /// direction and again after the load stopped
+    ///
+    /// The socket is switched to non-blocking mode for the duration of the
+    /// test so probes, load and replies can be interleaved.
+    fn run_bufferbloat_test(&mut self) -> Result<BufferbloatResult> {
+        self.socket.set_nonblocking(true)
+            .context("Failed to switch socket to non-blocking mode")?;
+        
+        let result = self.run_bufferbloat_phases();
         
-        let decrypted = crypto::decrypt(
-            encrypted_payload,
-            &self.shared_secret,
-            &response_nonce,
-            &response_header_bytes,
-        )
-        .context("Failed to decrypt ECHO_REPLY")?;
+        self.socket.set_nonblocking(false)
+            .context("Failed to switch socket back to blocking mode")?;
+        result
+    }
+    
+    fn run_bufferbloat_phases(&mut self) -> Result<BufferbloatResult> {
+        let idle_duration = Duration::from_secs(self.tests.bufferbloat.idle_duration_s);
+        let mut load_duration = Duration::from_secs(self.tests.bufferbloat.test_duration_s);
+        if let Ok(session) = self.session() {
+            load_duration = load_duration.min(Duration::from_millis(session.limits.max_phase_ms as u64));
+        }
+        
+        let idle = self.run_probe_phase(idle_duration, None)
+            .context("Idle phase failed")?;
+        
+        let mut loaded = Vec::new();
+        for direction in self.tests.bufferbloat.directions() {
+            let phase = self.run_probe_phase(load_duration, Some(direction))
+                .with_context(|| format!("{:?} load phase failed", direction))?;
+            loaded.push((direction, phase));
+        }
+        
+        let recovery = self.run_probe_phase(idle_duration, None)
+            .context("Recovery phase failed")?;
+        
+        Ok(BufferbloatResult { idle, loaded, recovery })
+    }
+    
+    /// Send echo probes for `duration`, optionally saturating one direction
+    ///
+    /// After the phase, replies are collected for up to knock_timeout_ms so
+    /// that probes delayed by a full buffer are not counted as lost.
+    fn run_probe_phase(
+        &mut self,
+        duration: Duration,
+        load: Option<BufferbloatDirection>,
+    ) -> Result<ProbePhase> {
+        let probe_interval = Duration::from_millis(self.tests.bufferbloat.probe_interval_ms.max(1));
+        let rate_kbps = self.session()?.limits.clamp_rate_kbps(self.tests.bufferbloat.rate_kbps);
+        let bytes_per_sec = rate_kbps as f64 * 1000.0 / 8.0;
+        
+        let test_id = self.next_test_id;
+        self.next_test_id = self.next_test_id.wrapping_add(1);
+        
+        let mut phase = ProbePhase {
+            first_sequence: self.sequence.wrapping_add(1),
+            ..Default::default()
+        };
+        
+        if let Some(direction) = load {
+            let start = BufferbloatStartPayload {
+                test_id,
+                direction,
+                duration_ms: duration.as_millis() as u32,
+                rate_kbps,
+            };
+            self.send_packet(&start)
+                .context("Failed to send BUFFERBLOAT_START")?;
+        }
+        
+        let started_at = Instant::now();
+        let phase_end = started_at + duration;
+        let mut next_probe = started_at;
+        let mut upload_sequence: u32 = 0;
+        let mut wire_bytes: u64 = 0;
+        let mut buf = vec![0u8; 4096];
+        
+        while Instant::now() < phase_end {
+            let mut busy = false;
+            
+            if Instant::now() >= next_probe {
+                match self.send_probe() {
+                    Ok(()) => {}
+                    // Send buffer full of upload load - the probe is lost
+                    // just like one dropped by the saturated uplink
+                    Err(e) if is_timeout(&e) => debug!("Probe not sent: {:#}", e),
+                    Err(e) => return Err(e),
+                }
+                phase.sent += 1;
+                next_probe += probe_interval;
+            }
+            
+            if load == Some(BufferbloatDirection::Upload) {
+                // The server charges whole datagrams against its limit
+                let allowed = if rate_kbps > 0 {
+                    (started_at.elapsed().as_secs_f64() * bytes_per_sec) as u64
+                } else {
+                    u64::MAX
+                };
+                
+                for _ in 0..UPLOAD_BURST {
+                    if wire_bytes >= allowed {
+                        break;
+                    }
+                    let data = ThroughputDataPayload {
+                        test_id,
+                        sequence: upload_sequence,
+                        data: vec![0u8; THROUGHPUT_CHUNK_SIZE],
+                    };
+                    match self.send_packet(&data) {
+                        Ok(len) => {
+                            wire_bytes += len as u64;
+                            phase.load_bytes += THROUGHPUT_CHUNK_SIZE as u64;
+                            upload_sequence = upload_sequence.wrapping_add(1);
+                            busy = true;
+                        }
+                        // Socket send buffer full - the uplink is saturated
+                        Err(e) if is_timeout(&e) => break,
+                        Err(e) => return Err(e.context("Failed to send THROUGHPUT_DATA")),
+                    }
+                }
+            }
+            
+            busy |= self.poll_phase_packets(&mut phase, test_id, &mut buf)?;
+            
+            if !busy {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        
+        let load_duration = started_at.elapsed();
+        let end = load.map(|direction| BufferbloatEndPayload {
+            test_id,
+            direction,
+            total_bytes: phase.load_bytes,
+            duration_ms: load_duration.as_millis() as u32,
+        });
+        
+        // Only the upload phase is answered; the download stream stops by
+        // itself at its deadline and BUFFERBLOAT_END just cuts it short if
+        // the server's timing differs from ours
+        let expect_end = load == Some(BufferbloatDirection::Upload);
+        let max_end_attempts = if expect_end { 3 } else { 1 };
+        if load == Some(BufferbloatDirection::Download) {
+            let duration_ms = (load_duration.as_secs_f64() * 1000.0).max(1.0);
+            phase.load_kbps = Some(phase.load_bytes as f64 * 8.0 / duration_ms);
+        }
+        
+        // Collect late replies (and the upload BUFFERBLOAT_END reply); END
+        // is sent from here so a send buffer still full of upload load only
+        // delays it to the next pass
+        let drain_timeout = Duration::from_millis(self.config.knock_timeout_ms);
+        let drain_start = Instant::now();
+        let mut end_sent_at: Option<Instant> = None;
+        let mut end_attempts = 0;
+        
+        loop {
+            let probes_done = phase.rtts_ms.len() as u32 >= phase.sent;
+            let end_done = match end {
+                None => true,
+                Some(_) if expect_end => phase.end_received,
+                Some(_) => end_attempts > 0,
+            };
+            if (probes_done && end_done) || drain_start.elapsed() >= drain_timeout {
+                break;
+            }
+            
+            if let Some(ref end) = end
+                && !phase.end_received
+                && end_attempts < max_end_attempts
+                && end_sent_at.is_none_or(|t| t.elapsed() >= drain_timeout / 3)
+            {
+                match self.send_packet(end) {
+                    Ok(_) => {
+                        end_sent_at = Some(Instant::now());
+                        end_attempts += 1;
+                    }
+                    Err(e) if is_timeout(&e) => {}
+                    Err(e) => return Err(e.context("Failed to send BUFFERBLOAT_END")),
+                }
+            }
+            
+            if !self.poll_phase_packets(&mut phase, test_id, &mut buf)? {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        
+        debug!(
+            "Probe phase ({:?}): {}/{} probes answered, {} load bytes",
+            load,
+            phase.rtts_ms.len(),
+            phase.sent,
+            phase.load_bytes
+        );
+        
+        Ok(phase)
+    }
+    
+    /// Send an ECHO_REQUEST probe without waiting for the reply
+    fn send_probe(&mut self) -> Result<()> {
+        self.sequence += 1;
+        let t1_ns = self.time_sync.session_start.elapsed().as_nanos() as u64;
+        let request = EchoRequestPayload::with_timestamp(self.sequence, t1_ns);
+        self.send_packet(&request)
+            .context("Failed to send ECHO_REQUEST probe")?;
+        Ok(())
+    }
+    
+    /// Process all packets currently waiting on the (non-blocking) socket
+    ///
+    /// Returns whether any packet was received.
+    fn poll_phase_packets(&self, phase: &mut ProbePhase, test_id: u32, buf: &mut [u8]) -> Result<bool> {
+        let mut received = false;
+        
+        loop {
+            let len = match self.socket.recv_from(buf) {
+                Ok((len, _)) => len,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(received),
+                Err(e) => return Err(anyhow::Error::new(e).context("Failed to receive")),
+            };
+            received = true;
+            
+            let packet = match self.open_packet(&buf[..len]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    match self.server_error.get() {
+                        // A refused load phase won't produce anything worth measuring
+                        Some(error) if error.code == ErrorCode::TestRejected => return Err(e),
+                        // Upload load beyond the server's limit; like in the
+                        // upload test this must not make the other tests back off
+                        Some(error)
+                            if error.code == ErrorCode::RateLimited
+                                && error.request == PacketType::ThroughputData =>
+                        {
+                            self.server_error.set(None);
+                            phase.rate_limited = true;
+                        }
+                        _ => debug!("Ignoring packet during bufferbloat test: {}", e),
+                    }
+                    continue;
+                }
+            };
+            
+            match packet.header.packet_type {
+                PacketType::EchoReply => {
+                    let now_ns = self.time_sync.session_start.elapsed().as_nanos() as u64;
+                    let reply: EchoReplyPayload = packet.decode()
+                        .context("Invalid ECHO_REPLY payload")?;
+                    // Wrapping difference so probes of earlier phases are skipped
+                    if reply.sequence.wrapping_sub(phase.first_sequence) < phase.sent {
+                        let rtt_ns = now_ns.saturating_sub(reply.client_send_timestamp);
+                        phase.rtts_ms.push(rtt_ns as f64 / 1_000_000.0);
+                    }
+                }
+                PacketType::DownloadData => {
+                    let data: DownloadDataPayload = packet.decode()
+                        .context("Invalid DOWNLOAD_DATA payload")?;
+                    if data.test_id == test_id {
+                        phase.load_bytes += data.data.len() as u64;
+                    }
+                }
+                PacketType::BufferbloatEnd => {
+                    let end: BufferbloatEndPayload = packet.decode()
+                        .context("Invalid BUFFERBLOAT_END payload")?;
+                    if end.test_id == test_id && !phase.end_received {
+                        let duration_ms = end.duration_ms.max(1) as f64;
+                        phase.load_kbps = Some(end.total_bytes as f64 * 8.0 / duration_ms);
+                        phase.end_received = true;
+                    }
+                }
+                other => debug!("Skipping {:?} during bufferbloat test", other),
+            }
+        }
+    }
+    
+    /// Seal and send a packet to the server, returning its size on the wire
+    fn send_packet<P: Encode>(&self, payload: &P) -> Result<usize> {
+        let session = self.session()?;
+        let ids = PacketIds::new(self.client_id, session.id).with_version(session.version);
+        let packet = Packet::seal(payload, ids, Direction::ClientToServer, &session.key)
+            .map_err(|e| anyhow::anyhow!("Failed to seal {:?}: {}", P::PACKET_TYPE, e))?;
         
-        // Parse ECHO_REPLY payload
-        let reply = EchoReplyPayload::from_bytes(&decrypted)
-            .context("Invalid ECHO_REPLY payload")?;
+        Ok(self.socket.send_to(&packet, self.server_addr)?)
+    }
+    
+    /// Wait for a packet carrying a `P` and return its payload
+    ///
+    /// Packets of other types (e.g. a late ECHO_REPLY) are skipped until the
+    /// socket read timeout expires.
+    fn recv_packet<P: Decode>(&self) -> Result<P> {
+        let deadline = Instant::now() + Duration::from_millis(self.config.knock_timeout_ms);
+        let mut buf = vec![0u8; 4096];
         
-        Ok(reply)
+        loop {
+            let packet = self.recv_any(&mut buf)?;
+            if packet.header.packet_type == P::PACKET_TYPE {
+                return packet
+                    .decode()
+                    .with_context(|| format!("Invalid {:?} payload", P::PACKET_TYPE));
+            }
+            
+            debug!("Skipping {:?} while waiting for {:?}", packet.header.packet_type, P::PACKET_TYPE);
+            if Instant::now() >= deadline {
+                anyhow::bail!("timeout waiting for {:?}", P::PACKET_TYPE);
+            }
+        }
+    }
+    
+    /// Receive and decrypt the next packet from the server
+    fn recv_any(&self, buf: &mut [u8]) -> Result<Packet> {
+        let (len, _) = self.socket.recv_from(buf)?;
+        self.open_packet(&buf[..len])
+    }
+    
+    /// Parse and decrypt a received packet
+    ///
+    /// ERROR replies are recorded in `server_error` and returned as errors.
+    fn open_packet(&self, buf: &[u8]) -> Result<Packet> {
+        let packet = Packet::open(buf, Direction::ServerToClient, &self.session()?.key)
+            .context("Failed to open packet")?;
+        
+        if packet.header.packet_type == PacketType::Error {
+            let error: ErrorPayload = packet.decode().context("Invalid ERROR payload")?;
+            self.server_error.set(Some(error));
+            anyhow::bail!("Server replied {:?} to {:?}", error.code, error.request);
+        }
+        
+        Ok(packet)
     }
 }
 
+/// Measurement test type for a bufferbloat load direction
+fn bufferbloat_test_type(direction: BufferbloatDirection) -> &'static str {
+    match direction {
+        BufferbloatDirection::Upload => "bufferbloat_up",
+        BufferbloatDirection::Download => "bufferbloat_down",
+    }
+}
+
+/// Check whether an error was caused by a receive timeout
+///
+/// A std socket read timeout surfaces as `WouldBlock` on Unix and
+/// `TimedOut` on Windows, usually wrapped in some context.
+fn is_timeout(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(io_err) = cause.downcast_ref::<std::io::Error>() {
+            matches!(io_err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+        } else {
+            let msg = cause.to_string();
+            msg.contains("timeout") || msg.contains("timed out")
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AddressFamily;
+    
+    const SECRET: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    
+    /// Tester for a fake server listening on `server`, echo test only
+    fn tester(server: &UdpSocket, configure: impl FnOnce(&mut ServerConfig)) -> ServerTester {
+        let mut config = ServerConfig {
+            enabled: true,
+            host: "127.0.0.1".to_string(),
+            port: server.local_addr().unwrap().port(),
+            address_family: AddressFamily::Auto,
+            shared_secret: SECRET.to_string(),
+            client_id: 42,
+            knock_retry_attempts: 1,
+            knock_timeout_ms: 50,
+            port_knocking: false,
+            knock_ports: Vec::new(),
+            enable_echo_test: true,
+            enable_throughput_test: false,
+            enable_download_test: false,
+            enable_bufferbloat_test: false,
+        };
+        configure(&mut config);
+        
+        let interface = TestInterface {
+            name: None,
+            connection_type: "loopback".to_string(),
+            detect_type: false,
+        };
+        ServerTester::new(Arc::new(config), TestsConfig::default(), &interface).unwrap()
+    }
+    
+    /// Session as if the server had acknowledged a KNOCK
+    fn session() -> ServerSession {
+        ServerSession {
+            id: 7,
+            key: [2; 32],
+            version: protocol::PROTOCOL_VERSION,
+            capabilities: Capabilities::ALL,
+            limits: ServerLimits {
+                max_download_bytes: 0,
+                max_rate_kbps: 0,
+                max_phase_ms: 0,
+            },
+        }
+    }
+    
+    /// Send `payload` to the tester as the server of `session()`
+    fn reply<P: Encode>(server: &UdpSocket, client: SocketAddr, payload: &P) {
+        let ids = PacketIds::new(42, 7).with_version(protocol::PROTOCOL_VERSION);
+        let packet = Packet::seal(payload, ids, Direction::ServerToClient, &[2; 32]).unwrap();
+        server.send_to(&packet, client).unwrap();
+    }
+    
+    /// Receive the next packet the tester sent to the server of `session()`
+    fn receive(server: &UdpSocket) -> (Packet, SocketAddr) {
+        let mut buf = [0u8; 4096];
+        let (len, client) = server.recv_from(&mut buf).unwrap();
+        (Packet::open(&buf[..len], Direction::ClientToServer, &[2; 32]).unwrap(), client)
+    }
+    
+    fn bind() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        socket
+    }
+    
+    #[test]
+    fn test_re_knock_sends_knock_sequence() {
+        let server = bind();
+        let knock_ports = [bind(), bind()];
+        let mut tester = tester(&server, |config| {
+            config.port_knocking = true;
+            config.knock_ports = knock_ports.iter().map(|s| s.local_addr().unwrap().port()).collect();
+        });
+        tester.session = Some(session());
+        tester.echo_timeouts = RE_KNOCK_AFTER_TIMEOUTS;
+        
+        // Nobody answers, so the old session is kept
+        tester.run_test().unwrap();
+        assert_eq!(tester.session.as_ref().map(|session| session.id), Some(7));
+        
+        let mut buf = [0u8; 4096];
+        for socket in &knock_ports {
+            socket.recv_from(&mut buf).expect("knock port datagram");
+        }
+        let (len, _) = server.recv_from(&mut buf).unwrap();
+        let secret = crypto::parse_shared_secret(SECRET).unwrap();
+        let knock = Packet::open(&buf[..len], Direction::ClientToServer, &secret).unwrap();
+        assert_eq!(knock.header.packet_type, PacketType::Knock);
+    }
+    
+    #[test]
+    fn test_reaction_to_each_error_code() {
+        let server = bind();
+        let mut tester = tester(&server, |_| {});
+        tester.session = Some(session());
+        
+        // Only a rejected test leaves everything as it was
+        tester.handle_server_error(ErrorPayload::new(ErrorCode::TestRejected, PacketType::BufferbloatStart));
+        assert!(tester.session.is_some());
+        assert!(tester.backoff_until.is_none());
+        assert!(tester.unsupported.is_empty());
+        assert!(!tester.disabled);
+        
+        let before = Instant::now();
+        let error = ErrorPayload::new(ErrorCode::RateLimited, PacketType::EchoRequest)
+            .with_retry_after(Duration::from_secs(30));
+        tester.handle_server_error(error);
+        let backoff = tester.backoff_until.unwrap() - before;
+        assert!(backoff >= Duration::from_secs(30) && backoff < Duration::from_secs(31), "{:?}", backoff);
+        
+        // Without a hint the default backoff applies
+        tester.handle_server_error(ErrorPayload::new(ErrorCode::ServerOverloaded, PacketType::DownloadRequest));
+        assert!(tester.backoff_until.unwrap() - before < DEFAULT_BACKOFF + Duration::from_secs(1));
+        
+        tester.handle_server_error(ErrorPayload::new(ErrorCode::UnsupportedRequest, PacketType::DownloadRequest));
+        assert!(!tester.supports(Capabilities::DOWNLOAD, &[PacketType::DownloadRequest]));
+        assert!(tester.supports(Capabilities::ECHO, &[PacketType::EchoRequest]));
+        
+        tester.handle_server_error(ErrorPayload::new(ErrorCode::InvalidSession, PacketType::EchoRequest));
+        assert!(tester.session.is_none());
+        assert!(!tester.disabled);
+        
+        tester.handle_server_error(ErrorPayload::new(ErrorCode::UnsupportedVersion, PacketType::Knock));
+        assert!(tester.disabled);
+        assert!(tester.run_test().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_unsupported_version_stops_knock_retries() {
+        let server = bind();
+        let mut tester = tester(&server, |config| {
+            config.knock_retry_attempts = 3;
+            config.knock_timeout_ms = 500;
+        });
+        let secret = crypto::parse_shared_secret(SECRET).unwrap();
+        
+        let fake_server = std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            let knock = Packet::open(&buf[..len], Direction::ClientToServer, &secret).unwrap();
+            let error = ErrorPayload::new(ErrorCode::UnsupportedVersion, PacketType::Knock);
+            let reply = Packet::seal(&error, PacketIds::reply_to(&knock.header), Direction::ServerToClient, &secret)
+                .unwrap();
+            server.send_to(&reply, client).unwrap();
+            
+            // No second KNOCK follows
+            server.recv_from(&mut buf).is_err()
+        });
+        
+        assert!(tester.authenticate().is_err());
+        assert!(fake_server.join().unwrap());
+        
+        // The next run gives up on the server for good
+        assert!(tester.run_test().unwrap().is_empty());
+        assert!(tester.disabled);
+    }
+    
+    #[test]
+    fn test_rate_limited_echo_backs_off() {
+        let server = bind();
+        let mut tester = tester(&server, |config| config.knock_timeout_ms = 500);
+        tester.session = Some(session());
+        
+        let packet = std::thread::scope(|scope| {
+            let fake_server = scope.spawn(|| {
+                let (packet, client) = receive(&server);
+                let error = ErrorPayload::new(ErrorCode::RateLimited, PacketType::EchoRequest)
+                    .with_retry_after(Duration::from_secs(30));
+                reply(&server, client, &error);
+                packet
+            });
+            let measurements = tester.run_test().unwrap();
+            assert_eq!(measurements.len(), 1);
+            assert_eq!(measurements[0].status, "error");
+            fake_server.join().unwrap()
+        });
+        assert_eq!(packet.header.packet_type, PacketType::EchoRequest);
+        
+        // Nothing is sent while backing off
+        assert!(tester.run_test().unwrap().is_empty());
+        assert!(tester.backoff_until.is_some());
+        let mut buf = [0u8; 4096];
+        assert!(server.recv_from(&mut buf).is_err());
+    }
+    
+    #[test]
+    fn test_upload_is_paced_and_rate_limit_drops_are_flagged() {
+        let server = bind();
+        let mut tester = tester(&server, |config| config.knock_timeout_ms = 500);
+        let mut session = session();
+        session.limits.max_rate_kbps = 400;
+        tester.session = Some(session);
+        
+        let fake_server = std::thread::spawn(move || {
+            let mut data_packets = 0;
+            loop {
+                let (packet, client) = receive(&server);
+                match packet.header.packet_type {
+                    PacketType::ThroughputData => data_packets += 1,
+                    PacketType::ThroughputEnd => {
+                        let end: ThroughputEndPayload = packet.decode().unwrap();
+                        let notice = ErrorPayload::new(ErrorCode::RateLimited, PacketType::ThroughputData);
+                        reply(&server, client, &notice);
+                        let stats = ThroughputStatsPayload {
+                            test_id: end.test_id,
+                            total_bytes: end.total_bytes,
+                            duration_ms: 100,
+                            throughput_kbps: 400,
+                            packet_loss_pct: 10.0,
+                        };
+                        reply(&server, client, &stats);
+                        return data_packets;
+                    }
+                    _ => {}
+                }
+            }
+        });
+        
+        // 10 datagrams of ~1.2 KB at 400 kbps take at least 200ms
+        let started = Instant::now();
+        let result = tester.run_upload_test(10 * THROUGHPUT_CHUNK_SIZE as u64).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200), "{:?}", started.elapsed());
+        assert_eq!(fake_server.join().unwrap(), 10);
+        
+        assert!(result.rate_limited);
+        assert_eq!(result.stats.throughput_kbps, 400);
+        // The notice about bulk data doesn't make the other tests back off
+        assert!(tester.server_error.get().is_none());
+    }
+    
+    #[test]
+    fn test_unpaced_upload_phase_survives_full_send_buffer() {
+        let server = bind();
+        let mut tester = tester(&server, |config| config.knock_timeout_ms = 500);
+        tester.session = Some(session());
+        tester.tests.bufferbloat.rate_kbps = 0;
+        tester.tests.bufferbloat.probe_interval_ms = 10;
+        // As run_bufferbloat_test does; the unpaced load keeps the send
+        // buffer as full as the kernel lets it get
+        tester.socket.set_nonblocking(true).unwrap();
+        
+        let fake_server = std::thread::spawn(move || {
+            let mut notified = false;
+            loop {
+                let (packet, client) = receive(&server);
+                match packet.header.packet_type {
+                    PacketType::ThroughputData if !notified => {
+                        let notice = ErrorPayload::new(ErrorCode::RateLimited, PacketType::ThroughputData);
+                        reply(&server, client, &notice);
+                        notified = true;
+                    }
+                    PacketType::EchoRequest => {
+                        let request: EchoRequestPayload = packet.decode().unwrap();
+                        reply(&server, client, &EchoReplyPayload::new(&request));
+                    }
+                    PacketType::BufferbloatEnd => {
+                        let end: BufferbloatEndPayload = packet.decode().unwrap();
+                        reply(&server, client, &end);
+                        return end;
+                    }
+                    _ => {}
+                }
+            }
+        });
+        
+        let phase = tester.run_probe_phase(Duration::from_millis(200), Some(BufferbloatDirection::Upload)).unwrap();
+        let end = fake_server.join().unwrap();
+        
+        assert!(phase.end_received);
+        assert!(phase.sent > 0);
+        assert!(phase.load_bytes > 0);
+        assert_eq!(end.total_bytes, phase.load_bytes);
+        
+        // Flagged, but the other tests don't back off
+        assert!(phase.rate_limited);
+        assert!(tester.server_error.get().is_none());
+    }
+}
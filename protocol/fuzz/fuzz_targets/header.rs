@@ -0,0 +1,13 @@
+//! PacketHeader parsing (runs on every datagram before anything else)
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use protocol::packets::PacketHeader;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = PacketHeader::from_bytes(data) {
+        // Whatever parses has to serialize back to the same bytes
+        assert_eq!(header.to_bytes().as_slice(), &data[..PacketHeader::SIZE]);
+    }
+});
@@ -0,0 +1,16 @@
+//! Payload parsing; the first byte picks the payload type
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use protocol::packets::PacketType;
+use protocol_fuzz::decode_payload;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&packet_type, payload)) = data.split_first() else {
+        return;
+    };
+    if let Some(packet_type) = PacketType::from_u8(packet_type) {
+        decode_payload(packet_type, payload);
+    }
+});
@@ -0,0 +1,25 @@
+//! Helpers shared by the fuzz targets
+
+use protocol::codec::Decode;
+use protocol::packets::*;
+
+/// Parse `bytes` as the payload of `packet_type`, the way the receiving
+/// side would after decryption
+pub fn decode_payload(packet_type: PacketType, bytes: &[u8]) {
+    let _ = match packet_type {
+        PacketType::Knock => KnockPayload::decode(bytes).map(drop),
+        PacketType::KnockAck => KnockAckPayload::decode(bytes).map(drop),
+        PacketType::EchoRequest => EchoRequestPayload::decode(bytes).map(drop),
+        PacketType::EchoReply => EchoReplyPayload::decode(bytes).map(drop),
+        PacketType::ThroughputStart => ThroughputStartPayload::decode(bytes).map(drop),
+        PacketType::ThroughputData => ThroughputDataPayload::decode(bytes).map(drop),
+        PacketType::ThroughputEnd => ThroughputEndPayload::decode(bytes).map(drop),
+        PacketType::ThroughputStats => ThroughputStatsPayload::decode(bytes).map(drop),
+        PacketType::DownloadRequest => DownloadRequestPayload::decode(bytes).map(drop),
+        PacketType::DownloadData => DownloadDataPayload::decode(bytes).map(drop),
+        PacketType::DownloadEnd => DownloadEndPayload::decode(bytes).map(drop),
+        PacketType::BufferbloatStart => BufferbloatStartPayload::decode(bytes).map(drop),
+        PacketType::BufferbloatEnd => BufferbloatEndPayload::decode(bytes).map(drop),
+        PacketType::Error => ErrorPayload::decode(bytes).map(drop),
+    };
+}
@@ -0,0 +1,164 @@
+//! Packet framing shared by client and server
+//!
+//! A packet is the cleartext header followed by the payload, encrypted with
+//! ChaCha20-Poly1305 under the header as associated data. `Packet::seal`
+//! and `Packet::open` are the only places that build or take apart this
+//! framing; payload structs just implement `Encode` and `Decode`, and their
+//! packet type comes from `Payload`.
+
+use crate::crypto::{self, Direction};
+use crate::packets::{PacketError, PacketHeader, PacketType, PROTOCOL_VERSION};
+
+/// A payload and the packet type that carries it
+pub trait Payload {
+    const PACKET_TYPE: PacketType;
+}
+
+/// Serialize a payload (before encryption)
+pub trait Encode: Payload {
+    fn encode(&self) -> Vec<u8>;
+}
+
+/// Parse a payload (after decryption)
+///
+/// Trailing bytes are ignored, so fields can be appended to a payload in
+/// later protocol versions.
+pub trait Decode: Payload + Sized {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError>;
+}
+
+/// Client, session and protocol version a packet is sent under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketIds {
+    pub client_id: u64,
+    /// 0 before the KNOCK exchange
+    pub session_id: u64,
+    pub version: u8,
+}
+
+impl PacketIds {
+    pub fn new(client_id: u64, session_id: u64) -> Self {
+        Self { client_id, session_id, version: PROTOCOL_VERSION }
+    }
+    
+    /// Use another (negotiated) protocol version than our own
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+    
+    /// Same client, session and version as a received packet
+    pub fn reply_to(header: &PacketHeader) -> Self {
+        Self {
+            client_id: header.client_id,
+            session_id: header.session_id,
+            version: header.version,
+        }
+    }
+}
+
+/// A received packet with its decrypted payload
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub header: PacketHeader,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Build an encrypted packet (header + ciphertext)
+    pub fn seal<P: Encode>(
+        payload: &P,
+        ids: PacketIds,
+        direction: Direction,
+        key: &[u8; 32],
+    ) -> Result<Vec<u8>, PacketError> {
+        let plaintext = payload.encode();
+        let payload_len = u16::try_from(plaintext.len() + crypto::TAG_SIZE).map_err(|_| {
+            PacketError::InvalidPayload(format!("{} byte {:?} payload too large", plaintext.len(), P::PACKET_TYPE))
+        })?;
+        
+        let header = PacketHeader::new(P::PACKET_TYPE, payload_len, ids.client_id, ids.session_id)
+            .with_version(ids.version);
+        let header_bytes = header.to_bytes();
+        
+        let ciphertext = crypto::encrypt(&plaintext, key, &header.nonce(direction), &header_bytes)
+            .map_err(|e| PacketError::EncryptionError(e.to_string()))?;
+        
+        let mut packet = Vec::with_capacity(PacketHeader::SIZE + ciphertext.len());
+        packet.extend_from_slice(&header_bytes);
+        packet.extend_from_slice(&ciphertext);
+        Ok(packet)
+    }
+    
+    /// Parse the header and decrypt the payload of a received packet
+    pub fn open(bytes: &[u8], direction: Direction, key: &[u8; 32]) -> Result<Self, PacketError> {
+        let header = PacketHeader::from_bytes(bytes)?;
+        Self::open_with_header(header, bytes, direction, key)
+    }
+    
+    /// Decrypt a received packet whose header was already parsed
+    ///
+    /// For receivers that pick the key based on the header. Bytes after
+    /// the announced payload length are ignored.
+    pub fn open_with_header(
+        header: PacketHeader,
+        bytes: &[u8],
+        direction: Direction,
+        key: &[u8; 32],
+    ) -> Result<Self, PacketError> {
+        let end = PacketHeader::SIZE + header.payload_len as usize;
+        let ciphertext = bytes.get(PacketHeader::SIZE..end).ok_or(PacketError::TooShort)?;
+        
+        let payload = crypto::decrypt(ciphertext, key, &header.nonce(direction), &header.to_bytes())
+            .map_err(|e| PacketError::DecryptionError(e.to_string()))?;
+        
+        Ok(Self { header, payload })
+    }
+    
+    /// Parse the payload as `P`, which has to match the packet type
+    pub fn decode<P: Decode>(&self) -> Result<P, PacketError> {
+        if self.header.packet_type != P::PACKET_TYPE {
+            return Err(PacketError::UnexpectedPacketType {
+                expected: P::PACKET_TYPE,
+                actual: self.header.packet_type,
+            });
+        }
+        P::decode(&self.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{EchoReplyPayload, EchoRequestPayload};
+    
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let key = [7u8; 32];
+        let request = EchoRequestPayload::with_timestamp(5, 123_456);
+        let ids = PacketIds::new(0x1122334455667788, 42);
+        
+        let bytes = Packet::seal(&request, ids, Direction::ClientToServer, &key).unwrap();
+        
+        let packet = Packet::open(&bytes, Direction::ClientToServer, &key).unwrap();
+        assert_eq!(packet.header.packet_type, PacketType::EchoRequest);
+        assert_eq!(PacketIds::reply_to(&packet.header), ids);
+        let decoded: EchoRequestPayload = packet.decode().unwrap();
+        assert_eq!(decoded.sequence, 5);
+        assert_eq!(decoded.client_timestamp, 123_456);
+        
+        // Wrong payload type, wrong direction, tampered header
+        assert!(matches!(
+            packet.decode::<EchoReplyPayload>(),
+            Err(PacketError::UnexpectedPacketType { .. })
+        ));
+        assert!(Packet::open(&bytes, Direction::ServerToClient, &key).is_err());
+        let mut tampered = bytes.clone();
+        tampered[16] ^= 1;
+        assert!(Packet::open(&tampered, Direction::ClientToServer, &key).is_err());
+        assert!(matches!(
+            Packet::open(&bytes[..bytes.len() - 1], Direction::ClientToServer, &key),
+            Err(PacketError::TooShort)
+        ));
+    }
+}
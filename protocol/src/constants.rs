@@ -9,3 +9,8 @@ pub const KNOCK_SEQUENCE: [u16; 4] = [12345, 23456, 34567, 45678];
 /// Knock timeout window (nanoseconds) - 60 seconds
 pub const KNOCK_TIMEOUT_NS: u64 = 60_000_000_000;
 
+/// Data bytes per THROUGHPUT_DATA chunk
+///
+/// Keeps header + test_id/sequence + auth tag + IP/UDP headers below a
+/// 1500 byte MTU so test traffic is never fragmented.
+pub const THROUGHPUT_CHUNK_SIZE: usize = 1200;
@@ -1,11 +1,20 @@
 //! Cryptographic functions for Bufferbane protocol
 //! Uses ChaCha20-Poly1305 AEAD for encryption and authentication
+//!
+//! KNOCK/KNOCK_ACK are encrypted with the long-term shared secret and carry
+//! ephemeral X25519 public keys. All later packets of a session use a key
+//! derived from that exchange, so a leaked secret doesn't decrypt recorded
+//! sessions.
 
 use chacha20poly1305::{
     aead::{Aead, KeyInit, Payload},
     ChaCha20Poly1305,
 };
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::HashMap;
 use thiserror::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
 /// Shared secret size (32 bytes for ChaCha20)
 pub const SECRET_SIZE: usize = 32;
@@ -16,6 +25,34 @@ pub const TAG_SIZE: usize = 16;
 /// Nonce size (12 bytes)
 pub const NONCE_SIZE: usize = 12;
 
+/// X25519 public key size (32 bytes)
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+/// HKDF info prefix for session keys
+const SESSION_KEY_INFO: &[u8] = b"bufferbane session key v1";
+
+/// Sender of a packet, mixed into the nonce
+///
+/// Client and server encrypt with the same key, so their nonces must not
+/// overlap even if both build a packet in the same nanosecond.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Direction {
+    ClientToServer = 0x00,
+    ServerToClient = 0x01,
+}
+
+/// Derive the 12-byte nonce of a packet
+///
+/// Layout: direction (1 byte) || client_id low 3 bytes || nonce_timestamp (8 bytes)
+pub fn derive_nonce(direction: Direction, client_id: u64, nonce_timestamp: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[0] = direction as u8;
+    nonce[1..4].copy_from_slice(&client_id.to_be_bytes()[5..8]);
+    nonce[4..12].copy_from_slice(&nonce_timestamp.to_be_bytes());
+    nonce
+}
+
 /// Crypto errors
 #[derive(Debug, Error)]
 pub enum CryptoError {
@@ -27,6 +64,92 @@ pub enum CryptoError {
     
     #[error("Invalid shared secret length (expected {expected}, got {got})")]
     InvalidSecretLength { expected: usize, got: usize },
+    
+    #[error("Key exchange failed (invalid peer public key)")]
+    KeyExchangeFailed,
+}
+
+/// Source of the secret shared with a client
+///
+/// Packets carry the client_id in cleartext, so the receiver can pick the
+/// right key before decrypting.
+pub trait KeyLookup {
+    /// Secret of `client_id`, or None if the client has no (valid) key
+    fn lookup(&self, client_id: u64) -> Option<[u8; SECRET_SIZE]>;
+}
+
+/// A single secret shared by every client
+impl KeyLookup for [u8; SECRET_SIZE] {
+    fn lookup(&self, _client_id: u64) -> Option<[u8; SECRET_SIZE]> {
+        Some(*self)
+    }
+}
+
+/// Individual secrets keyed by client_id
+impl KeyLookup for HashMap<u64, [u8; SECRET_SIZE]> {
+    fn lookup(&self, client_id: u64) -> Option<[u8; SECRET_SIZE]> {
+        self.get(&client_id).copied()
+    }
+}
+
+/// Ephemeral X25519 key pair for one KNOCK handshake
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+    
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.public.to_bytes()
+    }
+    
+    /// Finish the exchange with the peer's public key
+    ///
+    /// Fails for low-order peer keys that would make the result predictable.
+    pub fn diffie_hellman(self, peer_public: &[u8; PUBLIC_KEY_SIZE]) -> Result<[u8; 32], CryptoError> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer_public));
+        if !shared.was_contributory() {
+            return Err(CryptoError::KeyExchangeFailed);
+        }
+        Ok(shared.to_bytes())
+    }
+}
+
+/// Derive the key of a session with HKDF-SHA256
+///
+/// The X25519 result is the input key material, salted with the long-term
+/// secret so only holders of the secret end up with the same key. Both
+/// public keys and the session_id are bound through the info string.
+pub fn derive_session_key(
+    shared_secret: &[u8; SECRET_SIZE],
+    dh_result: &[u8; 32],
+    client_public: &[u8; PUBLIC_KEY_SIZE],
+    server_public: &[u8; PUBLIC_KEY_SIZE],
+    session_id: u64,
+) -> [u8; SECRET_SIZE] {
+    let mut info = Vec::with_capacity(SESSION_KEY_INFO.len() + 2 * PUBLIC_KEY_SIZE + 8);
+    info.extend_from_slice(SESSION_KEY_INFO);
+    info.extend_from_slice(client_public);
+    info.extend_from_slice(server_public);
+    info.extend_from_slice(&session_id.to_be_bytes());
+    
+    let mut key = [0u8; SECRET_SIZE];
+    Hkdf::<Sha256>::new(Some(shared_secret), dh_result)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
 }
 
 /// Encrypt payload using ChaCha20-Poly1305 AEAD
@@ -34,7 +157,7 @@ pub enum CryptoError {
 /// # Arguments
 /// * `plaintext` - Data to encrypt
 /// * `shared_secret` - 32-byte shared secret
-/// * `nonce` - 12-byte nonce (derived from client_id + timestamp)
+/// * `nonce` - 12-byte nonce (see `derive_nonce`)
 /// * `associated_data` - Additional authenticated data (packet header)
 ///
 /// # Returns
@@ -71,7 +194,7 @@ pub fn encrypt(
 /// # Arguments
 /// * `ciphertext` - Encrypted data + 16-byte auth tag
 /// * `shared_secret` - 32-byte shared secret
-/// * `nonce` - 12-byte nonce (derived from client_id + timestamp)
+/// * `nonce` - 12-byte nonce (see `derive_nonce`)
 /// * `associated_data` - Additional authenticated data (packet header)
 ///
 /// # Returns
@@ -199,6 +322,90 @@ mod tests {
         assert!(result.is_err());
     }
     
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+    
+    #[test]
+    fn test_vectors_direction_separated_nonces() {
+        // Nonce layout since protocol version 3; ciphertexts cross-checked with an independent
+        // ChaCha20-Poly1305 implementation
+        let secret = parse_shared_secret("a7b3c9d8e1f4a2b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9").unwrap();
+        let client_id = 0x0102030405060708;
+        let nonce_timestamp = 0x17f0e5d4c3b2a190;
+        let aad = hex("4246424e031000220102030405060708112233445566778817f0e5d4c3b2a190");
+        let plaintext = b"Hello, Bufferbane!";
+        
+        let vectors = [
+            (
+                Direction::ClientToServer,
+                "0006070817f0e5d4c3b2a190",
+                "74f1c4aad713963f2c65077c145871eef9bb70a2f20d5141e25ba93f24bb9f029b44",
+            ),
+            (
+                Direction::ServerToClient,
+                "0106070817f0e5d4c3b2a190",
+                "62add28179db8af5bb1b6c3493e28b21d0b3ec4228318bbf617bdaf7c3c9379dac0b",
+            ),
+        ];
+        
+        for (direction, expected_nonce, expected_ciphertext) in vectors {
+            let nonce = derive_nonce(direction, client_id, nonce_timestamp);
+            assert_eq!(nonce.to_vec(), hex(expected_nonce), "{:?}", direction);
+            
+            let ciphertext = encrypt(plaintext, &secret, &nonce, &aad).unwrap();
+            assert_eq!(ciphertext, hex(expected_ciphertext), "{:?}", direction);
+        }
+        
+        // A packet can't be accepted as coming from the other direction
+        let nonce = derive_nonce(Direction::ClientToServer, client_id, nonce_timestamp);
+        let ciphertext = encrypt(plaintext, &secret, &nonce, &aad).unwrap();
+        let reflected = derive_nonce(Direction::ServerToClient, client_id, nonce_timestamp);
+        assert!(decrypt(&ciphertext, &secret, &reflected, &aad).is_err());
+    }
+    
+    #[test]
+    fn test_key_lookup_by_client_id() {
+        let secret1 = generate_shared_secret();
+        let secret2 = generate_shared_secret();
+        let keys = HashMap::from([(1, secret1), (2, secret2)]);
+        
+        assert_eq!(keys.lookup(1), Some(secret1));
+        assert_eq!(keys.lookup(2), Some(secret2));
+        assert_eq!(keys.lookup(3), None);
+        
+        // A shared secret answers for every client
+        assert_eq!(secret1.lookup(3), Some(secret1));
+    }
+    
+    #[test]
+    fn test_session_key_agreement() {
+        let secret = generate_shared_secret();
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_public = client.public_key();
+        let server_public = server.public_key();
+        
+        let client_dh = client.diffie_hellman(&server_public).unwrap();
+        let server_dh = server.diffie_hellman(&client_public).unwrap();
+        assert_eq!(client_dh, server_dh);
+        
+        let client_key = derive_session_key(&secret, &client_dh, &client_public, &server_public, 42);
+        let server_key = derive_session_key(&secret, &server_dh, &client_public, &server_public, 42);
+        assert_eq!(client_key, server_key);
+        
+        // Different long-term secret or session gives a different key
+        let other_secret = generate_shared_secret();
+        assert_ne!(derive_session_key(&other_secret, &client_dh, &client_public, &server_public, 42), client_key);
+        assert_ne!(derive_session_key(&secret, &client_dh, &client_public, &server_public, 43), client_key);
+        
+        // Low-order points are rejected
+        assert!(KeyExchange::new().diffie_hellman(&[0u8; PUBLIC_KEY_SIZE]).is_err());
+    }
+    
     #[test]
     fn test_parse_shared_secret() {
         let hex = "a7b3c9d8e1f4a2b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9";
@@ -3,6 +3,7 @@
 //! Shared protocol definitions for Bufferbane client-server communication.
 //! This includes packet types, constants, and serialization/deserialization logic.
 
+pub mod codec;
 pub mod constants;
 pub mod error;
 pub mod packets;
@@ -10,9 +11,7 @@ pub mod crypto;
 
 pub use constants::*;
 pub use error::ProtocolError;
-
-/// Protocol version
-pub const PROTOCOL_VERSION: u8 = 1;
+pub use packets::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
 /// Magic bytes: "BFBN" (0x4246424E)
 pub const MAGIC_BYTES: [u8; 4] = [0x42, 0x46, 0x42, 0x4E];
@@ -21,4 +20,4 @@ pub const MAGIC_BYTES: [u8; 4] = [0x42, 0x46, 0x42, 0x4E];
 pub const MAX_PACKET_SIZE: usize = 65536;
 
 /// Minimum packet size (header only)
-pub const MIN_PACKET_SIZE: usize = 24;
+pub const MIN_PACKET_SIZE: usize = 32;
@@ -1,13 +1,30 @@
 //! Bufferbane protocol packet structures
 
-use std::time::SystemTime;
+use crate::codec::{Decode, Encode, Payload};
+use crate::crypto::{self, Direction, NONCE_SIZE, PUBLIC_KEY_SIZE};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
 /// Protocol version
-pub const PROTOCOL_VERSION: u8 = 1;
+pub const PROTOCOL_VERSION: u8 = 6;
+
+/// Oldest protocol version this build speaks
+///
+/// Only the current packet layouts are implemented, so this equals
+/// PROTOCOL_VERSION; it may only stay behind on a version bump if the old
+/// layouts are kept. KNOCK is always sent with this version in its header,
+/// so any peer that supports it can read the offered version range.
+pub const MIN_PROTOCOL_VERSION: u8 = 6;
+
+/// Pick the highest protocol version both sides speak
+pub fn negotiate_version(peer_min: u8, peer_max: u8) -> Option<u8> {
+    let version = peer_max.min(PROTOCOL_VERSION);
+    (version >= peer_min && version >= MIN_PROTOCOL_VERSION).then_some(version)
+}
 
 /// Packet types
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum PacketType {
     /// Port knocking authentication
@@ -38,6 +55,24 @@ pub enum PacketType {
 }
 
 impl PacketType {
+    /// Every packet type, in wire order
+    pub const ALL: [Self; 14] = [
+        Self::Knock,
+        Self::KnockAck,
+        Self::EchoRequest,
+        Self::EchoReply,
+        Self::ThroughputStart,
+        Self::ThroughputData,
+        Self::ThroughputEnd,
+        Self::ThroughputStats,
+        Self::DownloadRequest,
+        Self::DownloadData,
+        Self::DownloadEnd,
+        Self::BufferbloatStart,
+        Self::BufferbloatEnd,
+        Self::Error,
+    ];
+    
     pub fn from_u8(value: u8) -> Option<Self> {
         match value {
             0x01 => Some(Self::Knock),
@@ -59,8 +94,11 @@ impl PacketType {
     }
 }
 
-/// Cleartext packet header (24 bytes)
-#[derive(Debug, Clone)]
+/// Cleartext packet header (32 bytes)
+///
+/// The header is authenticated as associated data, so the session_id can't
+/// be swapped without breaking decryption.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct PacketHeader {
     /// Magic bytes "BFBN" (4 bytes)
     pub magic: u32,
@@ -72,29 +110,35 @@ pub struct PacketHeader {
     pub payload_len: u16,
     /// Client ID (8 bytes)
     pub client_id: u64,
+    /// Session ID from KNOCK_ACK (8 bytes, 0 for KNOCK)
+    pub session_id: u64,
     /// Nonce timestamp in nanoseconds (8 bytes)
     pub nonce_timestamp: u64,
 }
 
 impl PacketHeader {
-    pub const SIZE: usize = 24;
+    pub const SIZE: usize = 32;
     
-    pub fn new(packet_type: PacketType, payload_len: u16, client_id: u64) -> Self {
-        let nonce_timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64;
-            
+    pub fn new(packet_type: PacketType, payload_len: u16, client_id: u64, session_id: u64) -> Self {
+        let nonce_timestamp = next_nonce_timestamp();
+        
         Self {
             magic: crate::constants::MAGIC_BYTES,
             version: PROTOCOL_VERSION,
             packet_type,
             payload_len,
             client_id,
+            session_id,
             nonce_timestamp,
         }
     }
     
+    /// Use another (negotiated) protocol version than our own
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+    
     pub fn to_bytes(&self) -> [u8; Self::SIZE] {
         let mut bytes = [0u8; Self::SIZE];
         bytes[0..4].copy_from_slice(&self.magic.to_be_bytes());
@@ -102,7 +146,8 @@ impl PacketHeader {
         bytes[5] = self.packet_type as u8;
         bytes[6..8].copy_from_slice(&self.payload_len.to_be_bytes());
         bytes[8..16].copy_from_slice(&self.client_id.to_be_bytes());
-        bytes[16..24].copy_from_slice(&self.nonce_timestamp.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.session_id.to_be_bytes());
+        bytes[24..32].copy_from_slice(&self.nonce_timestamp.to_be_bytes());
         bytes
     }
     
@@ -117,7 +162,7 @@ impl PacketHeader {
         }
         
         let version = bytes[4];
-        if version != PROTOCOL_VERSION {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
             return Err(PacketError::UnsupportedVersion(version));
         }
         
@@ -129,10 +174,14 @@ impl PacketHeader {
             bytes[8], bytes[9], bytes[10], bytes[11],
             bytes[12], bytes[13], bytes[14], bytes[15],
         ]);
-        let nonce_timestamp = u64::from_be_bytes([
+        let session_id = u64::from_be_bytes([
             bytes[16], bytes[17], bytes[18], bytes[19],
             bytes[20], bytes[21], bytes[22], bytes[23],
         ]);
+        let nonce_timestamp = u64::from_be_bytes([
+            bytes[24], bytes[25], bytes[26], bytes[27],
+            bytes[28], bytes[29], bytes[30], bytes[31],
+        ]);
         
         Ok(Self {
             magic,
@@ -140,16 +189,37 @@ impl PacketHeader {
             packet_type,
             payload_len,
             client_id,
+            session_id,
             nonce_timestamp,
         })
     }
     
-    /// Generate 12-byte nonce from client_id and nonce_timestamp
-    pub fn nonce(&self) -> [u8; 12] {
-        let mut nonce = [0u8; 12];
-        nonce[0..4].copy_from_slice(&self.client_id.to_be_bytes()[0..4]);
-        nonce[4..12].copy_from_slice(&self.nonce_timestamp.to_be_bytes());
-        nonce
+    /// Generate 12-byte nonce for a packet sent in `direction`
+    pub fn nonce(&self, direction: Direction) -> [u8; NONCE_SIZE] {
+        crypto::derive_nonce(direction, self.client_id, self.nonce_timestamp)
+    }
+}
+
+/// Last nonce timestamp handed out by this process
+static LAST_NONCE_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// Current time in nanoseconds, but strictly increasing
+///
+/// Two packets built within the clock's resolution would otherwise share a
+/// nonce and the second one would be rejected as a replay.
+fn next_nonce_timestamp() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    
+    let mut last = LAST_NONCE_TIMESTAMP.load(Ordering::Relaxed);
+    loop {
+        let next = now.max(last + 1);
+        match LAST_NONCE_TIMESTAMP.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            Err(actual) => last = actual,
+        }
     }
 }
 
@@ -168,62 +238,178 @@ pub enum PacketError {
     #[error("Unknown packet type: {0:#x}")]
     UnknownPacketType(u8),
     
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(String),
+    
     #[error("Encryption error: {0}")]
     EncryptionError(String),
     
     #[error("Decryption error: {0}")]
     DecryptionError(String),
+    
+    #[error("Expected {expected:?} packet, got {actual:?}")]
+    UnexpectedPacketType { expected: PacketType, actual: PacketType },
+}
+
+/// Features a peer offers, exchanged in KNOCK and KNOCK_ACK
+///
+/// Unknown bits are ignored, so new features can be added without a
+/// version bump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// ECHO_REQUEST / ECHO_REPLY
+    pub const ECHO: Self = Self(1 << 0);
+    /// Upload throughput test (THROUGHPUT_*)
+    pub const UPLOAD: Self = Self(1 << 1);
+    /// Download throughput test (DOWNLOAD_*)
+    pub const DOWNLOAD: Self = Self(1 << 2);
+    /// Bufferbloat load phases (BUFFERBLOAT_*)
+    pub const BUFFERBLOAT: Self = Self(1 << 3);
+    
+    /// Everything this build implements
+    pub const ALL: Self = Self(Self::ECHO.0 | Self::UPLOAD.0 | Self::DOWNLOAD.0 | Self::BUFFERBLOAT.0);
+    
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+    
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Test limits a server announces in KNOCK_ACK
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Largest total_size of a DOWNLOAD_REQUEST (bytes)
+    pub max_download_bytes: u64,
+    /// Highest test rate in either direction (kbps, 0 = unlimited)
+    pub max_rate_kbps: u32,
+    /// Longest bufferbloat load phase (ms)
+    pub max_phase_ms: u32,
+}
+
+impl ServerLimits {
+    /// Limit a requested rate (0 = unpaced) to what the server allows
+    pub fn clamp_rate_kbps(&self, rate_kbps: u32) -> u32 {
+        if self.max_rate_kbps > 0 && (rate_kbps == 0 || rate_kbps > self.max_rate_kbps) {
+            self.max_rate_kbps
+        } else {
+            rate_kbps
+        }
+    }
 }
 
 /// KNOCK packet payload
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct KnockPayload {
     /// Random challenge (32 bytes)
     pub challenge: [u8; 32],
+    /// Client's ephemeral X25519 public key (32 bytes)
+    pub public_key: [u8; PUBLIC_KEY_SIZE],
+    /// Oldest protocol version the client speaks (1 byte)
+    pub min_version: u8,
+    /// Newest protocol version the client speaks (1 byte)
+    pub max_version: u8,
+    /// Features the client wants to use (4 bytes)
+    pub capabilities: Capabilities,
 }
 
 impl KnockPayload {
-    pub fn new() -> Self {
+    /// Create a knock with a random challenge, offering all our versions
+    /// and features
+    pub fn new(public_key: [u8; PUBLIC_KEY_SIZE]) -> Self {
         use rand::Rng;
         let mut rng = rand::thread_rng();
         let mut challenge = [0u8; 32];
         rng.fill(&mut challenge);
-        Self { challenge }
+        Self {
+            challenge,
+            public_key,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::ALL,
+        }
     }
-    
-    pub fn to_bytes(&self) -> Vec<u8> {
-        self.challenge.to_vec()
+}
+
+impl Encode for KnockPayload {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(70);
+        bytes.extend_from_slice(&self.challenge);
+        bytes.extend_from_slice(&self.public_key);
+        bytes.push(self.min_version);
+        bytes.push(self.max_version);
+        bytes.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        bytes
     }
-    
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
-        if bytes.len() < 32 {
+}
+
+impl Decode for KnockPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < 70 {
             return Err(PacketError::TooShort);
         }
         let mut challenge = [0u8; 32];
         challenge.copy_from_slice(&bytes[0..32]);
-        Ok(Self { challenge })
+        let mut public_key = [0u8; PUBLIC_KEY_SIZE];
+        public_key.copy_from_slice(&bytes[32..64]);
+        let capabilities = Capabilities(u32::from_be_bytes([bytes[66], bytes[67], bytes[68], bytes[69]]));
+        Ok(Self {
+            challenge,
+            public_key,
+            min_version: bytes[64],
+            max_version: bytes[65],
+            capabilities,
+        })
     }
 }
 
 /// KNOCK_ACK packet payload
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct KnockAckPayload {
     /// Session ID assigned by server (8 bytes)
     pub session_id: u64,
     /// Challenge response (32 bytes - hash of client challenge)
     pub challenge_response: [u8; 32],
+    /// Server's ephemeral X25519 public key (32 bytes)
+    pub public_key: [u8; PUBLIC_KEY_SIZE],
+    /// Protocol version chosen for the session (1 byte)
+    pub version: u8,
+    /// Features the server offers (4 bytes)
+    pub capabilities: Capabilities,
+    /// Server's test limits (16 bytes)
+    pub limits: ServerLimits,
 }
 
-impl KnockAckPayload {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(40);
+impl Encode for KnockAckPayload {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(93);
         bytes.extend_from_slice(&self.session_id.to_be_bytes());
         bytes.extend_from_slice(&self.challenge_response);
+        bytes.extend_from_slice(&self.public_key);
+        bytes.push(self.version);
+        bytes.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        bytes.extend_from_slice(&self.limits.max_download_bytes.to_be_bytes());
+        bytes.extend_from_slice(&self.limits.max_rate_kbps.to_be_bytes());
+        bytes.extend_from_slice(&self.limits.max_phase_ms.to_be_bytes());
         bytes
     }
-    
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
-        if bytes.len() < 40 {
+}
+
+impl Decode for KnockAckPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < 93 {
             return Err(PacketError::TooShort);
         }
         let session_id = u64::from_be_bytes([
@@ -232,12 +418,119 @@ impl KnockAckPayload {
         ]);
         let mut challenge_response = [0u8; 32];
         challenge_response.copy_from_slice(&bytes[8..40]);
-        Ok(Self { session_id, challenge_response })
+        let mut public_key = [0u8; PUBLIC_KEY_SIZE];
+        public_key.copy_from_slice(&bytes[40..72]);
+        let capabilities = Capabilities(u32::from_be_bytes([bytes[73], bytes[74], bytes[75], bytes[76]]));
+        let limits = ServerLimits {
+            max_download_bytes: u64::from_be_bytes([
+                bytes[77], bytes[78], bytes[79], bytes[80],
+                bytes[81], bytes[82], bytes[83], bytes[84],
+            ]),
+            max_rate_kbps: u32::from_be_bytes([bytes[85], bytes[86], bytes[87], bytes[88]]),
+            max_phase_ms: u32::from_be_bytes([bytes[89], bytes[90], bytes[91], bytes[92]]),
+        };
+        Ok(Self {
+            session_id,
+            challenge_response,
+            public_key,
+            version: bytes[72],
+            capabilities,
+            limits,
+        })
+    }
+}
+
+/// Error codes carried by ERROR packets
+///
+/// The values are part of the wire format and must not be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// Session unknown or expired - the client has to KNOCK again
+    InvalidSession = 0x0001,
+    /// Source IP or client is over its rate limit - slow down
+    RateLimited = 0x0002,
+    /// Protocol version not supported by the server
+    UnsupportedVersion = 0x0003,
+    /// Server can't take more clients or tests right now
+    ServerOverloaded = 0x0004,
+    /// Test request refused (bad parameters or a test already running)
+    TestRejected = 0x0005,
+    /// Request type not offered by this server - stop sending it
+    UnsupportedRequest = 0x0006,
+}
+
+impl ErrorCode {
+    pub fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            0x0001 => Some(Self::InvalidSession),
+            0x0002 => Some(Self::RateLimited),
+            0x0003 => Some(Self::UnsupportedVersion),
+            0x0004 => Some(Self::ServerOverloaded),
+            0x0005 => Some(Self::TestRejected),
+            0x0006 => Some(Self::UnsupportedRequest),
+            _ => None,
+        }
+    }
+}
+
+/// ERROR packet payload
+///
+/// Only sent to peers that authenticated the packet it answers, so it
+/// tells nothing to anyone without a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorPayload {
+    /// Error code (2 bytes)
+    pub code: ErrorCode,
+    /// Type of the packet that caused the error (1 byte)
+    pub request: PacketType,
+    /// When the client may try again, if the server knows (4 bytes, ms, 0 = no hint)
+    pub retry_after: Option<Duration>,
+}
+
+impl ErrorPayload {
+    pub fn new(code: ErrorCode, request: PacketType) -> Self {
+        Self { code, request, retry_after: None }
+    }
+    
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl Encode for ErrorPayload {
+    fn encode(&self) -> Vec<u8> {
+        let retry_after_ms = self
+            .retry_after
+            .map_or(0, |d| d.as_millis().clamp(1, u32::MAX as u128) as u32);
+        
+        let mut bytes = Vec::with_capacity(7);
+        bytes.extend_from_slice(&(self.code as u16).to_be_bytes());
+        bytes.push(self.request as u8);
+        bytes.extend_from_slice(&retry_after_ms.to_be_bytes());
+        bytes
+    }
+}
+
+impl Decode for ErrorPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < 7 {
+            return Err(PacketError::TooShort);
+        }
+        let value = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let code = ErrorCode::from_u16(value)
+            .ok_or_else(|| PacketError::InvalidPayload(format!("unknown error code {:#06x}", value)))?;
+        let request = PacketType::from_u8(bytes[2])
+            .ok_or(PacketError::UnknownPacketType(bytes[2]))?;
+        let retry_after_ms = u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]);
+        let retry_after = (retry_after_ms > 0).then(|| Duration::from_millis(retry_after_ms as u64));
+        Ok(Self { code, request, retry_after })
     }
 }
 
 /// ECHO_REQUEST packet payload
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct EchoRequestPayload {
     /// Sequence number
     pub sequence: u32,
@@ -258,15 +551,19 @@ impl EchoRequestPayload {
     pub fn with_timestamp(sequence: u32, timestamp_ns: u64) -> Self {
         Self { sequence, client_timestamp: timestamp_ns }
     }
-    
-    pub fn to_bytes(&self) -> Vec<u8> {
+}
+
+impl Encode for EchoRequestPayload {
+    fn encode(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(12);
         bytes.extend_from_slice(&self.sequence.to_be_bytes());
         bytes.extend_from_slice(&self.client_timestamp.to_be_bytes());
         bytes
     }
-    
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
+}
+
+impl Decode for EchoRequestPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
         if bytes.len() < 12 {
             return Err(PacketError::TooShort);
         }
@@ -280,7 +577,7 @@ impl EchoRequestPayload {
 }
 
 /// ECHO_REPLY packet payload
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct EchoReplyPayload {
     /// Sequence number (echoed from request)
     pub sequence: u32,
@@ -328,8 +625,10 @@ impl EchoReplyPayload {
             .unwrap()
             .as_nanos() as u64;
     }
-    
-    pub fn to_bytes(&self) -> Vec<u8> {
+}
+
+impl Encode for EchoReplyPayload {
+    fn encode(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(28);
         bytes.extend_from_slice(&self.sequence.to_be_bytes());
         bytes.extend_from_slice(&self.client_send_timestamp.to_be_bytes());
@@ -337,8 +636,10 @@ impl EchoReplyPayload {
         bytes.extend_from_slice(&self.server_send_timestamp.to_be_bytes());
         bytes
     }
-    
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
+}
+
+impl Decode for EchoReplyPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
         if bytes.len() < 28 {
             return Err(PacketError::TooShort);
         }
@@ -365,7 +666,7 @@ impl EchoReplyPayload {
 }
 
 /// THROUGHPUT_START packet payload (for upload testing)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ThroughputStartPayload {
     /// Test ID
     pub test_id: u32,
@@ -373,15 +674,17 @@ pub struct ThroughputStartPayload {
     pub total_size: u64,
 }
 
-impl ThroughputStartPayload {
-    pub fn to_bytes(&self) -> Vec<u8> {
+impl Encode for ThroughputStartPayload {
+    fn encode(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(12);
         bytes.extend_from_slice(&self.test_id.to_be_bytes());
         bytes.extend_from_slice(&self.total_size.to_be_bytes());
         bytes
     }
-    
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
+}
+
+impl Decode for ThroughputStartPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
         if bytes.len() < 12 {
             return Err(PacketError::TooShort);
         }
@@ -395,7 +698,7 @@ impl ThroughputStartPayload {
 }
 
 /// THROUGHPUT_DATA packet payload
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ThroughputDataPayload {
     /// Test ID
     pub test_id: u32,
@@ -405,16 +708,18 @@ pub struct ThroughputDataPayload {
     pub data: Vec<u8>,
 }
 
-impl ThroughputDataPayload {
-    pub fn to_bytes(&self) -> Vec<u8> {
+impl Encode for ThroughputDataPayload {
+    fn encode(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(8 + self.data.len());
         bytes.extend_from_slice(&self.test_id.to_be_bytes());
         bytes.extend_from_slice(&self.sequence.to_be_bytes());
         bytes.extend_from_slice(&self.data);
         bytes
     }
-    
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
+}
+
+impl Decode for ThroughputDataPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
         if bytes.len() < 8 {
             return Err(PacketError::TooShort);
         }
@@ -426,7 +731,7 @@ impl ThroughputDataPayload {
 }
 
 /// THROUGHPUT_END packet payload
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ThroughputEndPayload {
     /// Test ID
     pub test_id: u32,
@@ -434,15 +739,17 @@ pub struct ThroughputEndPayload {
     pub total_bytes: u64,
 }
 
-impl ThroughputEndPayload {
-    pub fn to_bytes(&self) -> Vec<u8> {
+impl Encode for ThroughputEndPayload {
+    fn encode(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(12);
         bytes.extend_from_slice(&self.test_id.to_be_bytes());
         bytes.extend_from_slice(&self.total_bytes.to_be_bytes());
         bytes
     }
-    
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
+}
+
+impl Decode for ThroughputEndPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
         if bytes.len() < 12 {
             return Err(PacketError::TooShort);
         }
@@ -456,7 +763,7 @@ impl ThroughputEndPayload {
 }
 
 /// THROUGHPUT_STATS packet payload (server response)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ThroughputStatsPayload {
     /// Test ID
     pub test_id: u32,
@@ -470,8 +777,8 @@ pub struct ThroughputStatsPayload {
     pub packet_loss_pct: f32,
 }
 
-impl ThroughputStatsPayload {
-    pub fn to_bytes(&self) -> Vec<u8> {
+impl Encode for ThroughputStatsPayload {
+    fn encode(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(24);
         bytes.extend_from_slice(&self.test_id.to_be_bytes());
         bytes.extend_from_slice(&self.total_bytes.to_be_bytes());
@@ -480,8 +787,10 @@ impl ThroughputStatsPayload {
         bytes.extend_from_slice(&self.packet_loss_pct.to_be_bytes());
         bytes
     }
-    
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
+}
+
+impl Decode for ThroughputStatsPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
         if bytes.len() < 24 {
             return Err(PacketError::TooShort);
         }
@@ -503,4 +812,607 @@ impl ThroughputStatsPayload {
     }
 }
 
+/// DOWNLOAD_REQUEST packet payload (client asks server to stream data)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadRequestPayload {
+    /// Test ID
+    pub test_id: u32,
+    /// Total size to send in bytes
+    pub total_size: u64,
+    /// Requested send rate in kbps (0 = as fast as the server allows)
+    pub rate_kbps: u32,
+}
+
+impl Encode for DownloadRequestPayload {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.test_id.to_be_bytes());
+        bytes.extend_from_slice(&self.total_size.to_be_bytes());
+        bytes.extend_from_slice(&self.rate_kbps.to_be_bytes());
+        bytes
+    }
+}
+
+impl Decode for DownloadRequestPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < 16 {
+            return Err(PacketError::TooShort);
+        }
+        let test_id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let total_size = u64::from_be_bytes([
+            bytes[4], bytes[5], bytes[6], bytes[7],
+            bytes[8], bytes[9], bytes[10], bytes[11],
+        ]);
+        let rate_kbps = u32::from_be_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+        Ok(Self { test_id, total_size, rate_kbps })
+    }
+}
+
+/// DOWNLOAD_DATA packet payload (same layout as THROUGHPUT_DATA)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadDataPayload {
+    /// Test ID
+    pub test_id: u32,
+    /// Sequence number
+    pub sequence: u32,
+    /// Data chunk (variable size)
+    pub data: Vec<u8>,
+}
+
+impl Encode for DownloadDataPayload {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.data.len());
+        bytes.extend_from_slice(&self.test_id.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+impl Decode for DownloadDataPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < 8 {
+            return Err(PacketError::TooShort);
+        }
+        let test_id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let sequence = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let data = bytes[8..].to_vec();
+        Ok(Self { test_id, sequence, data })
+    }
+}
+
+/// DOWNLOAD_END packet payload (server reports what it sent)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadEndPayload {
+    /// Test ID
+    pub test_id: u32,
+    /// Total bytes sent
+    pub total_bytes: u64,
+    /// Number of DOWNLOAD_DATA packets sent
+    pub packets_sent: u32,
+    /// Time the server spent sending in milliseconds
+    pub duration_ms: u32,
+}
+
+impl Encode for DownloadEndPayload {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(20);
+        bytes.extend_from_slice(&self.test_id.to_be_bytes());
+        bytes.extend_from_slice(&self.total_bytes.to_be_bytes());
+        bytes.extend_from_slice(&self.packets_sent.to_be_bytes());
+        bytes.extend_from_slice(&self.duration_ms.to_be_bytes());
+        bytes
+    }
+}
+
+impl Decode for DownloadEndPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < 20 {
+            return Err(PacketError::TooShort);
+        }
+        let test_id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let total_bytes = u64::from_be_bytes([
+            bytes[4], bytes[5], bytes[6], bytes[7],
+            bytes[8], bytes[9], bytes[10], bytes[11],
+        ]);
+        let packets_sent = u32::from_be_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+        let duration_ms = u32::from_be_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]);
+        Ok(Self { test_id, total_bytes, packets_sent, duration_ms })
+    }
+}
+
+/// Load direction of a bufferbloat test phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BufferbloatDirection {
+    /// Client saturates the uplink with THROUGHPUT_DATA
+    Upload = 0,
+    /// Server saturates the downlink with DOWNLOAD_DATA
+    Download = 1,
+}
+
+impl BufferbloatDirection {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Upload),
+            1 => Some(Self::Download),
+            _ => None,
+        }
+    }
+}
+
+/// BUFFERBLOAT_START packet payload (client starts a load phase)
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferbloatStartPayload {
+    /// Test ID (upload data is sent as THROUGHPUT_DATA with this ID,
+    /// download data as DOWNLOAD_DATA)
+    pub test_id: u32,
+    /// Which direction to saturate
+    pub direction: BufferbloatDirection,
+    /// Length of the load phase in milliseconds
+    pub duration_ms: u32,
+    /// Download send rate in kbps (0 = as fast as the server allows)
+    pub rate_kbps: u32,
+}
+
+impl Encode for BufferbloatStartPayload {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(13);
+        bytes.extend_from_slice(&self.test_id.to_be_bytes());
+        bytes.push(self.direction as u8);
+        bytes.extend_from_slice(&self.duration_ms.to_be_bytes());
+        bytes.extend_from_slice(&self.rate_kbps.to_be_bytes());
+        bytes
+    }
+}
+
+impl Decode for BufferbloatStartPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < 13 {
+            return Err(PacketError::TooShort);
+        }
+        let test_id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let direction = BufferbloatDirection::from_u8(bytes[4])
+            .ok_or_else(|| PacketError::InvalidPayload(format!("unknown bufferbloat direction {}", bytes[4])))?;
+        let duration_ms = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
+        let rate_kbps = u32::from_be_bytes([bytes[9], bytes[10], bytes[11], bytes[12]]);
+        Ok(Self { test_id, direction, duration_ms, rate_kbps })
+    }
+}
+
+/// BUFFERBLOAT_END packet payload
+///
+/// Sent by the client to stop a load phase. For upload phases the server
+/// answers with a BUFFERBLOAT_END reporting what it received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferbloatEndPayload {
+    /// Test ID
+    pub test_id: u32,
+    /// Direction of the phase being stopped
+    pub direction: BufferbloatDirection,
+    /// Bytes sent (client -> server) or received (server -> client)
+    pub total_bytes: u64,
+    /// Phase duration in milliseconds as seen by the sender
+    pub duration_ms: u32,
+}
+
+impl Encode for BufferbloatEndPayload {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(17);
+        bytes.extend_from_slice(&self.test_id.to_be_bytes());
+        bytes.push(self.direction as u8);
+        bytes.extend_from_slice(&self.total_bytes.to_be_bytes());
+        bytes.extend_from_slice(&self.duration_ms.to_be_bytes());
+        bytes
+    }
+}
+
+impl Decode for BufferbloatEndPayload {
+    fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < 17 {
+            return Err(PacketError::TooShort);
+        }
+        let test_id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let direction = BufferbloatDirection::from_u8(bytes[4])
+            .ok_or_else(|| PacketError::InvalidPayload(format!("unknown bufferbloat direction {}", bytes[4])))?;
+        let total_bytes = u64::from_be_bytes([
+            bytes[5], bytes[6], bytes[7], bytes[8],
+            bytes[9], bytes[10], bytes[11], bytes[12],
+        ]);
+        let duration_ms = u32::from_be_bytes([bytes[13], bytes[14], bytes[15], bytes[16]]);
+        Ok(Self { test_id, direction, total_bytes, duration_ms })
+    }
+}
+
+/// Packet type carrying each payload
+macro_rules! payload_types {
+    ($($payload:ty => $packet_type:ident),* $(,)?) => {
+        $(
+            impl Payload for $payload {
+                const PACKET_TYPE: PacketType = PacketType::$packet_type;
+            }
+        )*
+    };
+}
+
+payload_types! {
+    KnockPayload => Knock,
+    KnockAckPayload => KnockAck,
+    ErrorPayload => Error,
+    EchoRequestPayload => EchoRequest,
+    EchoReplyPayload => EchoReply,
+    ThroughputStartPayload => ThroughputStart,
+    ThroughputDataPayload => ThroughputData,
+    ThroughputEndPayload => ThroughputEnd,
+    ThroughputStatsPayload => ThroughputStats,
+    DownloadRequestPayload => DownloadRequest,
+    DownloadDataPayload => DownloadData,
+    DownloadEndPayload => DownloadEnd,
+    BufferbloatStartPayload => BufferbloatStart,
+    BufferbloatEndPayload => BufferbloatEnd,
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::fmt::Debug;
+    
+    const ERROR_CODES: [ErrorCode; 6] = [
+        ErrorCode::InvalidSession,
+        ErrorCode::RateLimited,
+        ErrorCode::UnsupportedVersion,
+        ErrorCode::ServerOverloaded,
+        ErrorCode::TestRejected,
+        ErrorCode::UnsupportedRequest,
+    ];
+    
+    fn packet_type() -> impl Strategy<Value = PacketType> {
+        prop::sample::select(PacketType::ALL.to_vec())
+    }
+    
+    fn direction() -> impl Strategy<Value = BufferbloatDirection> {
+        prop_oneof![Just(BufferbloatDirection::Upload), Just(BufferbloatDirection::Download)]
+    }
+    
+    fn data() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u8>(), 0..64)
+    }
+    
+    /// Parse `bytes` as the payload of `packet_type`; only checks that
+    /// parsing doesn't panic
+    fn decode_payload(packet_type: PacketType, bytes: &[u8]) {
+        let _ = match packet_type {
+            PacketType::Knock => KnockPayload::decode(bytes).map(drop),
+            PacketType::KnockAck => KnockAckPayload::decode(bytes).map(drop),
+            PacketType::EchoRequest => EchoRequestPayload::decode(bytes).map(drop),
+            PacketType::EchoReply => EchoReplyPayload::decode(bytes).map(drop),
+            PacketType::ThroughputStart => ThroughputStartPayload::decode(bytes).map(drop),
+            PacketType::ThroughputData => ThroughputDataPayload::decode(bytes).map(drop),
+            PacketType::ThroughputEnd => ThroughputEndPayload::decode(bytes).map(drop),
+            PacketType::ThroughputStats => ThroughputStatsPayload::decode(bytes).map(drop),
+            PacketType::DownloadRequest => DownloadRequestPayload::decode(bytes).map(drop),
+            PacketType::DownloadData => DownloadDataPayload::decode(bytes).map(drop),
+            PacketType::DownloadEnd => DownloadEndPayload::decode(bytes).map(drop),
+            PacketType::BufferbloatStart => BufferbloatStartPayload::decode(bytes).map(drop),
+            PacketType::BufferbloatEnd => BufferbloatEndPayload::decode(bytes).map(drop),
+            PacketType::Error => ErrorPayload::decode(bytes).map(drop),
+        };
+    }
+    
+    fn roundtrip<P: Encode + Decode + PartialEq + Debug>(payload: P) -> Result<(), TestCaseError> {
+        let bytes = payload.encode();
+        prop_assert_eq!(P::decode(&bytes).ok(), Some(payload));
+        Ok(())
+    }
+    
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+    
+    /// Check `payload` against its wire format, written out by hand
+    fn golden<P: Encode + Decode + PartialEq + Debug>(payload: P, expected: &str) {
+        let expected = hex(expected);
+        assert_eq!(payload.encode(), expected, "{:?} encoding changed", P::PACKET_TYPE);
+        assert_eq!(P::decode(&expected).unwrap(), payload);
+    }
+    
+    #[test]
+    fn test_golden_vectors() {
+        let header = PacketHeader {
+            magic: crate::constants::MAGIC_BYTES,
+            version: 6,
+            packet_type: PacketType::EchoRequest,
+            payload_len: 28,
+            client_id: 0x0102030405060708,
+            session_id: 0x1112131415161718,
+            nonce_timestamp: 0x2122232425262728,
+        };
+        let expected = hex("4246424e 06 10 001c 0102030405060708 1112131415161718 2122232425262728");
+        assert_eq!(header.to_bytes().as_slice(), expected.as_slice());
+        assert_eq!(PacketHeader::from_bytes(&expected).unwrap(), header);
+        
+        golden(
+            KnockPayload {
+                challenge: [0xaa; 32],
+                public_key: [0xbb; PUBLIC_KEY_SIZE],
+                min_version: 6,
+                max_version: 7,
+                capabilities: Capabilities::ALL,
+            },
+            &format!("{} {} 06 07 0000000f", "aa".repeat(32), "bb".repeat(32)),
+        );
+        golden(
+            KnockAckPayload {
+                session_id: 0x0102030405060708,
+                challenge_response: [0xcc; 32],
+                public_key: [0xdd; PUBLIC_KEY_SIZE],
+                version: 6,
+                capabilities: Capabilities::ECHO | Capabilities::DOWNLOAD,
+                limits: ServerLimits {
+                    max_download_bytes: 100 * 1024 * 1024,
+                    max_rate_kbps: 10_000,
+                    max_phase_ms: 300_000,
+                },
+            },
+            &format!(
+                "0102030405060708 {} {} 06 00000005 0000000006400000 00002710 000493e0",
+                "cc".repeat(32),
+                "dd".repeat(32)
+            ),
+        );
+        golden(
+            ErrorPayload::new(ErrorCode::RateLimited, PacketType::EchoRequest)
+                .with_retry_after(Duration::from_millis(1500)),
+            "0002 10 000005dc",
+        );
+        golden(ErrorPayload::new(ErrorCode::UnsupportedVersion, PacketType::Knock), "0003 01 00000000");
+        golden(
+            EchoRequestPayload::with_timestamp(7, 0x0102030405060708),
+            "00000007 0102030405060708",
+        );
+        golden(
+            EchoReplyPayload {
+                sequence: 7,
+                client_send_timestamp: 1,
+                server_recv_timestamp: 2,
+                server_send_timestamp: 3,
+            },
+            "00000007 0000000000000001 0000000000000002 0000000000000003",
+        );
+        golden(
+            ThroughputStartPayload { test_id: 0x11, total_size: 1_000_000 },
+            "00000011 00000000000f4240",
+        );
+        golden(
+            ThroughputDataPayload { test_id: 0x11, sequence: 2, data: vec![1, 2, 3] },
+            "00000011 00000002 010203",
+        );
+        golden(
+            ThroughputEndPayload { test_id: 0x11, total_bytes: 1_000_000 },
+            "00000011 00000000000f4240",
+        );
+        golden(
+            ThroughputStatsPayload {
+                test_id: 0x11,
+                total_bytes: 1_000_000,
+                duration_ms: 1000,
+                throughput_kbps: 8000,
+                packet_loss_pct: 0.5,
+            },
+            "00000011 00000000000f4240 000003e8 00001f40 3f000000",
+        );
+        golden(
+            DownloadRequestPayload { test_id: 0x12, total_size: 1 << 20, rate_kbps: 10_000 },
+            "00000012 0000000000100000 00002710",
+        );
+        golden(
+            DownloadDataPayload { test_id: 0x12, sequence: 0, data: vec![0xff] },
+            "00000012 00000000 ff",
+        );
+        golden(
+            DownloadEndPayload { test_id: 0x12, total_bytes: 1 << 20, packets_sent: 42, duration_ms: 1000 },
+            "00000012 0000000000100000 0000002a 000003e8",
+        );
+        golden(
+            BufferbloatStartPayload {
+                test_id: 0x13,
+                direction: BufferbloatDirection::Download,
+                duration_ms: 5000,
+                rate_kbps: 0,
+            },
+            "00000013 01 00001388 00000000",
+        );
+        golden(
+            BufferbloatEndPayload {
+                test_id: 0x13,
+                direction: BufferbloatDirection::Upload,
+                total_bytes: 0x10000,
+                duration_ms: 5000,
+            },
+            "00000013 00 0000000000010000 00001388",
+        );
+    }
+    
+    proptest! {
+        #[test]
+        fn prop_header_roundtrip(
+            version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION,
+            packet_type in packet_type(),
+            payload_len: u16,
+            client_id: u64,
+            session_id: u64,
+            nonce_timestamp: u64,
+        ) {
+            let header = PacketHeader {
+                magic: crate::constants::MAGIC_BYTES,
+                version,
+                packet_type,
+                payload_len,
+                client_id,
+                session_id,
+                nonce_timestamp,
+            };
+            prop_assert_eq!(PacketHeader::from_bytes(&header.to_bytes()).ok(), Some(header));
+        }
+        
+        #[test]
+        fn prop_parsers_never_panic(
+            packet_type in packet_type(),
+            bytes in prop::collection::vec(any::<u8>(), 0..128),
+        ) {
+            let _ = PacketHeader::from_bytes(&bytes);
+            decode_payload(packet_type, &bytes);
+        }
+        
+        #[test]
+        fn prop_knock_roundtrip(
+            challenge: [u8; 32],
+            public_key: [u8; PUBLIC_KEY_SIZE],
+            min_version: u8,
+            max_version: u8,
+            capabilities: u32,
+        ) {
+            roundtrip(KnockPayload {
+                challenge,
+                public_key,
+                min_version,
+                max_version,
+                capabilities: Capabilities(capabilities),
+            })?;
+        }
+        
+        #[test]
+        fn prop_knock_ack_roundtrip(
+            session_id: u64,
+            challenge_response: [u8; 32],
+            public_key: [u8; PUBLIC_KEY_SIZE],
+            version: u8,
+            capabilities: u32,
+            limits: (u64, u32, u32),
+        ) {
+            roundtrip(KnockAckPayload {
+                session_id,
+                challenge_response,
+                public_key,
+                version,
+                capabilities: Capabilities(capabilities),
+                limits: ServerLimits {
+                    max_download_bytes: limits.0,
+                    max_rate_kbps: limits.1,
+                    max_phase_ms: limits.2,
+                },
+            })?;
+        }
+        
+        #[test]
+        fn prop_error_roundtrip(
+            code in prop::sample::select(ERROR_CODES.to_vec()),
+            request in packet_type(),
+            retry_after_ms in prop::option::of(1..=u32::MAX as u64),
+        ) {
+            // Retry hints travel in whole milliseconds
+            roundtrip(ErrorPayload {
+                code,
+                request,
+                retry_after: retry_after_ms.map(Duration::from_millis),
+            })?;
+        }
+        
+        #[test]
+        fn prop_echo_roundtrip(sequence: u32, timestamps: (u64, u64, u64)) {
+            roundtrip(EchoRequestPayload::with_timestamp(sequence, timestamps.0))?;
+            roundtrip(EchoReplyPayload {
+                sequence,
+                client_send_timestamp: timestamps.0,
+                server_recv_timestamp: timestamps.1,
+                server_send_timestamp: timestamps.2,
+            })?;
+        }
+        
+        #[test]
+        fn prop_throughput_roundtrip(
+            test_id: u32,
+            sequence: u32,
+            total: u64,
+            data in data(),
+            duration_ms: u32,
+            throughput_kbps: u32,
+            packet_loss_pct in 0.0f32..=100.0,
+        ) {
+            roundtrip(ThroughputStartPayload { test_id, total_size: total })?;
+            roundtrip(ThroughputDataPayload { test_id, sequence, data })?;
+            roundtrip(ThroughputEndPayload { test_id, total_bytes: total })?;
+            roundtrip(ThroughputStatsPayload {
+                test_id,
+                total_bytes: total,
+                duration_ms,
+                throughput_kbps,
+                packet_loss_pct,
+            })?;
+        }
+        
+        #[test]
+        fn prop_download_roundtrip(
+            test_id: u32,
+            sequence: u32,
+            total: u64,
+            rate_kbps: u32,
+            data in data(),
+            duration_ms: u32,
+        ) {
+            roundtrip(DownloadRequestPayload { test_id, total_size: total, rate_kbps })?;
+            roundtrip(DownloadDataPayload { test_id, sequence, data })?;
+            roundtrip(DownloadEndPayload {
+                test_id,
+                total_bytes: total,
+                packets_sent: sequence,
+                duration_ms,
+            })?;
+        }
+        
+        #[test]
+        fn prop_bufferbloat_roundtrip(
+            test_id: u32,
+            direction in direction(),
+            total_bytes: u64,
+            duration_ms: u32,
+            rate_kbps: u32,
+        ) {
+            roundtrip(BufferbloatStartPayload { test_id, direction, duration_ms, rate_kbps })?;
+            roundtrip(BufferbloatEndPayload { test_id, direction, total_bytes, duration_ms })?;
+        }
+    }
+    
+    #[test]
+    fn test_version_negotiation_and_knock_ack() {
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION, u8::MAX), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 1, u8::MAX), None);
+        assert_eq!(negotiate_version(0, MIN_PROTOCOL_VERSION - 1), None);
+        
+        let ack = KnockAckPayload {
+            session_id: 42,
+            challenge_response: [1; 32],
+            public_key: [2; PUBLIC_KEY_SIZE],
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::ECHO | Capabilities::DOWNLOAD,
+            limits: ServerLimits {
+                max_download_bytes: 100 * 1024 * 1024,
+                max_rate_kbps: 10_000,
+                max_phase_ms: 300_000,
+            },
+        };
+        let parsed = KnockAckPayload::decode(&ack.encode()).unwrap();
+        assert_eq!(parsed.version, PROTOCOL_VERSION);
+        assert!(parsed.capabilities.contains(Capabilities::DOWNLOAD));
+        assert!(!parsed.capabilities.contains(Capabilities::UPLOAD));
+        assert_eq!(parsed.limits, ack.limits);
+        assert_eq!(parsed.limits.clamp_rate_kbps(0), 10_000);
+        assert_eq!(parsed.limits.clamp_rate_kbps(5_000), 5_000);
+    }
+}
@@ -0,0 +1,167 @@
+//! KNOCK outcomes ([logging] section)
+//!
+//! Every KNOCK that reaches authentication ends up here, accepted or not.
+//! It goes to the server log if log_successful_knocks / log_failed_knocks
+//! ask for it, is counted in the metrics, and is appended to the audit_log
+//! file if one is configured. The file gets every outcome regardless of the
+//! log_* options, one line each:
+//!
+//! ```text
+//! 2025-10-20T14:03:11Z KNOCK_FAILED ip=192.0.2.7 port=40312 client_id=17 reason="no valid key for client 17"
+//! 2025-10-20T14:03:12Z KNOCK_OK ip=192.0.2.8 port=51220 client_id=42 session_id=9
+//! ```
+//!
+//! which a fail2ban filter can match with
+//! `failregex = ^\S+ KNOCK_FAILED ip=<HOST> `. IPv4 clients of a dual-stack
+//! socket are written as plain IPv4 addresses, not IPv4-mapped IPv6 ones,
+//! so the ban hits the address family they use. The file is reopened on a
+//! config reload, so it can be rotated like any other log.
+
+use crate::config::LoggingConfig;
+use crate::metrics::Metrics;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
+
+/// Reports KNOCK outcomes to the log, the metrics and the audit file
+pub struct KnockAudit {
+    log_successful: AtomicBool,
+    log_failed: AtomicBool,
+    file: Mutex<Option<File>>,
+    metrics: Arc<Metrics>,
+}
+
+impl KnockAudit {
+    /// Open (or create) the audit file if one is configured
+    pub fn new(config: &LoggingConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        let audit = Self {
+            log_successful: AtomicBool::new(false),
+            log_failed: AtomicBool::new(false),
+            file: Mutex::new(None),
+            metrics,
+        };
+        audit.reconfigure(config)?;
+        Ok(audit)
+    }
+    
+    /// Apply the [logging] options and (re)open the audit file
+    ///
+    /// Nothing changes if the file can't be opened.
+    pub fn reconfigure(&self, config: &LoggingConfig) -> Result<()> {
+        let file = match &config.audit_log {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open audit log {}", path))?,
+            ),
+            None => None,
+        };
+        
+        *self.file.lock().unwrap() = file;
+        self.log_successful.store(config.log_successful_knocks, Ordering::Relaxed);
+        self.log_failed.store(config.log_failed_knocks, Ordering::Relaxed);
+        Ok(())
+    }
+    
+    /// A KNOCK created a session
+    pub fn accepted(&self, addr: SocketAddr, client_id: u64, session_id: u64, version: u8) {
+        if self.log_successful.load(Ordering::Relaxed) {
+            info!(
+                "Created session {} for client {} ({}, protocol v{})",
+                session_id, client_id, addr, version
+            );
+        } else {
+            debug!(
+                "Created session {} for client {} ({}, protocol v{})",
+                session_id, client_id, addr, version
+            );
+        }
+        self.metrics.record_knock(true);
+        self.write(&format!(
+            "KNOCK_OK ip={} port={} client_id={} session_id={}",
+            addr.ip().to_canonical(),
+            addr.port(),
+            client_id,
+            session_id
+        ));
+    }
+    
+    /// A KNOCK failed authentication or was refused
+    pub fn rejected(&self, addr: SocketAddr, client_id: u64, reason: &str) {
+        if self.log_failed.load(Ordering::Relaxed) {
+            warn!("KNOCK failed from {} (client {}): {}", addr, client_id, reason);
+        } else {
+            debug!("KNOCK failed from {} (client {}): {}", addr, client_id, reason);
+        }
+        self.metrics.record_knock(false);
+        self.write(&format!(
+            "KNOCK_FAILED ip={} port={} client_id={} reason=\"{}\"",
+            addr.ip().to_canonical(),
+            addr.port(),
+            client_id,
+            sanitize(reason)
+        ));
+    }
+    
+    fn write(&self, event: &str) {
+        let mut file = self.file.lock().unwrap();
+        let Some(file) = file.as_mut() else {
+            return;
+        };
+        
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let line = format!("{} {}\n", timestamp, event);
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write audit log: {}", e);
+        }
+    }
+}
+
+/// Keep a reason on one line and inside its quotes
+fn sanitize(reason: &str) -> String {
+    reason
+        .chars()
+        .map(|c| if c == '"' || c.is_control() { '\'' } else { c })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_audit_lines() {
+        let path = std::env::temp_dir().join(format!("bufferbane-audit-{}.log", std::process::id()));
+        let config = LoggingConfig {
+            level: "info".to_string(),
+            log_successful_knocks: false,
+            log_failed_knocks: false,
+            log_echo_requests: false,
+            audit_log: Some(path.to_string_lossy().into_owned()),
+        };
+        let audit = KnockAudit::new(&config, Arc::default()).unwrap();
+        let addr: SocketAddr = "192.0.2.7:40312".parse().unwrap();
+        
+        audit.rejected(addr, 17, "bad \"key\"\nsecond line");
+        // IPv4 client on a dual-stack socket
+        let mapped: SocketAddr = "[::ffff:192.0.2.7]:40312".parse().unwrap();
+        audit.accepted(mapped, 42, 9, 1);
+        
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" KNOCK_FAILED ip=192.0.2.7 port=40312 client_id=17 reason=\"bad 'key''second line\""));
+        assert!(lines[1].ends_with(" KNOCK_OK ip=192.0.2.7 port=40312 client_id=42 session_id=9"));
+        
+        // Timestamp first, as fail2ban expects
+        let (timestamp, _) = lines[0].split_once(' ').unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+    }
+}
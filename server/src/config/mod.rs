@@ -2,50 +2,144 @@
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub general: GeneralConfig,
     pub security: SecurityConfig,
-    #[allow(dead_code)]
     pub rate_limiting: RateLimitingConfig,
-    #[allow(dead_code)]
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    #[serde(default)]
+    pub monitoring: MonitoringConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct GeneralConfig {
+    /// IP address to listen on; "::" listens on IPv6 and IPv4 (dual-stack)
     pub bind_address: String,
     pub bind_port: u16,
-    pub max_concurrent_clients: usize,
+    pub max_concurrent_clients: usize,  // 0 = unlimited
+    #[serde(default)]
+    pub admission_policy: AdmissionPolicy,
+    /// How long SIGTERM waits for running tests before exiting
+    #[serde(default = "default_shutdown_grace_sec")]
+    pub shutdown_grace_sec: u64,
+}
+
+/// What a KNOCK from a new client gets while max_concurrent_clients are connected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdmissionPolicy {
+    /// Answer with SERVER_OVERLOADED
+    #[default]
+    Reject,
+    /// Drop the sessions of the client that was seen least recently
+    EvictLru,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct SecurityConfig {
-    pub shared_secret: String,  // Hex-encoded 32-byte secret
-    #[allow(dead_code)]
+    pub shared_secret: String,  // Hex-encoded 32-byte secret, "" = key_file only
+    #[serde(default)]
+    pub key_file: Option<String>,
     pub knock_timeout_sec: u64,
+    #[serde(default)]
+    pub port_knocking: bool,
+    #[serde(default = "default_knock_ports")]
+    pub knock_ports: Vec<u16>,
     pub session_timeout_sec: u64,
-    #[allow(dead_code)]
     pub enable_rate_limiting: bool,
+    #[serde(default = "default_replay_window_sec")]
+    pub replay_window_sec: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
-#[allow(dead_code)]
 pub struct RateLimitingConfig {
     pub max_packets_per_second: usize,
-    pub max_bandwidth_mbps: usize,
+    pub max_bandwidth_mbps: usize,  // 0 = unlimited
     pub burst_size: usize,
+    #[serde(default = "default_decrypt_failure_threshold")]
+    pub decrypt_failure_threshold: u32,  // 0 = never block
+    #[serde(default = "default_decrypt_failure_window_sec")]
+    pub decrypt_failure_window_sec: u64,
+    #[serde(default = "default_decrypt_failure_block_sec")]
+    pub decrypt_failure_block_sec: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
-#[allow(dead_code)]
 pub struct LoggingConfig {
-    pub level: String,
+    pub level: String,  // Overridden by RUST_LOG
     pub log_successful_knocks: bool,
     pub log_failed_knocks: bool,
     pub log_echo_requests: bool,
+    /// File that gets a line for every KNOCK outcome (for fail2ban)
+    #[serde(default)]
+    pub audit_log: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuotaConfig {
+    /// Tests a client may run at the same time (0 = unlimited)
+    #[serde(default = "default_max_tests_per_client")]
+    pub max_tests_per_client: usize,
+    /// Traffic per client and UTC day in MB, tests and echo alike (0 = unlimited)
+    #[serde(default)]
+    pub daily_mb_per_client: u64,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            max_tests_per_client: default_max_tests_per_client(),
+            daily_mb_per_client: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MonitoringConfig {
+    /// Serve /metrics and /healthz over HTTP
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_monitoring_bind_address")]
+    pub bind_address: String,
+    #[serde(default = "default_monitoring_port")]
+    pub port: u16,
+}
+
+impl Default for MonitoringConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: default_monitoring_bind_address(),
+            port: default_monitoring_port(),
+        }
+    }
+}
+
+impl GeneralConfig {
+    /// Address of the service socket
+    pub fn bind_addr(&self) -> SocketAddr {
+        self.socket_addr(self.bind_port)
+    }
+    
+    /// Address for `port` on bind_address (knock ports)
+    pub fn socket_addr(&self, port: u16) -> SocketAddr {
+        let ip: IpAddr = self.bind_address.parse().expect("bind_address is validated on load");
+        SocketAddr::new(ip, port)
+    }
+}
+
+impl MonitoringConfig {
+    /// Address of the HTTP endpoint
+    pub fn bind_addr(&self) -> SocketAddr {
+        let ip: IpAddr = self.bind_address.parse().expect("bind_address is validated on load");
+        SocketAddr::new(ip, self.port)
+    }
 }
 
 impl Config {
@@ -57,11 +151,90 @@ impl Config {
             .context("Failed to parse config file")?;
         
         // Validate shared secret format
-        if config.security.shared_secret.len() != 64 {
+        if config.security.shared_secret.is_empty() {
+            if config.security.key_file.is_none() {
+                anyhow::bail!("Either shared_secret or key_file must be set");
+            }
+        } else if config.security.shared_secret.len() != 64 {
             anyhow::bail!("shared_secret must be exactly 64 hex characters (32 bytes)");
         }
         
+        if config.general.bind_address.parse::<IpAddr>().is_err() {
+            anyhow::bail!(
+                "bind_address \"{}\" is not an IP address (e.g. \"0.0.0.0\" or \"::\")",
+                config.general.bind_address
+            );
+        }
+        if config.monitoring.bind_address.parse::<IpAddr>().is_err() {
+            anyhow::bail!(
+                "monitoring bind_address \"{}\" is not an IP address",
+                config.monitoring.bind_address
+            );
+        }
+        
+        if config.logging.level.parse::<tracing::Level>().is_err() {
+            anyhow::bail!(
+                "Invalid log level \"{}\" (expected trace, debug, info, warn or error)",
+                config.logging.level
+            );
+        }
+        
+        if config.security.replay_window_sec == 0 {
+            anyhow::bail!("replay_window_sec must be greater than 0");
+        }
+        
+        if config.security.port_knocking {
+            let ports = &config.security.knock_ports;
+            if ports.is_empty() {
+                anyhow::bail!("knock_ports must not be empty when port_knocking is enabled");
+            }
+            if ports.iter().enumerate().any(|(i, port)| ports[..i].contains(port)) {
+                anyhow::bail!("knock_ports must not contain a port twice");
+            }
+            if ports.contains(&config.general.bind_port) {
+                anyhow::bail!("knock_ports must not contain bind_port");
+            }
+            if config.security.knock_timeout_sec == 0 {
+                anyhow::bail!("knock_timeout_sec must be greater than 0");
+            }
+        }
+        
         Ok(config)
     }
 }
 
+fn default_shutdown_grace_sec() -> u64 {
+    10
+}
+
+fn default_max_tests_per_client() -> usize {
+    2
+}
+
+fn default_knock_ports() -> Vec<u16> {
+    protocol::KNOCK_SEQUENCE.to_vec()
+}
+
+fn default_replay_window_sec() -> u64 {
+    30
+}
+
+fn default_decrypt_failure_threshold() -> u32 {
+    10
+}
+
+fn default_decrypt_failure_window_sec() -> u64 {
+    60
+}
+
+fn default_decrypt_failure_block_sec() -> u64 {
+    300 // 5 minutes
+}
+
+fn default_monitoring_bind_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_monitoring_port() -> u16 {
+    9877
+}
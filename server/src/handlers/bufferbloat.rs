@@ -0,0 +1,265 @@
+//! Bufferbloat testing handler (load phases)
+//!
+//! The client measures latency with ordinary ECHO_REQUESTs while a load
+//! phase saturates one direction:
+//! - Upload: BUFFERBLOAT_START registers an upload test, the client streams
+//!   THROUGHPUT_DATA for it and BUFFERBLOAT_END is answered with what arrived
+//! - Download: BUFFERBLOAT_START starts a time-limited DOWNLOAD_DATA stream,
+//!   BUFFERBLOAT_END stops it early if it is still running
+
+use super::download::{self, DownloadManager, DownloadStream};
+use super::{seal, PacketContext, PacketHandler};
+use super::throughput::ThroughputManager;
+use async_trait::async_trait;
+use protocol::{
+    codec::Decode,
+    packets::{
+        BufferbloatDirection, BufferbloatEndPayload, BufferbloatStartPayload, PacketType,
+    },
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+/// Longest load phase a client may request
+pub const MAX_PHASE_DURATION: Duration = Duration::from_secs(300);
+
+/// Handler for bufferbloat packets (BUFFERBLOAT_START and BUFFERBLOAT_END)
+///
+/// Only BUFFERBLOAT_END of an upload phase produces a response.
+pub struct BufferbloatHandler {
+    throughput_manager: Arc<ThroughputManager>,
+    download_manager: Arc<DownloadManager>,
+    socket: Arc<UdpSocket>,
+}
+
+impl BufferbloatHandler {
+    pub fn new(
+        throughput_manager: Arc<ThroughputManager>,
+        download_manager: Arc<DownloadManager>,
+        socket: Arc<UdpSocket>,
+    ) -> Self {
+        Self { throughput_manager, download_manager, socket }
+    }
+}
+
+#[async_trait]
+impl PacketHandler for BufferbloatHandler {
+    fn starts_test(&self, packet_type: PacketType) -> bool {
+        packet_type == PacketType::BufferbloatStart
+    }
+    
+    async fn handle(&self, ctx: PacketContext<'_>) -> Result<Vec<Vec<u8>>, String> {
+        let PacketContext { payload, header, client_addr, key: shared_secret } = ctx;
+        let throughput_manager = &self.throughput_manager;
+        let download_manager = &self.download_manager;
+        
+        match header.packet_type {
+            PacketType::BufferbloatStart => {
+                let start = BufferbloatStartPayload::decode(payload)
+                    .map_err(|e| format!("Invalid bufferbloat start: {}", e))?;
+                
+                let duration = Duration::from_millis(start.duration_ms as u64);
+                if duration.is_zero() || duration > MAX_PHASE_DURATION {
+                    return Err(format!(
+                        "Bufferbloat phase duration {}ms out of range (1..={}ms)",
+                        start.duration_ms,
+                        MAX_PHASE_DURATION.as_millis()
+                    ));
+                }
+                
+                match start.direction {
+                    BufferbloatDirection::Upload => {
+                        throughput_manager
+                            .start_test(header.client_id, start.test_id, 0)
+                            .await;
+                    }
+                    BufferbloatDirection::Download => {
+                        let stream = DownloadStream {
+                            test_id: start.test_id,
+                            total_size: u64::MAX,
+                            deadline: Some(Instant::now() + duration),
+                            rate_kbps: start.rate_kbps,
+                        };
+                        download::start_stream(
+                            stream,
+                            header,
+                            client_addr,
+                            shared_secret,
+                            download_manager.clone(),
+                            self.socket.clone(),
+                        )
+                        .await?;
+                    }
+                }
+                
+                info!(
+                    "Bufferbloat {:?} phase started: test_id={}, duration={}ms, client_id={} ({})",
+                    start.direction, start.test_id, start.duration_ms, header.client_id, client_addr
+                );
+                
+                Ok(Vec::new())
+            }
+            
+            PacketType::BufferbloatEnd => {
+                let end = BufferbloatEndPayload::decode(payload)
+                    .map_err(|e| format!("Invalid bufferbloat end: {}", e))?;
+                
+                match end.direction {
+                    BufferbloatDirection::Upload => {
+                        let stats = throughput_manager
+                            .finish_test(header.client_id, end.test_id, end.total_bytes)
+                            .await?;
+                        
+                        let reply = BufferbloatEndPayload {
+                            test_id: end.test_id,
+                            direction: end.direction,
+                            total_bytes: stats.total_bytes,
+                            duration_ms: stats.duration_ms,
+                        };
+                        
+                        seal(&reply, header, shared_secret).map(|reply| vec![reply])
+                    }
+                    BufferbloatDirection::Download => {
+                        if download_manager.stop(header.client_id).await {
+                            debug!("Stopping bufferbloat download test_id={} early", end.test_id);
+                        }
+                        Ok(Vec::new())
+                    }
+                }
+            }
+            
+            other => Err(format!("Unexpected packet type for bufferbloat handler: {:?}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::codec::{Encode, Packet};
+    use protocol::crypto::Direction;
+    use protocol::packets::{DownloadEndPayload, PacketHeader};
+    
+    const KEY: [u8; 32] = [4; 32];
+    
+    struct Phases {
+        handler: BufferbloatHandler,
+        throughput_manager: Arc<ThroughputManager>,
+        download_manager: Arc<DownloadManager>,
+        client: UdpSocket,
+    }
+    
+    impl Phases {
+        async fn new() -> Self {
+            let throughput_manager = Arc::new(ThroughputManager::default());
+            let download_manager = Arc::new(DownloadManager::new(0, Arc::default(), Arc::default()));
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            Self {
+                handler: BufferbloatHandler::new(throughput_manager.clone(), download_manager.clone(), socket),
+                throughput_manager,
+                download_manager,
+                client: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            }
+        }
+        
+        /// Hand a packet of client 42 to the handler
+        async fn send<P: Encode>(&self, payload: &P) -> Result<Vec<Vec<u8>>, String> {
+            let header = PacketHeader::new(P::PACKET_TYPE, 0, 42, 7);
+            self.handler
+                .handle(PacketContext {
+                    payload: &payload.encode(),
+                    header: &header,
+                    client_addr: self.client.local_addr().unwrap(),
+                    key: &KEY,
+                })
+                .await
+        }
+        
+        async fn start(&self, direction: BufferbloatDirection, duration_ms: u32) -> Result<Vec<Vec<u8>>, String> {
+            self.send(&BufferbloatStartPayload {
+                test_id: 9,
+                direction,
+                duration_ms,
+                rate_kbps: 800,
+            })
+            .await
+        }
+        
+        async fn end(&self, direction: BufferbloatDirection, total_bytes: u64) -> Result<Vec<Vec<u8>>, String> {
+            self.send(&BufferbloatEndPayload {
+                test_id: 9,
+                direction,
+                total_bytes,
+                duration_ms: 100,
+            })
+            .await
+        }
+        
+        /// Wait for the DOWNLOAD_END of the download phase
+        async fn download_end(&self) -> DownloadEndPayload {
+            let mut buf = vec![0u8; 65535];
+            loop {
+                let len = tokio::time::timeout(Duration::from_secs(5), self.client.recv(&mut buf))
+                    .await
+                    .expect("download phase did not end")
+                    .unwrap();
+                let packet = Packet::open(&buf[..len], Direction::ServerToClient, &KEY).unwrap();
+                if packet.header.packet_type == PacketType::DownloadEnd {
+                    return packet.decode().unwrap();
+                }
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_upload_then_download_phase() {
+        let phases = Phases::new().await;
+        
+        // An upload phase has to be started before it can end
+        assert!(phases.end(BufferbloatDirection::Upload, 0).await.is_err());
+        assert!(phases.start(BufferbloatDirection::Upload, 0).await.is_err());
+        assert!(phases.start(BufferbloatDirection::Upload, 301_000).await.is_err());
+        
+        assert!(phases.start(BufferbloatDirection::Upload, 200).await.unwrap().is_empty());
+        assert_eq!(phases.throughput_manager.running_tests_of(42).await, 1);
+        
+        // Nothing arrived, which the reply to BUFFERBLOAT_END reports
+        let replies = phases.end(BufferbloatDirection::Upload, 12_000).await.unwrap();
+        assert_eq!(replies.len(), 1);
+        let reply: BufferbloatEndPayload = Packet::open(&replies[0], Direction::ServerToClient, &KEY)
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!(reply.test_id, 9);
+        assert_eq!(reply.direction, BufferbloatDirection::Upload);
+        assert_eq!(reply.total_bytes, 0);
+        assert_eq!(phases.throughput_manager.running_tests_of(42).await, 0);
+        
+        // The download phase runs for its duration without BUFFERBLOAT_END
+        let started = Instant::now();
+        assert!(phases.start(BufferbloatDirection::Download, 150).await.unwrap().is_empty());
+        assert!(phases.download_manager.is_running(42).await);
+        let end = phases.download_end().await;
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(end.duration_ms >= 140, "ended after {}ms", end.duration_ms);
+        assert!(end.total_bytes > 0);
+    }
+    
+    #[tokio::test]
+    async fn test_download_phase_ends_early() {
+        let phases = Phases::new().await;
+        
+        assert!(phases.start(BufferbloatDirection::Download, 10_000).await.unwrap().is_empty());
+        // A client runs one phase at a time
+        assert!(phases.start(BufferbloatDirection::Download, 10_000).await.is_err());
+        
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let started = Instant::now();
+        assert!(phases.end(BufferbloatDirection::Download, 0).await.unwrap().is_empty());
+        let end = phases.download_end().await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(end.duration_ms < 1000, "ended after {}ms", end.duration_ms);
+    }
+}
@@ -0,0 +1,427 @@
+//! Download testing handler (server -> client throughput)
+//!
+//! On DOWNLOAD_REQUEST the server streams paced, encrypted DOWNLOAD_DATA
+//! chunks to the client and finishes with DOWNLOAD_END, which reports how
+//! much was sent so the client can compute loss from what arrived.
+//!
+//! The same stream, limited by time instead of size, provides the download
+//! load of a bufferbloat test.
+
+use super::{seal, PacketContext, PacketHandler};
+use crate::metrics::Metrics;
+use crate::quota::Quotas;
+use async_trait::async_trait;
+use protocol::{
+    codec::Decode,
+    packets::{DownloadDataPayload, DownloadEndPayload, DownloadRequestPayload, PacketHeader, PacketType},
+    THROUGHPUT_CHUNK_SIZE,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// Largest download a client may request (100 MB)
+pub const MAX_DOWNLOAD_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Pacing granularity: the sender wakes up this often and sends whatever
+/// the requested rate allows for the elapsed time
+const PACING_TICK: Duration = Duration::from_millis(1);
+
+/// DOWNLOAD_END is sent a few times since the client has no way to ask again
+const END_REPEAT: usize = 3;
+
+/// Tracks running downloads so a client can only have one at a time
+pub struct DownloadManager {
+    /// Stop flag of the running stream, keyed by client_id
+    active: Mutex<HashMap<u64, Arc<AtomicBool>>>,
+    /// Upper bound for stream rates in kbps (0 = unlimited)
+    max_rate_kbps: AtomicU32,
+    /// Streams are sent outside the main loop, so they count their own bytes
+    metrics: Arc<Metrics>,
+    quotas: Arc<Quotas>,
+}
+
+impl DownloadManager {
+    pub fn new(max_rate_kbps: u32, metrics: Arc<Metrics>, quotas: Arc<Quotas>) -> Self {
+        Self {
+            active: Mutex::new(HashMap::new()),
+            max_rate_kbps: AtomicU32::new(max_rate_kbps),
+            metrics,
+            quotas,
+        }
+    }
+    
+    /// Upper bound for stream rates in kbps (0 = unlimited)
+    pub fn max_rate_kbps(&self) -> u32 {
+        self.max_rate_kbps.load(Ordering::Relaxed)
+    }
+    
+    /// Change the rate cap for streams started from now on (config reload)
+    pub fn set_max_rate_kbps(&self, max_rate_kbps: u32) {
+        self.max_rate_kbps.store(max_rate_kbps, Ordering::Relaxed);
+    }
+    
+    /// Get number of running downloads
+    pub async fn active_downloads(&self) -> usize {
+        self.active.lock().await.len()
+    }
+    
+    /// Whether a client has a stream running
+    pub async fn is_running(&self, client_id: u64) -> bool {
+        self.active.lock().await.contains_key(&client_id)
+    }
+    
+    /// Ask a client's running stream to finish early
+    ///
+    /// Returns false if the client has no stream running.
+    pub async fn stop(&self, client_id: u64) -> bool {
+        match self.active.lock().await.get(&client_id) {
+            Some(stop) => {
+                stop.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+    
+    /// Ask all running streams to finish early (shutdown)
+    ///
+    /// Each still sends its DOWNLOAD_END. Returns how many were running.
+    pub async fn stop_all(&self) -> usize {
+        let active = self.active.lock().await;
+        for stop in active.values() {
+            stop.store(true, Ordering::Relaxed);
+        }
+        active.len()
+    }
+}
+
+/// Parameters of a DOWNLOAD_DATA stream
+#[derive(Debug, Clone)]
+pub struct DownloadStream {
+    pub test_id: u32,
+    /// Stop after this many bytes
+    pub total_size: u64,
+    /// Stop at this point in time even if `total_size` was not reached
+    pub deadline: Option<Instant>,
+    /// Send rate in kbps (0 = unpaced)
+    pub rate_kbps: u32,
+}
+
+/// Handler for DOWNLOAD_REQUEST
+///
+/// The stream is sent from a background task; nothing is returned to the
+/// main loop directly.
+pub struct DownloadHandler {
+    manager: Arc<DownloadManager>,
+    socket: Arc<UdpSocket>,
+}
+
+impl DownloadHandler {
+    pub fn new(manager: Arc<DownloadManager>, socket: Arc<UdpSocket>) -> Self {
+        Self { manager, socket }
+    }
+}
+
+#[async_trait]
+impl PacketHandler for DownloadHandler {
+    fn starts_test(&self, _packet_type: PacketType) -> bool {
+        true
+    }
+    
+    async fn handle(&self, ctx: PacketContext<'_>) -> Result<Vec<Vec<u8>>, String> {
+        let PacketContext { payload, header, client_addr, key: shared_secret } = ctx;
+        
+        let request = DownloadRequestPayload::decode(payload)
+            .map_err(|e| format!("Invalid download request: {}", e))?;
+        
+        if request.total_size == 0 || request.total_size > MAX_DOWNLOAD_SIZE {
+            return Err(format!(
+                "Download size {} out of range (1..={})",
+                request.total_size, MAX_DOWNLOAD_SIZE
+            ));
+        }
+        
+        let stream = DownloadStream {
+            test_id: request.test_id,
+            total_size: request.total_size,
+            deadline: None,
+            rate_kbps: request.rate_kbps,
+        };
+        start_stream(
+            stream,
+            header,
+            client_addr,
+            shared_secret,
+            self.manager.clone(),
+            self.socket.clone(),
+        )
+        .await?;
+        
+        info!(
+            "Download test started: test_id={}, total_size={} bytes, rate={} kbps, client_id={} ({})",
+            request.test_id,
+            request.total_size,
+            request.rate_kbps,
+            header.client_id,
+            client_addr
+        );
+        
+        Ok(Vec::new())
+    }
+}
+
+/// Start sending a DOWNLOAD_DATA stream from a background task
+///
+/// Fails if the client already has a stream running. The rate is capped to
+/// the manager's limit.
+pub async fn start_stream(
+    mut stream: DownloadStream,
+    header: &PacketHeader,
+    client_addr: SocketAddr,
+    shared_secret: &[u8; 32],
+    download_manager: Arc<DownloadManager>,
+    socket: Arc<UdpSocket>,
+) -> Result<(), String> {
+    let max_rate_kbps = download_manager.max_rate_kbps();
+    if max_rate_kbps > 0 && (stream.rate_kbps == 0 || stream.rate_kbps > max_rate_kbps) {
+        debug!(
+            "Capping download test_id={} at {} kbps (requested {})",
+            stream.test_id, max_rate_kbps, stream.rate_kbps
+        );
+        stream.rate_kbps = max_rate_kbps;
+    }
+    
+    let client_id = header.client_id;
+    let request = *header;
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let mut active = download_manager.active.lock().await;
+        if active.contains_key(&client_id) {
+            return Err(format!("Client {} already has a download running", client_id));
+        }
+        active.insert(client_id, stop.clone());
+    }
+    
+    let shared_secret = *shared_secret;
+    tokio::spawn(async move {
+        match stream_download(&stream, &stop, &request, client_addr, &shared_secret, &socket, &download_manager).await {
+            Ok(end) => info!(
+                "Download test finished: test_id={}, sent {} bytes in {} packets, {}ms",
+                end.test_id, end.total_bytes, end.packets_sent, end.duration_ms
+            ),
+            Err(e) => warn!("Download test {} to {} aborted: {}", stream.test_id, client_addr, e),
+        }
+        
+        download_manager.active.lock().await.remove(&client_id);
+    });
+    
+    Ok(())
+}
+
+/// Send the requested amount of data, paced to the requested rate
+///
+/// Ends early at the stream deadline, once `stop` is set or once the
+/// client's daily quota is used up; DOWNLOAD_END is sent either way.
+async fn stream_download(
+    stream: &DownloadStream,
+    stop: &AtomicBool,
+    request: &PacketHeader,
+    client_addr: SocketAddr,
+    shared_secret: &[u8; 32],
+    socket: &UdpSocket,
+    download_manager: &DownloadManager,
+) -> Result<DownloadEndPayload, String> {
+    let DownloadManager { metrics, quotas, .. } = download_manager;
+    let started_at = Instant::now();
+    let bytes_per_sec = stream.rate_kbps as f64 * 1000.0 / 8.0;
+    
+    let mut sent: u64 = 0;
+    let mut sequence: u32 = 0;
+    
+    while sent < stream.total_size {
+        if stop.load(Ordering::Relaxed) || stream.deadline.is_some_and(|d| Instant::now() >= d) {
+            break;
+        }
+        if quotas.bytes_left(request.client_id) == Some(0) {
+            debug!("Download test_id={} reached the daily quota of client {}", stream.test_id, request.client_id);
+            break;
+        }
+        
+        // Bytes the rate allows so far (unlimited if no rate requested)
+        let allowed = if stream.rate_kbps > 0 {
+            (started_at.elapsed().as_secs_f64() * bytes_per_sec) as u64
+        } else {
+            u64::MAX
+        };
+        
+        if sent >= allowed {
+            tokio::time::sleep(PACING_TICK).await;
+            continue;
+        }
+        
+        let chunk_len = (stream.total_size - sent).min(THROUGHPUT_CHUNK_SIZE as u64) as usize;
+        let data = DownloadDataPayload {
+            test_id: stream.test_id,
+            sequence,
+            data: vec![0u8; chunk_len],
+        };
+        
+        let packet = seal(&data, request, shared_secret)?;
+        socket
+            .send_to(&packet, client_addr)
+            .await
+            .map_err(|e| format!("Failed to send DOWNLOAD_DATA: {}", e))?;
+        metrics.record_sent(packet.len());
+        quotas.record_bytes(request.client_id, packet.len() as u64);
+        
+        sent += chunk_len as u64;
+        sequence = sequence.wrapping_add(1);
+        
+        // Unpaced streams still have to let the receive loop run
+        if stream.rate_kbps == 0 && sequence.is_multiple_of(64) {
+            tokio::task::yield_now().await;
+        }
+    }
+    
+    let end = DownloadEndPayload {
+        test_id: stream.test_id,
+        total_bytes: sent,
+        packets_sent: sequence,
+        duration_ms: started_at.elapsed().as_millis() as u32,
+    };
+    
+    let packet = seal(&end, request, shared_secret)?;
+    for _ in 0..END_REPEAT {
+        match socket.send_to(&packet, client_addr).await {
+            Ok(len) => {
+                metrics.record_sent(len);
+                quotas.record_bytes(request.client_id, len as u64);
+            }
+            Err(e) => debug!("Failed to send DOWNLOAD_END to {}: {}", client_addr, e),
+        }
+    }
+    
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QuotaConfig;
+    use protocol::codec::Packet;
+    use protocol::crypto::Direction;
+    
+    const KEY: [u8; 32] = [3; 32];
+    
+    /// Start `stream` for client 42 towards a fresh client socket
+    async fn start(stream: DownloadStream, manager: &Arc<DownloadManager>) -> Result<UdpSocket, String> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = PacketHeader::new(PacketType::DownloadRequest, 0, 42, 7);
+        start_stream(stream, &request, client.local_addr().unwrap(), &KEY, manager.clone(), socket).await?;
+        Ok(client)
+    }
+    
+    /// Receive the chunks of a stream up to its first DOWNLOAD_END
+    async fn receive(client: &UdpSocket) -> (Vec<DownloadDataPayload>, DownloadEndPayload) {
+        let mut buf = vec![0u8; 65535];
+        let mut chunks = Vec::new();
+        loop {
+            let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .expect("stream stalled")
+                .unwrap();
+            let packet = Packet::open(&buf[..len], Direction::ServerToClient, &KEY).unwrap();
+            match packet.header.packet_type {
+                PacketType::DownloadData => chunks.push(packet.decode().unwrap()),
+                PacketType::DownloadEnd => return (chunks, packet.decode().unwrap()),
+                other => panic!("Unexpected {:?}", other),
+            }
+        }
+    }
+    
+    fn stream(total_size: u64, deadline: Option<Instant>) -> DownloadStream {
+        DownloadStream {
+            test_id: 5,
+            total_size,
+            deadline,
+            rate_kbps: 0,
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_stream_is_paced_and_ends_after_total_size() {
+        // Unpaced requests are capped to 800 kbps = 100 kB/s
+        let manager = Arc::new(DownloadManager::new(800, Arc::default(), Arc::default()));
+        let started = Instant::now();
+        let client = start(stream(30_500, None), &manager).await.unwrap();
+        
+        // One stream per client at a time
+        assert!(start(stream(1000, None), &manager).await.is_err());
+        
+        let (chunks, end) = receive(&client).await;
+        assert!(started.elapsed() >= Duration::from_millis(250), "sent in {:?}", started.elapsed());
+        
+        let sequences: Vec<u32> = chunks.iter().map(|chunk| chunk.sequence).collect();
+        assert_eq!(sequences, (0..26).collect::<Vec<_>>());
+        assert_eq!(chunks.last().unwrap().data.len(), 500);
+        assert_eq!(end.test_id, 5);
+        assert_eq!(end.total_bytes, 30_500);
+        assert_eq!(end.packets_sent, 26);
+        
+        // DOWNLOAD_END is repeated, then the client may start another stream
+        for _ in 1..END_REPEAT {
+            let (chunks, repeated) = receive(&client).await;
+            assert!(chunks.is_empty());
+            assert_eq!(repeated, end);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!manager.is_running(42).await);
+    }
+    
+    #[tokio::test]
+    async fn test_stream_ends_at_deadline_or_when_stopped() {
+        let manager = Arc::new(DownloadManager::new(800, Arc::default(), Arc::default()));
+        
+        let deadline = Instant::now() + Duration::from_millis(150);
+        let client = start(stream(u64::MAX, Some(deadline)), &manager).await.unwrap();
+        let (chunks, end) = receive(&client).await;
+        assert!(Instant::now() >= deadline);
+        assert!(end.duration_ms >= 140, "ended after {}ms", end.duration_ms);
+        // 150ms at 100 kB/s
+        assert!((10_000..=20_000).contains(&end.total_bytes), "sent {} bytes", end.total_bytes);
+        assert_eq!(end.packets_sent as usize, chunks.len());
+        
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let client = start(stream(u64::MAX, None), &manager).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(manager.stop(42).await);
+        let (_, end) = receive(&client).await;
+        assert!(end.total_bytes < 30_000, "sent {} bytes", end.total_bytes);
+    }
+    
+    #[tokio::test]
+    async fn test_stream_ends_when_daily_quota_is_used_up() {
+        let quotas = Arc::new(Quotas::new(QuotaConfig {
+            max_tests_per_client: 0,
+            daily_mb_per_client: 1,
+        }));
+        quotas.record_bytes(42, 990_000);
+        let manager = Arc::new(DownloadManager::new(0, Arc::default(), quotas.clone()));
+        
+        // Like a bufferbloat download phase: no size limit, no deadline
+        let client = start(stream(u64::MAX, None), &manager).await.unwrap();
+        let (chunks, end) = receive(&client).await;
+        
+        // 10 kB left, each chunk costs a bit more on the wire than its data
+        assert!((8_000..10_000).contains(&end.total_bytes), "sent {} bytes", end.total_bytes);
+        assert_eq!(end.packets_sent as usize, chunks.len());
+        assert_eq!(quotas.bytes_left(42), Some(0));
+    }
+}
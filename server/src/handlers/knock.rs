@@ -1,84 +1,148 @@
 //! Port knocking handler
 
+use super::{bufferbloat, download, seal, DownloadManager, PacketContext, PacketHandler};
+use crate::audit::KnockAudit;
 use crate::session::SessionManager;
+use async_trait::async_trait;
 use protocol::{
-    crypto,
+    codec::{Decode, Packet, PacketIds},
+    crypto::{self, Direction, KeyExchange},
     packets::{
-        KnockAckPayload, KnockPayload, PacketHeader, PacketType,
+        self, Capabilities, ErrorCode, ErrorPayload, KnockAckPayload, KnockPayload,
+        PacketType, ServerLimits,
     },
 };
-use std::net::SocketAddr;
 use std::sync::Arc;
-use tracing::{debug, info};
+use tracing::debug;
 
-/// Handle KNOCK packet
+/// Tests this server offers
+const SERVER_CAPABILITIES: Capabilities = Capabilities::ALL;
+
+/// Handler for KNOCK
+///
+/// This authenticates a client and creates a session. The packet was
+/// encrypted with the client's long-term key (`shared_secret`), which also
+/// protects the KNOCK_ACK; the session gets its own key from the X25519
+/// exchange.
 ///
-/// This authenticates a client and creates a session
-pub async fn handle_knock(
-    payload: &[u8],
-    header: &PacketHeader,
-    client_addr: SocketAddr,
-    shared_secret: &[u8; 32],
+/// KNOCK_ACK also tells the client the protocol version chosen for the
+/// session, which tests the server offers and its limits. Clients without
+/// a common version get UNSUPPORTED_VERSION instead, and new clients of a
+/// full server SERVER_OVERLOADED.
+pub struct KnockHandler {
     session_manager: Arc<SessionManager>,
-) -> Result<Vec<u8>, String> {
-    // Decrypt knock payload
-    let nonce = header.nonce();
-    let header_bytes = header.to_bytes();
-    
-    let decrypted = crypto::decrypt(payload, shared_secret, &nonce, &header_bytes)
-        .map_err(|e| format!("Knock decryption failed: {}", e))?;
-    
-    // Parse knock payload
-    let knock = KnockPayload::from_bytes(&decrypted)
-        .map_err(|e| format!("Invalid knock payload: {}", e))?;
-    
-    debug!(
-        "Received valid KNOCK from client_id={}, addr={}",
-        header.client_id, client_addr
-    );
-    
-    // Create session
-    let session_id = session_manager
-        .create_session(header.client_id, client_addr)
-        .await;
-    
-    info!(
-        "Created session {} for client {} ({})",
-        session_id, header.client_id, client_addr
-    );
-    
-    // Prepare KNOCK_ACK response
-    // Challenge response is SHA256 of client challenge
-    use sha2::{Sha256, Digest};
-    let mut hasher = Sha256::new();
-    hasher.update(&knock.challenge);
-    let challenge_response: [u8; 32] = hasher.finalize().into();
-    
-    let ack_payload = KnockAckPayload {
-        session_id,
-        challenge_response,
-    };
-    
-    // Build response packet
-    let ack_bytes = ack_payload.to_bytes();
-    let response_header = PacketHeader::new(
-        PacketType::KnockAck,
-        (ack_bytes.len() + crypto::TAG_SIZE) as u16,
-        header.client_id,
-    );
-    
-    // Encrypt response
-    let response_nonce = response_header.nonce();
-    let response_header_bytes = response_header.to_bytes();
+    /// Source of the current download rate cap
+    download_manager: Arc<DownloadManager>,
+    audit: Arc<KnockAudit>,
+}
+
+impl KnockHandler {
+    pub fn new(
+        session_manager: Arc<SessionManager>,
+        download_manager: Arc<DownloadManager>,
+        audit: Arc<KnockAudit>,
+    ) -> Self {
+        Self { session_manager, download_manager, audit }
+    }
     
-    let encrypted = crypto::encrypt(&ack_bytes, shared_secret, &response_nonce, &response_header_bytes)
-        .map_err(|e| format!("Failed to encrypt response: {}", e))?;
+    /// Limits announced in KNOCK_ACK
+    fn limits(&self) -> ServerLimits {
+        ServerLimits {
+            max_download_bytes: download::MAX_DOWNLOAD_SIZE,
+            max_rate_kbps: self.download_manager.max_rate_kbps(),
+            max_phase_ms: bufferbloat::MAX_PHASE_DURATION.as_millis() as u32,
+        }
+    }
+}
+
+#[async_trait]
+impl PacketHandler for KnockHandler {
+    /// KNOCK is what creates the session
+    fn requires_session(&self) -> bool {
+        false
+    }
     
-    // Combine header + encrypted payload
-    let mut response = Vec::with_capacity(PacketHeader::SIZE + encrypted.len());
-    response.extend_from_slice(&response_header_bytes);
-    response.extend_from_slice(&encrypted);
+    /// Failed authentication is never answered
+    fn reject_with(&self, _packet_type: PacketType) -> Option<ErrorCode> {
+        None
+    }
     
-    Ok(response)
+    async fn handle(&self, ctx: PacketContext<'_>) -> Result<Vec<Vec<u8>>, String> {
+        let PacketContext { payload, header, client_addr, key: shared_secret } = ctx;
+        let session_manager = &self.session_manager;
+        let limits = self.limits();
+        
+        // Parse knock payload
+        let knock = KnockPayload::decode(payload)
+            .map_err(|e| format!("Invalid knock payload: {}", e))?;
+        
+        debug!(
+            "Received valid KNOCK from client_id={}, addr={}, versions {}..={}, capabilities {:#x}",
+            header.client_id, client_addr, knock.min_version, knock.max_version, knock.capabilities.0
+        );
+        
+        let Some(version) = packets::negotiate_version(knock.min_version, knock.max_version) else {
+            let reason = format!(
+                "offers protocol versions {}..={}, we speak {}..={}",
+                knock.min_version,
+                knock.max_version,
+                packets::MIN_PROTOCOL_VERSION,
+                packets::PROTOCOL_VERSION
+            );
+            self.audit.rejected(client_addr, header.client_id, &reason);
+            let error = ErrorPayload::new(ErrorCode::UnsupportedVersion, PacketType::Knock);
+            return seal(&error, header, shared_secret).map(|error| vec![error]);
+        };
+        
+        // Derive the session key from our ephemeral key and the client's
+        let exchange = KeyExchange::new();
+        let server_public = exchange.public_key();
+        let dh_result = exchange
+            .diffie_hellman(&knock.public_key)
+            .map_err(|e| format!("Key exchange failed: {}", e))?;
+        
+        let session_id = session_manager.new_session_id();
+        let session_key = crypto::derive_session_key(
+            shared_secret,
+            &dh_result,
+            &knock.public_key,
+            &server_public,
+            session_id,
+        );
+        
+        // Create session, unless the server is full
+        if let Err(reason) = session_manager
+            .create_session(session_id, header.client_id, session_key, client_addr)
+            .await
+        {
+            self.audit.rejected(client_addr, header.client_id, &reason);
+            let error = ErrorPayload::new(ErrorCode::ServerOverloaded, PacketType::Knock);
+            return seal(&error, header, shared_secret).map(|error| vec![error]);
+        }
+        
+        self.audit.accepted(client_addr, header.client_id, session_id, version);
+        
+        // Prepare KNOCK_ACK response
+        // Challenge response is SHA256 of client challenge
+        use sha2::{Sha256, Digest};
+        let mut hasher = Sha256::new();
+        hasher.update(knock.challenge);
+        let challenge_response: [u8; 32] = hasher.finalize().into();
+        
+        let ack_payload = KnockAckPayload {
+            session_id,
+            challenge_response,
+            public_key: server_public,
+            version,
+            capabilities: SERVER_CAPABILITIES,
+            limits,
+        };
+        
+        // The ACK already carries the new session ID and negotiated version
+        let ids = PacketIds::new(header.client_id, session_id).with_version(version);
+        Packet::seal(&ack_payload, ids, Direction::ServerToClient, shared_secret)
+            .map(|ack| vec![ack])
+            .map_err(|e| format!("Failed to seal KNOCK_ACK: {}", e))
+    }
 }
 
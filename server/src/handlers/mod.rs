@@ -1,10 +1,197 @@
 //! Packet handlers for different protocol packet types
+//!
+//! Every packet type the server answers has a `PacketHandler` in the
+//! `HandlerRegistry`. Handlers receive the already decrypted payload;
+//! decryption and the session check happen in `handle_packet` so that
+//! failures can be tracked by the rate limiter, and the registry tells the
+//! main loop which rate-limit budget a packet is charged against.
 
 pub mod knock;
 pub mod echo;
 pub mod throughput;
+pub mod download;
+pub mod bufferbloat;
 
-pub use knock::handle_knock;
-pub use echo::handle_echo_request;
-pub use throughput::handle_throughput;
+pub use throughput::ThroughputManager;
+pub use download::DownloadManager;
 
+use crate::audit::KnockAudit;
+use crate::rate_limit::RateClass;
+use crate::session::SessionManager;
+use async_trait::async_trait;
+use bufferbloat::BufferbloatHandler;
+use download::DownloadHandler;
+use echo::EchoHandler;
+use knock::KnockHandler;
+use protocol::{
+    codec::{Encode, Packet, PacketIds},
+    crypto::Direction,
+    packets::{ErrorCode, PacketHeader, PacketType},
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use throughput::ThroughputHandler;
+use tokio::net::UdpSocket;
+
+/// An authenticated packet handed to a handler
+#[derive(Debug, Clone, Copy)]
+pub struct PacketContext<'a> {
+    /// Decrypted payload
+    pub payload: &'a [u8],
+    pub header: &'a PacketHeader,
+    pub client_addr: SocketAddr,
+    /// Key the packet was sealed with, used for the responses as well
+    /// (the session key, or the client's long-term key for KNOCK)
+    pub key: &'a [u8; 32],
+}
+
+/// Handler for one or more packet types
+#[async_trait]
+pub trait PacketHandler: Send + Sync {
+    /// Whether the packet has to belong to a live session of its client
+    fn requires_session(&self) -> bool {
+        true
+    }
+    
+    /// Budget a packet of `packet_type` is charged against
+    fn rate_class(&self, _packet_type: PacketType) -> RateClass {
+        RateClass::Control
+    }
+    
+    /// ERROR code sent back when `handle` fails for a packet of
+    /// `packet_type` (None = drop silently)
+    fn reject_with(&self, _packet_type: PacketType) -> Option<ErrorCode> {
+        Some(ErrorCode::TestRejected)
+    }
+    
+    /// Whether a packet of `packet_type` starts a new test (refused with
+    /// SERVER_OVERLOADED while the server shuts down)
+    fn starts_test(&self, _packet_type: PacketType) -> bool {
+        false
+    }
+    
+    /// Process a packet and return the packets to send back, in order
+    ///
+    /// Long streams (download tests) are sent from a background task by the
+    /// handler itself instead.
+    async fn handle(&self, ctx: PacketContext<'_>) -> Result<Vec<Vec<u8>>, String>;
+}
+
+/// Handlers by the packet type they process
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<PacketType, Arc<dyn PacketHandler>>,
+}
+
+impl HandlerRegistry {
+    /// Registry with the handlers for every test this server offers
+    pub fn new(
+        session_manager: Arc<SessionManager>,
+        throughput_manager: Arc<ThroughputManager>,
+        download_manager: Arc<DownloadManager>,
+        socket: Arc<UdpSocket>,
+        knock_audit: Arc<KnockAudit>,
+        log_echo_requests: Arc<AtomicBool>,
+    ) -> Self {
+        let mut registry = Self::default();
+        registry.register(
+            &[PacketType::Knock],
+            KnockHandler::new(session_manager, download_manager.clone(), knock_audit),
+        );
+        registry.register(&[PacketType::EchoRequest], EchoHandler::new(log_echo_requests));
+        registry.register(
+            &[PacketType::ThroughputStart, PacketType::ThroughputData, PacketType::ThroughputEnd],
+            ThroughputHandler::new(throughput_manager.clone()),
+        );
+        registry.register(
+            &[PacketType::DownloadRequest],
+            DownloadHandler::new(download_manager.clone(), socket.clone()),
+        );
+        registry.register(
+            &[PacketType::BufferbloatStart, PacketType::BufferbloatEnd],
+            BufferbloatHandler::new(throughput_manager, download_manager, socket),
+        );
+        registry
+    }
+    
+    /// Route `packet_types` to `handler`, replacing any earlier handler
+    pub fn register(&mut self, packet_types: &[PacketType], handler: impl PacketHandler + 'static) {
+        let handler: Arc<dyn PacketHandler> = Arc::new(handler);
+        for &packet_type in packet_types {
+            self.handlers.insert(packet_type, handler.clone());
+        }
+    }
+    
+    pub fn get(&self, packet_type: PacketType) -> Option<&dyn PacketHandler> {
+        self.handlers.get(&packet_type).map(|handler| handler.as_ref())
+    }
+    
+    /// Rate-limit budget of a packet type (unhandled types count as control)
+    pub fn rate_class(&self, packet_type: PacketType) -> RateClass {
+        self.get(packet_type)
+            .map_or(RateClass::Control, |handler| handler.rate_class(packet_type))
+    }
+    
+    /// Whether a packet type needs a session (unhandled types do, so they
+    /// are only answered to authenticated clients)
+    pub fn requires_session(&self, packet_type: PacketType) -> bool {
+        self.get(packet_type).is_none_or(|handler| handler.requires_session())
+    }
+}
+
+/// Build an encrypted packet (header + ciphertext) in reply to `request`
+///
+/// Client, session and protocol version are taken from the request.
+pub(crate) fn seal<P: Encode>(
+    payload: &P,
+    request: &PacketHeader,
+    shared_secret: &[u8; 32],
+) -> Result<Vec<u8>, String> {
+    Packet::seal(payload, PacketIds::reply_to(request), Direction::ServerToClient, shared_secret)
+        .map_err(|e| format!("Failed to seal {:?}: {}", P::PACKET_TYPE, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LoggingConfig;
+    use crate::session::Admission;
+    
+    #[tokio::test]
+    async fn test_registry_routes_packet_types() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let logging = LoggingConfig {
+            level: "info".to_string(),
+            log_successful_knocks: false,
+            log_failed_knocks: true,
+            log_echo_requests: false,
+            audit_log: None,
+        };
+        let registry = HandlerRegistry::new(
+            Arc::new(SessionManager::new(60, Admission::default())),
+            Arc::new(ThroughputManager::default()),
+            Arc::new(DownloadManager::new(0, Arc::default(), Arc::default())),
+            socket,
+            Arc::new(KnockAudit::new(&logging, Arc::default()).unwrap()),
+            Arc::default(),
+        );
+        
+        // Only KNOCK works without a session, unhandled types need one too
+        assert!(!registry.requires_session(PacketType::Knock));
+        assert!(registry.requires_session(PacketType::EchoRequest));
+        assert!(registry.get(PacketType::KnockAck).is_none());
+        assert!(registry.requires_session(PacketType::KnockAck));
+        
+        assert_eq!(registry.rate_class(PacketType::ThroughputData), RateClass::Bulk);
+        assert_eq!(registry.rate_class(PacketType::ThroughputEnd), RateClass::Control);
+        assert_eq!(registry.rate_class(PacketType::KnockAck), RateClass::Control);
+        
+        let throughput = registry.get(PacketType::ThroughputData).unwrap();
+        assert_eq!(throughput.reject_with(PacketType::ThroughputData), None);
+        assert_eq!(throughput.reject_with(PacketType::ThroughputEnd), Some(ErrorCode::TestRejected));
+        assert!(throughput.starts_test(PacketType::ThroughputStart));
+        assert!(!throughput.starts_test(PacketType::ThroughputData));
+    }
+}
@@ -1,48 +1,358 @@
-//! Throughput testing handler (upload/download)
+//! Throughput testing handler (upload)
+//!
+//! The client announces a test with THROUGHPUT_START, streams THROUGHPUT_DATA
+//! chunks and finishes with THROUGHPUT_END. The server counts what actually
+//! arrived and answers THROUGHPUT_END with THROUGHPUT_STATS.
 
-use crate::session::SessionManager;
+use super::{seal, PacketContext, PacketHandler};
+use crate::rate_limit::RateClass;
+use async_trait::async_trait;
 use protocol::{
-    crypto,
-    packets::{PacketHeader, ThroughputStartPayload},
+    codec::Decode,
+    packets::{
+        ErrorCode, PacketType, ThroughputDataPayload, ThroughputEndPayload,
+        ThroughputStartPayload, ThroughputStatsPayload,
+    },
 };
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
 use tracing::{debug, info};
 
-/// Handle throughput-related packets
-///
-/// This is a simplified handler for Phase 2
-pub async fn handle_throughput(
-    payload: &[u8],
-    header: &PacketHeader,
-    _client_addr: SocketAddr,
-    shared_secret: &[u8; 32],
-    session_manager: Arc<SessionManager>,
-) -> Result<Option<Vec<u8>>, String> {
-    // Decrypt payload
-    let nonce = header.nonce();
-    let header_bytes = header.to_bytes();
+/// How long a test is kept after its last packet (finished tests keep their
+/// stats so a retransmitted THROUGHPUT_END gets the same answer)
+const TEST_EXPIRY: Duration = Duration::from_secs(120);
+
+/// An unfinished test without data for this long is considered abandoned
+const TEST_IDLE: Duration = Duration::from_secs(5);
+
+/// State of a single upload test
+#[derive(Debug)]
+struct ThroughputTest {
+    /// Size announced in THROUGHPUT_START
+    expected_size: u64,
+    started_at: Instant,
+    last_packet_at: Instant,
+    bytes_received: u64,
+    packets_received: u64,
+    /// Highest sequence number seen so far
+    highest_sequence: Option<u32>,
+    /// Sequence numbers received, to recognize duplicates
+    seen_sequences: HashSet<u32>,
+    /// Missing sequence numbers below `highest_sequence`
+    sequence_gaps: u64,
+    /// Duplicated chunks, not counted as received
+    duplicates: u64,
+    /// Stats computed on THROUGHPUT_END
+    stats: Option<ThroughputStatsPayload>,
+}
+
+impl ThroughputTest {
+    fn new(expected_size: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            expected_size,
+            started_at: now,
+            last_packet_at: now,
+            bytes_received: 0,
+            packets_received: 0,
+            highest_sequence: None,
+            seen_sequences: HashSet::new(),
+            sequence_gaps: 0,
+            duplicates: 0,
+            stats: None,
+        }
+    }
+    
+    /// Unfinished and still receiving data
+    fn is_running(&self, now: Instant) -> bool {
+        self.stats.is_none() && now.duration_since(self.last_packet_at) < TEST_IDLE
+    }
+    
+    /// Account for a received data chunk
+    ///
+    /// Duplicates are ignored, so they neither hide loss nor inflate the
+    /// throughput.
+    fn record(&mut self, sequence: u32, bytes: u64) {
+        self.last_packet_at = Instant::now();
+        
+        if !self.seen_sequences.insert(sequence) {
+            self.duplicates += 1;
+            return;
+        }
+        
+        match self.highest_sequence {
+            Some(highest) if sequence > highest => {
+                self.sequence_gaps += (sequence - highest - 1) as u64;
+                self.highest_sequence = Some(sequence);
+            }
+            Some(_) => {
+                // Missing packet arriving late fills one of the gaps
+                self.sequence_gaps = self.sequence_gaps.saturating_sub(1);
+            }
+            None => {
+                self.sequence_gaps += sequence as u64;
+                self.highest_sequence = Some(sequence);
+            }
+        }
+        
+        self.bytes_received += bytes;
+        self.packets_received += 1;
+    }
+    
+    /// Compute final statistics
+    ///
+    /// Loss is based on bytes so that packets lost at the tail of the test
+    /// (which never show up as a sequence gap) are counted as well.
+    fn finish(&mut self, test_id: u32, total_bytes_sent: u64) -> ThroughputStatsPayload {
+        let duration = self.last_packet_at.duration_since(self.started_at);
+        let duration_ms = duration.as_millis().max(1) as u32;
+        
+        // bits per millisecond == kilobits per second
+        let throughput_kbps = (self.bytes_received * 8 / duration_ms as u64) as u32;
+        
+        let packet_loss_pct = if total_bytes_sent > 0 {
+            let lost = total_bytes_sent.saturating_sub(self.bytes_received);
+            (lost as f64 / total_bytes_sent as f64 * 100.0) as f32
+        } else {
+            0.0
+        };
+        
+        ThroughputStatsPayload {
+            test_id,
+            total_bytes: self.bytes_received,
+            duration_ms,
+            throughput_kbps,
+            packet_loss_pct,
+        }
+    }
+}
+
+/// Tracks running upload tests, keyed by (client_id, test_id)
+#[derive(Default)]
+pub struct ThroughputManager {
+    tests: RwLock<HashMap<(u64, u32), ThroughputTest>>,
+}
+
+impl ThroughputManager {
+    /// Remove tests that have been idle for too long
+    pub async fn cleanup_expired(&self) {
+        let mut tests = self.tests.write().await;
+        let now = Instant::now();
+        tests.retain(|_, test| now.duration_since(test.last_packet_at) < TEST_EXPIRY);
+    }
     
-    let decrypted = crypto::decrypt(payload, shared_secret, &nonce, &header_bytes)
-        .map_err(|e| format!("Throughput decryption failed: {}", e))?;
+    /// Get number of tracked tests
+    pub async fn active_tests(&self) -> usize {
+        self.tests.read().await.len()
+    }
     
-    // For now, just parse THROUGHPUT_START and log it
-    let start = ThroughputStartPayload::from_bytes(&decrypted)
-        .map_err(|e| format!("Invalid throughput start: {}", e))?;
+    /// Get number of unfinished tests that still receive data
+    pub async fn running_tests(&self) -> usize {
+        let now = Instant::now();
+        self.tests
+            .read()
+            .await
+            .values()
+            .filter(|test| test.is_running(now))
+            .count()
+    }
     
-    info!(
-        "Throughput test started: test_id={}, total_size={} bytes",
-        start.test_id, start.total_size
-    );
+    /// Get number of running tests of one client
+    pub async fn running_tests_of(&self, client_id: u64) -> usize {
+        let now = Instant::now();
+        self.tests
+            .read()
+            .await
+            .iter()
+            .filter(|((id, _), test)| *id == client_id && test.is_running(now))
+            .count()
+    }
     
-    // Update session
-    session_manager.update_last_seen(header.client_id).await;
+    /// Start tracking a test (replaces a test with the same ID)
+    pub async fn start_test(&self, client_id: u64, test_id: u32, expected_size: u64) {
+        let mut tests = self.tests.write().await;
+        tests.insert((client_id, test_id), ThroughputTest::new(expected_size));
+    }
     
-    // TODO: Implement full throughput testing in later implementation
-    // For Phase 2 MVP, we're focusing on knock + echo first
+    /// Finish a test and return its stats
+    ///
+    /// Repeated calls return the stats computed by the first one.
+    pub async fn finish_test(
+        &self,
+        client_id: u64,
+        test_id: u32,
+        total_bytes_sent: u64,
+    ) -> Result<ThroughputStatsPayload, String> {
+        let mut tests = self.tests.write().await;
+        let test = tests
+            .get_mut(&(client_id, test_id))
+            .ok_or_else(|| format!("End for unknown throughput test {}", test_id))?;
+        
+        if test.stats.is_none() {
+            let stats = test.finish(test_id, total_bytes_sent);
+            info!(
+                "Throughput test finished: test_id={}, received {}/{} bytes (expected {}) in {}ms, {} kbps, {:.2}% loss, {} packets, {} sequence gaps, {} duplicates",
+                test_id,
+                stats.total_bytes,
+                total_bytes_sent,
+                test.expected_size,
+                stats.duration_ms,
+                stats.throughput_kbps,
+                stats.packet_loss_pct,
+                test.packets_received,
+                test.sequence_gaps,
+                test.duplicates
+            );
+            test.stats = Some(stats);
+        } else {
+            debug!("Repeated THROUGHPUT_END for test_id={}", test_id);
+        }
+        
+        Ok(test.stats.clone().unwrap())
+    }
+}
+
+/// Handler for throughput-related packets (THROUGHPUT_START, _DATA and _END)
+///
+/// Only THROUGHPUT_END produces a response (THROUGHPUT_STATS).
+pub struct ThroughputHandler {
+    manager: Arc<ThroughputManager>,
+}
+
+impl ThroughputHandler {
+    pub fn new(manager: Arc<ThroughputManager>) -> Self {
+        Self { manager }
+    }
+}
+
+#[async_trait]
+impl PacketHandler for ThroughputHandler {
+    fn rate_class(&self, packet_type: PacketType) -> RateClass {
+        match packet_type {
+            PacketType::ThroughputData => RateClass::Bulk,
+            _ => RateClass::Control,
+        }
+    }
+    
+    /// Data chunks are never answered, not even with errors
+    fn reject_with(&self, packet_type: PacketType) -> Option<ErrorCode> {
+        (packet_type != PacketType::ThroughputData).then_some(ErrorCode::TestRejected)
+    }
     
-    debug!("Throughput testing not fully implemented yet");
+    fn starts_test(&self, packet_type: PacketType) -> bool {
+        packet_type == PacketType::ThroughputStart
+    }
     
-    Ok(None) // No response for now
+    async fn handle(&self, ctx: PacketContext<'_>) -> Result<Vec<Vec<u8>>, String> {
+        let PacketContext { payload, header, client_addr, key: shared_secret } = ctx;
+        let throughput_manager = &self.manager;
+        
+        match header.packet_type {
+            PacketType::ThroughputStart => {
+                let start = ThroughputStartPayload::decode(payload)
+                    .map_err(|e| format!("Invalid throughput start: {}", e))?;
+                
+                info!(
+                    "Throughput test started: test_id={}, total_size={} bytes, client_id={} ({})",
+                    start.test_id, start.total_size, header.client_id, client_addr
+                );
+                
+                throughput_manager
+                    .start_test(header.client_id, start.test_id, start.total_size)
+                    .await;
+                
+                Ok(Vec::new())
+            }
+            
+            PacketType::ThroughputData => {
+                let data = ThroughputDataPayload::decode(payload)
+                    .map_err(|e| format!("Invalid throughput data: {}", e))?;
+                
+                let mut tests = throughput_manager.tests.write().await;
+                let test = tests
+                    .get_mut(&(header.client_id, data.test_id))
+                    .ok_or_else(|| format!("Data for unknown throughput test {}", data.test_id))?;
+                
+                if test.stats.is_none() {
+                    test.record(data.sequence, data.data.len() as u64);
+                }
+                
+                Ok(Vec::new())
+            }
+            
+            PacketType::ThroughputEnd => {
+                let end = ThroughputEndPayload::decode(payload)
+                    .map_err(|e| format!("Invalid throughput end: {}", e))?;
+                
+                let stats = throughput_manager
+                    .finish_test(header.client_id, end.test_id, end.total_bytes)
+                    .await?;
+                
+                seal(&stats, header, shared_secret).map(|stats| vec![stats])
+            }
+            
+            other => Err(format!("Unexpected packet type for throughput handler: {:?}", other)),
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_record_counts_gaps_and_reordering() {
+        let mut test = ThroughputTest::new(5000);
+        test.record(0, 1000);
+        test.record(1, 1000);
+        test.record(4, 1000); // 2 and 3 missing
+        assert_eq!(test.sequence_gaps, 2);
+        
+        test.record(2, 1000); // late arrival fills a gap
+        assert_eq!(test.sequence_gaps, 1);
+        assert_eq!(test.bytes_received, 4000);
+        assert_eq!(test.packets_received, 4);
+    }
+    
+    #[test]
+    fn test_record_ignores_duplicates() {
+        let mut test = ThroughputTest::new(3000);
+        test.record(0, 1000);
+        test.record(2, 1000); // 1 missing
+        assert_eq!(test.sequence_gaps, 1);
+        
+        // Duplicates of received chunks must not fill the gap or add bytes
+        test.record(0, 1000);
+        test.record(2, 1000);
+        assert_eq!(test.sequence_gaps, 1);
+        assert_eq!(test.bytes_received, 2000);
+        assert_eq!(test.packets_received, 2);
+        assert_eq!(test.duplicates, 2);
+        
+        test.record(1, 1000);
+        test.record(1, 1000);
+        assert_eq!(test.sequence_gaps, 0);
+        assert_eq!(test.duplicates, 3);
+        
+        let stats = test.finish(1, 3000);
+        assert_eq!(stats.total_bytes, 3000);
+        assert_eq!(stats.packet_loss_pct, 0.0);
+    }
+    
+    #[test]
+    fn test_finish_reports_byte_loss() {
+        let mut test = ThroughputTest::new(4000);
+        test.record(0, 1000);
+        test.record(1, 1000);
+        test.record(2, 1000);
+        
+        // Last chunk lost at the tail - no sequence gap, but bytes are missing
+        let stats = test.finish(7, 4000);
+        assert_eq!(stats.test_id, 7);
+        assert_eq!(stats.total_bytes, 3000);
+        assert!((stats.packet_loss_pct - 25.0).abs() < 0.01);
+        assert!(stats.duration_ms >= 1);
+    }
+}
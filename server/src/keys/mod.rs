@@ -0,0 +1,250 @@
+//! Per-client keys
+//!
+//! The key store is a TOML file with one `[[client]]` entry per client_id
+//! holding its own 32-byte secret, managed with `bufferbane-server keys`.
+//! Revoked keys stay in the file so `keys list` still shows them.
+//!
+//! The legacy `shared_secret` keeps working for client_ids that have no
+//! entry in the store; leave it empty to only accept per-client keys.
+
+use anyhow::{Context, Result};
+use protocol::crypto::{self, KeyLookup, SECRET_SIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// One client's key and its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientKey {
+    pub client_id: u64,
+    /// Human readable owner, e.g. "parents"
+    pub name: String,
+    /// Hex-encoded 32-byte secret
+    pub secret: String,
+    /// Unix timestamp (seconds)
+    pub created: i64,
+    /// Unix timestamp (seconds) after which the key is rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+    /// Unix timestamp (seconds) of revocation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked: Option<i64>,
+}
+
+impl ClientKey {
+    /// Human readable state for `keys list`
+    pub fn status(&self, now: i64) -> &'static str {
+        if self.revoked.is_some() {
+            "revoked"
+        } else if self.expires.is_some_and(|expires| now >= expires) {
+            "expired"
+        } else {
+            "active"
+        }
+    }
+}
+
+/// Contents of the key store file
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KeyStore {
+    #[serde(default, rename = "client")]
+    pub clients: Vec<ClientKey>,
+}
+
+impl KeyStore {
+    /// Load the key store; a missing file is an empty store
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read key store {}", path.display()))?;
+        let store: KeyStore = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse key store {}", path.display()))?;
+        
+        for key in &store.clients {
+            crypto::parse_shared_secret(&key.secret)
+                .map_err(|e| anyhow::anyhow!("Invalid secret for client {}: {}", key.client_id, e))?;
+        }
+        
+        Ok(store)
+    }
+    
+    /// Write the key store, readable by the owner only
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let content = toml::to_string_pretty(self).context("Failed to serialize key store")?;
+        
+        // Write to a temporary file first so a crash can't truncate the store
+        let tmp_path = path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        
+        let mut file = options
+            .open(&tmp_path)
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        std::io::Write::write_all(&mut file, content.as_bytes())
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        drop(file);
+        
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+        Ok(())
+    }
+    
+    /// Generate a key for a new client
+    ///
+    /// A random client_id is picked if none is given. Fails if the client
+    /// already has a key that is not revoked.
+    pub fn add(&mut self, name: &str, client_id: Option<u64>, expires: Option<i64>, now: i64) -> Result<&ClientKey> {
+        let client_id = match client_id {
+            Some(id) => id,
+            None => loop {
+                let id: u64 = rand::random();
+                if !self.clients.iter().any(|key| key.client_id == id) {
+                    break id;
+                }
+            },
+        };
+        
+        if self.clients.iter().any(|key| key.client_id == client_id && key.revoked.is_none()) {
+            anyhow::bail!("Client {} already has a key (revoke it first)", client_id);
+        }
+        
+        self.clients.push(ClientKey {
+            client_id,
+            name: name.to_string(),
+            secret: crypto::format_shared_secret(&crypto::generate_shared_secret()),
+            created: now,
+            expires,
+            revoked: None,
+        });
+        Ok(self.clients.last().unwrap())
+    }
+    
+    /// Revoke the key of a client
+    pub fn revoke(&mut self, client_id: u64, now: i64) -> Result<&ClientKey> {
+        let key = self.clients
+            .iter_mut()
+            .find(|key| key.client_id == client_id && key.revoked.is_none())
+            .with_context(|| format!("Client {} has no active key", client_id))?;
+        key.revoked = Some(now);
+        Ok(key)
+    }
+}
+
+/// Key of a client in the store
+#[derive(Debug, Clone)]
+enum StoredKey {
+    Active {
+        secret: [u8; SECRET_SIZE],
+        expires: Option<i64>,
+    },
+    Revoked,
+}
+
+/// Keys the server accepts packets with
+pub struct ServerKeys {
+    clients: HashMap<u64, StoredKey>,
+    /// Fallback for client_ids without an entry in the store
+    shared_secret: Option<[u8; SECRET_SIZE]>,
+}
+
+impl ServerKeys {
+    pub fn new(store: &KeyStore, shared_secret: Option<[u8; SECRET_SIZE]>) -> Result<Self> {
+        let mut clients = HashMap::new();
+        for key in &store.clients {
+            let stored = if key.revoked.is_some() {
+                StoredKey::Revoked
+            } else {
+                let secret = crypto::parse_shared_secret(&key.secret)
+                    .map_err(|e| anyhow::anyhow!("Invalid secret for client {}: {}", key.client_id, e))?;
+                StoredKey::Active {
+                    secret,
+                    expires: key.expires,
+                }
+            };
+            
+            // An active key wins over older revoked ones of the same client
+            if !matches!(clients.get(&key.client_id), Some(StoredKey::Active { .. })) {
+                clients.insert(key.client_id, stored);
+            }
+        }
+        
+        Ok(Self { clients, shared_secret })
+    }
+    
+    /// Number of clients with an active key
+    pub fn client_keys(&self) -> usize {
+        self.clients
+            .values()
+            .filter(|key| matches!(key, StoredKey::Active { .. }))
+            .count()
+    }
+    
+    fn lookup_at(&self, client_id: u64, now: i64) -> Option<[u8; SECRET_SIZE]> {
+        match self.clients.get(&client_id) {
+            Some(StoredKey::Active { secret, expires }) => {
+                expires.is_none_or(|expires| now < expires).then_some(*secret)
+            }
+            Some(StoredKey::Revoked) => None,
+            None => self.shared_secret,
+        }
+    }
+}
+
+impl KeyLookup for ServerKeys {
+    fn lookup(&self, client_id: u64) -> Option<[u8; SECRET_SIZE]> {
+        self.lookup_at(client_id, chrono::Utc::now().timestamp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_lookup_honors_revocation_and_expiry() {
+        let shared = crypto::generate_shared_secret();
+        let mut store = KeyStore::default();
+        store.add("active", Some(1), None, 100).unwrap();
+        store.add("expiring", Some(2), Some(200), 100).unwrap();
+        store.add("revoked", Some(3), None, 100).unwrap();
+        store.revoke(3, 150).unwrap();
+        
+        let keys = ServerKeys::new(&store, Some(shared)).unwrap();
+        let secret1 = crypto::parse_shared_secret(&store.clients[0].secret).unwrap();
+        
+        assert_eq!(keys.lookup_at(1, 300), Some(secret1));
+        assert!(keys.lookup_at(2, 150).is_some());
+        assert_eq!(keys.lookup_at(2, 200), None);
+        
+        // Revoked clients don't fall back to the shared secret
+        assert_eq!(keys.lookup_at(3, 150), None);
+        assert_eq!(keys.lookup_at(4, 150), Some(shared));
+        
+        assert_eq!(keys.client_keys(), 2);
+    }
+    
+    #[test]
+    fn test_add_rejects_duplicate_client() {
+        let mut store = KeyStore::default();
+        store.add("first", Some(1), None, 100).unwrap();
+        assert!(store.add("second", Some(1), None, 100).is_err());
+        
+        // After revoking, the client can get a new key
+        store.revoke(1, 150).unwrap();
+        store.add("second", Some(1), None, 200).unwrap();
+        
+        let keys = ServerKeys::new(&store, None).unwrap();
+        let secret = crypto::parse_shared_secret(&store.clients[1].secret).unwrap();
+        assert_eq!(keys.lookup_at(1, 300), Some(secret));
+    }
+}
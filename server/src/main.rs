@@ -1,20 +1,42 @@
 //! Bufferbane Server - Network quality monitoring server
 
+mod audit;
 mod config;
 mod handlers;
+mod keys;
+mod metrics;
+mod port_knock;
+mod quota;
+mod rate_limit;
+mod replay;
 mod session;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use audit::KnockAudit;
+use clap::{Parser, Subcommand};
+use handlers::{DownloadManager, HandlerRegistry, PacketContext, ThroughputManager};
+use keys::{KeyStore, ServerKeys};
+use metrics::{Metrics, MetricsSources};
+use port_knock::PortKnockGuard;
+use quota::{QuotaExceeded, Quotas};
 use protocol::{
-    crypto,
-    packets::{PacketHeader, PacketType},
+    codec::Packet,
+    crypto::{self, Direction, KeyLookup},
+    packets::{ErrorCode, ErrorPayload, PacketHeader, PacketType},
 };
-use session::SessionManager;
+use rate_limit::RateLimiter;
+use replay::ReplayGuard;
+use session::{Admission, SessionManager};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tracing::{debug, error, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, trace, warn};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Registry};
 
 #[derive(Parser, Debug)]
 #[command(author = "Florian Schüller <schuellerf@gmail.com>")]
@@ -22,191 +44,1049 @@ use tracing::{debug, error, info, warn};
 #[command(about = "Bufferbane server - network quality monitoring", long_about = None)]
 struct Args {
     /// Configuration file path
-    #[arg(short, long, default_value = "server.conf")]
+    #[arg(short, long, default_value = "server.conf", global = true)]
     config: String,
+    
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage per-client keys (key_file in [security])
+    Keys {
+        #[command(subcommand)]
+        action: KeysAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysAction {
+    /// Generate a key for a client
+    Add {
+        /// Who the key is for
+        #[arg(long)]
+        name: String,
+        
+        /// Client ID (random if not given)
+        #[arg(long)]
+        client_id: Option<u64>,
+        
+        /// Days until the key expires (never if not given)
+        #[arg(long)]
+        expires_days: Option<u64>,
+    },
+    
+    /// Revoke a client's key
+    Revoke {
+        /// Client ID
+        client_id: u64,
+    },
+    
+    /// List all client keys
+    List,
+}
+
+/// How often a shutdown checks whether running tests are done
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// State shared by the main loop and the packet tasks
+struct ServerState {
+    socket: Arc<UdpSocket>,
+    /// Replaced on config reload, which ends the sessions of clients left without a key
+    keys: RwLock<Arc<ServerKeys>>,
+    registry: HandlerRegistry,
+    session_manager: Arc<SessionManager>,
+    throughput_manager: Arc<ThroughputManager>,
+    download_manager: Arc<DownloadManager>,
+    rate_limiter: Arc<RateLimiter>,
+    replay_guard: Arc<ReplayGuard>,
+    port_knock: Option<Arc<PortKnockGuard>>,
+    quotas: Arc<Quotas>,
+    metrics: Arc<Metrics>,
+    knock_audit: Arc<KnockAudit>,
+    log_echo_requests: Arc<AtomicBool>,
+    /// Set on SIGTERM; new tests are refused from then on
+    shutting_down: AtomicBool,
+}
+
+impl ServerState {
+    fn keys(&self) -> Arc<ServerKeys> {
+        self.keys.read().unwrap().clone()
+    }
 }
 
+/// Filter for the log output (RUST_LOG takes precedence over the configured level)
+type LogFilter = reload::Handle<EnvFilter, Registry>;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments
     let args = Args::parse();
     
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(tracing::Level::INFO.into()),
-        )
-        .init();
-    
-    info!("Starting Bufferbane server v{}", env!("CARGO_PKG_VERSION"));
+    if let Some(Command::Keys { action }) = args.command {
+        return run_keys_command(&args.config, action);
+    }
     
     // Load configuration
-    let config = config::Config::load(&args.config)
+    let mut config = config::Config::load(&args.config)
         .context("Failed to load configuration")?;
     
-    // Parse shared secret
-    let shared_secret = crypto::parse_shared_secret(&config.security.shared_secret)
-        .map_err(|e| anyhow::anyhow!("Invalid shared secret in configuration: {}", e))?;
+    // Initialize logging; the level can be changed on reload
+    let (filter, log_filter) = reload::Layer::new(log_level_filter(&config.logging.level));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
     
+    info!("Starting Bufferbane server v{}", env!("CARGO_PKG_VERSION"));
     info!(
         "Loaded configuration from: {}",
         args.config
     );
     
+    // Load per-client keys and the shared secret
+    let keys = load_keys(&config)?;
+    if let Some(path) = &config.security.key_file {
+        info!("Loaded {} client keys from {}", keys.client_keys(), path);
+    }
+    if !config.security.shared_secret.is_empty() {
+        info!("Shared secret accepted for clients without their own key");
+    }
+    
     // Create session manager
     let session_manager = Arc::new(SessionManager::new(
         config.security.session_timeout_sec,
+        admission(&config),
+    ));
+    
+    // Track per-client quota usage
+    let quotas = Arc::new(Quotas::new(config.quotas.clone()));
+    
+    // Counters for the /metrics endpoint (kept even if it is disabled)
+    let metrics = Arc::new(Metrics::default());
+    
+    // Report KNOCK outcomes (log, metrics and audit file)
+    let knock_audit = Arc::new(KnockAudit::new(&config.logging, metrics.clone())?);
+    if let Some(path) = &config.logging.audit_log {
+        info!("Writing knock audit log to {}", path);
+    }
+    let log_echo_requests = Arc::new(AtomicBool::new(config.logging.log_echo_requests));
+    
+    // Create throughput test tracker
+    let throughput_manager = Arc::new(ThroughputManager::default());
+    
+    // Create download test tracker (server-sent streams obey the bandwidth limit)
+    let download_manager = Arc::new(DownloadManager::new(max_download_kbps(&config), metrics.clone(), quotas.clone()));
+    
+    // Create replay guard
+    let replay_guard = Arc::new(ReplayGuard::new(Duration::from_secs(
+        config.security.replay_window_sec,
+    )));
+    
+    // Create rate limiter
+    let rate_limiter = Arc::new(RateLimiter::new(
+        config.security.enable_rate_limiting,
+        config.rate_limiting.clone(),
     ));
     
+    // Create knock sequence tracker
+    let port_knock = config.security.port_knocking.then(|| {
+        Arc::new(PortKnockGuard::new(
+            config.security.knock_ports.clone(),
+            Duration::from_secs(config.security.knock_timeout_sec),
+        ))
+    });
+    
     // Bind UDP socket
-    let bind_addr = format!("{}:{}", config.general.bind_address, config.general.bind_port);
+    let bind_addr = config.general.bind_addr();
     let socket = Arc::new(
-        UdpSocket::bind(&bind_addr)
-            .await
+        bind_udp(bind_addr)
             .context(format!("Failed to bind to {}", bind_addr))?
     );
     
-    info!("Server listening on {}", bind_addr);
-    info!("Max concurrent clients: {}", config.general.max_concurrent_clients);
+    if bind_addr.ip().is_unspecified() && bind_addr.is_ipv6() {
+        info!("Server listening on {} (IPv6 and IPv4)", bind_addr);
+    } else {
+        info!("Server listening on {}", bind_addr);
+    }
+    
+    // Route packet types to their handlers
+    let registry = HandlerRegistry::new(
+        session_manager.clone(),
+        throughput_manager.clone(),
+        download_manager.clone(),
+        socket.clone(),
+        knock_audit.clone(),
+        log_echo_requests.clone(),
+    );
+    
+    // Bind knock ports; KNOCKs are only answered after the sequence
+    if let Some(guard) = &port_knock {
+        for &port in guard.ports() {
+            let knock_addr = config.general.socket_addr(port);
+            let knock_socket = bind_udp(knock_addr)
+                .context(format!("Failed to bind knock port {}", knock_addr))?;
+            tokio::spawn(listen_knock_port(knock_socket, port, guard.clone()));
+        }
+        info!(
+            "Port knocking: sequence {:?} within {} seconds",
+            guard.ports(),
+            config.security.knock_timeout_sec
+        );
+    }
+    
+    // Serve metrics and health checks over HTTP
+    if config.monitoring.enabled {
+        let monitoring_addr = config.monitoring.bind_addr();
+        let listener = TcpListener::bind(&monitoring_addr)
+            .await
+            .context(format!("Failed to bind monitoring endpoint {}", monitoring_addr))?;
+        let sources = Arc::new(MetricsSources {
+            metrics: metrics.clone(),
+            session_manager: session_manager.clone(),
+            download_manager: download_manager.clone(),
+            rate_limiter: rate_limiter.clone(),
+            replay_guard: replay_guard.clone(),
+            port_knock: port_knock.clone(),
+            quotas: quotas.clone(),
+        });
+        tokio::spawn(metrics::serve(listener, sources));
+        info!("Metrics on http://{}/metrics", monitoring_addr);
+    }
+    
+    info!(
+        "Max concurrent clients: {} ({:?} when full)",
+        config.general.max_concurrent_clients, config.general.admission_policy
+    );
+    log_quotas(&config);
     info!("Session timeout: {} seconds", config.security.session_timeout_sec);
+    info!("Replay window: {} seconds", config.security.replay_window_sec);
+    log_rate_limits(&config);
     
     // Spawn cleanup task
     let cleanup_session_manager = session_manager.clone();
+    let cleanup_throughput_manager = throughput_manager.clone();
+    let cleanup_download_manager = download_manager.clone();
+    let cleanup_rate_limiter = rate_limiter.clone();
+    let cleanup_replay_guard = replay_guard.clone();
+    let cleanup_port_knock = port_knock.clone();
+    let cleanup_quotas = quotas.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+        let mut last_dropped = 0;
+        let mut last_failures = 0;
+        let mut last_replays = 0;
+        let mut last_knock_drops = 0;
         loop {
             interval.tick().await;
             cleanup_session_manager.cleanup_expired().await;
+            cleanup_throughput_manager.cleanup_expired().await;
+            cleanup_rate_limiter.cleanup_expired();
+            cleanup_quotas.cleanup_expired();
+            cleanup_replay_guard.cleanup_expired();
+            if let Some(guard) = &cleanup_port_knock {
+                guard.cleanup_expired();
+            }
             let active = cleanup_session_manager.active_sessions().await;
             if active > 0 {
                 debug!("Active sessions: {}", active);
             }
+            let tests = cleanup_throughput_manager.active_tests().await;
+            if tests > 0 {
+                debug!("Tracked throughput tests: {}", tests);
+            }
+            let downloads = cleanup_download_manager.active_downloads().await;
+            if downloads > 0 {
+                debug!("Running download tests: {}", downloads);
+            }
+            
+            // Report drops since the last round
+            let stats = cleanup_rate_limiter.stats();
+            let blocked = stats.dropped_blocked.load(Ordering::Relaxed);
+            let ip_rate = stats.dropped_ip_rate.load(Ordering::Relaxed);
+            let client_rate = stats.dropped_client_rate.load(Ordering::Relaxed);
+            let failures = stats.decrypt_failures.load(Ordering::Relaxed);
+            let dropped = blocked + ip_rate + client_rate;
+            if dropped > last_dropped || failures > last_failures {
+                info!(
+                    "Rate limiting: {} packets dropped ({} from blocked IPs, {} over IP limit, {} over client limit), {} decryption failures, {} IPs blocked ({} currently)",
+                    dropped,
+                    blocked,
+                    ip_rate,
+                    client_rate,
+                    failures,
+                    stats.ips_blocked.load(Ordering::Relaxed),
+                    cleanup_rate_limiter.blocked_ips()
+                );
+                last_dropped = dropped;
+                last_failures = failures;
+            }
+            
+            let replay_stats = cleanup_replay_guard.stats();
+            let stale = replay_stats.rejected_stale.load(Ordering::Relaxed);
+            let duplicate = replay_stats.rejected_duplicate.load(Ordering::Relaxed);
+            if stale + duplicate > last_replays {
+                warn!(
+                    "Replay protection: {} packets rejected ({} outside clock window, {} duplicate nonces)",
+                    stale + duplicate,
+                    stale,
+                    duplicate
+                );
+                last_replays = stale + duplicate;
+            }
+            
+            if let Some(guard) = &cleanup_port_knock {
+                let knock_stats = guard.stats();
+                let dropped = knock_stats.dropped_knocks.load(Ordering::Relaxed);
+                if dropped > last_knock_drops {
+                    info!(
+                        "Port knocking: {} KNOCKs dropped without knock sequence, {} sequences completed",
+                        dropped,
+                        knock_stats.sequences_completed.load(Ordering::Relaxed)
+                    );
+                    last_knock_drops = dropped;
+                }
+            }
         }
     });
     
+    let state = Arc::new(ServerState {
+        socket,
+        keys: RwLock::new(Arc::new(keys)),
+        registry,
+        session_manager,
+        throughput_manager,
+        download_manager,
+        rate_limiter,
+        replay_guard,
+        port_knock,
+        quotas,
+        metrics,
+        knock_audit,
+        log_echo_requests,
+        shutting_down: AtomicBool::new(false),
+    });
+    
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to install SIGTERM handler")?;
+    let mut interrupt = signal(SignalKind::interrupt()).context("Failed to install SIGINT handler")?;
+    
     // Main server loop
     let mut buf = vec![0u8; 65535]; // Max UDP packet size
+    let mut drain_deadline = None;
+    let mut drain_check = tokio::time::interval(DRAIN_CHECK_INTERVAL);
     
+    loop {
+        tokio::select! {
+            received = state.socket.recv_from(&mut buf) => match received {
+                Ok((len, client_addr)) => dispatch(&state, &buf[..len], client_addr),
+                Err(e) => {
+                    error!("Error receiving packet: {}", e);
+                }
+            },
+            
+            _ = hangup.recv() => {
+                reload_config(&args.config, &mut config, &state, &log_filter).await;
+            }
+            
+            // The first SIGTERM (or Ctrl-C) lets running tests finish, the
+            // second exits right away
+            _ = async { tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            } } => {
+                if drain_deadline.is_some() {
+                    warn!("Second stop signal, exiting without waiting for tests");
+                    break;
+                }
+                let grace = Duration::from_secs(config.general.shutdown_grace_sec);
+                begin_shutdown(&state, grace).await;
+                drain_deadline = Some(Instant::now() + grace);
+            }
+            
+            _ = drain_check.tick(), if drain_deadline.is_some() => {
+                let uploads = state.throughput_manager.running_tests().await;
+                let downloads = state.download_manager.active_downloads().await;
+                if uploads + downloads == 0 {
+                    info!("All tests finished");
+                    break;
+                }
+                if drain_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    warn!(
+                        "Shutdown grace period over, aborting {} upload and {} download tests",
+                        uploads, downloads
+                    );
+                    break;
+                }
+            }
+        }
+    }
+    
+    log_final_stats(&state).await;
+    info!("Server stopped");
+    Ok(())
+}
+
+/// Check a received datagram and spawn a task to answer it
+///
+/// Parses the header and applies port knocking and rate limits before
+/// spending a task (or any decryption work) on the packet.
+fn dispatch(state: &Arc<ServerState>, data: &[u8], client_addr: SocketAddr) {
+    let header = match PacketHeader::from_bytes(data) {
+        Ok(h) => h,
+        Err(e) => {
+            debug!("Invalid packet header from {}: {}", client_addr, e);
+            state.metrics.record_invalid(data.len());
+            return; // Silent drop
+        }
+    };
+    state.metrics.record_received(header.packet_type, data.len());
+    
+    if header.packet_type == PacketType::Knock
+        && let Some(guard) = &state.port_knock
+        && !guard.check(client_addr.ip())
+    {
+        trace!("Dropped KNOCK from {}: knock sequence not completed", client_addr);
+        return;
+    }
+    
+    let class = state.registry.rate_class(header.packet_type);
+    if let Err(reason) = state.rate_limiter.check(client_addr.ip(), header.client_id, class, data.len()) {
+        // The client_id isn't authenticated yet, so nobody is told; clients
+        // learn about their own limit when handle_packet charges them
+        trace!("Dropped {:?} from {}: {:?}", header.packet_type, client_addr, reason);
+        return;
+    }
+    
+    let data = data.to_vec();
+    let state = state.clone();
+    
+    // Spawn task to handle packet
+    tokio::spawn(async move {
+        let responses = handle_packet(&data, header, client_addr, &state).await;
+        for response in responses {
+            if !send_response(&state, &response, client_addr).await {
+                break;
+            }
+        }
+    });
+}
+
+/// Send a packet to a client, returns false if that failed
+async fn send_response(state: &ServerState, response: &[u8], client_addr: SocketAddr) -> bool {
+    match state.socket.send_to(response, client_addr).await {
+        Ok(len) => {
+            state.metrics.record_sent(len);
+            true
+        }
+        Err(e) => {
+            error!("Failed to send response to {}: {}", client_addr, e);
+            false
+        }
+    }
+}
+
+/// Re-read the config file and apply what can change at runtime (SIGHUP)
+///
+/// Keys, rate limits and logging are replaced; sessions and running tests
+/// are kept unless the client's key is gone. If the new config doesn't load,
+/// the old one stays in effect.
+async fn reload_config(path: &str, current: &mut config::Config, state: &ServerState, log_filter: &LogFilter) {
+    info!("Reloading configuration from {}", path);
+    
+    let mut new = match config::Config::load(path) {
+        Ok(new) => new,
+        Err(e) => {
+            error!("Keeping the current configuration: {:#}", e);
+            return;
+        }
+    };
+    let keys = match load_keys(&new) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Keeping the current configuration: {:#}", e);
+            return;
+        }
+    };
+    if let Err(e) = state.knock_audit.reconfigure(&new.logging) {
+        error!("Keeping the current configuration: {:#}", e);
+        return;
+    }
+    
+    if let Err(e) = log_filter.reload(log_level_filter(&new.logging.level)) {
+        error!("Failed to change the log level: {}", e);
+    }
+    state.log_echo_requests.store(new.logging.log_echo_requests, Ordering::Relaxed);
+    
+    if new.security.key_file.is_some() {
+        info!("Loaded {} client keys", keys.client_keys());
+    }
+    *state.keys.write().unwrap() = Arc::new(keys);
+    
+    // Sessions of revoked, expired or removed keys end right away
+    let keys = state.keys();
+    for client_id in state.session_manager.retain_clients(|client_id| keys.lookup(client_id).is_some()).await {
+        state.download_manager.stop(client_id).await;
+        info!("Dropped the sessions of client {}, its key is no longer valid", client_id);
+    }
+    
+    state.rate_limiter.reconfigure(new.security.enable_rate_limiting, new.rate_limiting.clone());
+    state.download_manager.set_max_rate_kbps(max_download_kbps(&new));
+    log_rate_limits(&new);
+    
+    state.session_manager.set_admission(admission(&new));
+    state.quotas.reconfigure(new.quotas.clone());
+    log_quotas(&new);
+    
+    // Sockets and long-lived trackers are only set up at startup
+    let restart_needed = [
+        (
+            "bind_address/bind_port",
+            current.general.bind_address != new.general.bind_address
+                || current.general.bind_port != new.general.bind_port,
+        ),
+        ("[monitoring]", current.monitoring != new.monitoring),
+        (
+            "port knocking",
+            current.security.port_knocking != new.security.port_knocking
+                || current.security.knock_ports != new.security.knock_ports
+                || current.security.knock_timeout_sec != new.security.knock_timeout_sec,
+        ),
+        ("session_timeout_sec", current.security.session_timeout_sec != new.security.session_timeout_sec),
+        ("replay_window_sec", current.security.replay_window_sec != new.security.replay_window_sec),
+        ("shutdown_grace_sec", current.general.shutdown_grace_sec != new.general.shutdown_grace_sec),
+    ];
+    for (setting, _) in restart_needed.iter().filter(|(_, changed)| *changed) {
+        warn!("Changes to {} need a restart, keeping the old values", setting);
+    }
+    
+    // The running values stay in effect, and later reloads compare to them
+    new.general.bind_address = current.general.bind_address.clone();
+    new.general.bind_port = current.general.bind_port;
+    new.general.shutdown_grace_sec = current.general.shutdown_grace_sec;
+    new.monitoring = current.monitoring.clone();
+    new.security.port_knocking = current.security.port_knocking;
+    new.security.knock_ports = current.security.knock_ports.clone();
+    new.security.knock_timeout_sec = current.security.knock_timeout_sec;
+    new.security.session_timeout_sec = current.security.session_timeout_sec;
+    new.security.replay_window_sec = current.security.replay_window_sec;
+    
+    *current = new;
+    info!("Configuration reloaded");
+}
+
+/// Refuse new tests and stop running download streams (SIGTERM)
+///
+/// Upload tests are left to finish; the main loop keeps serving them until
+/// they are done or the grace period is over.
+async fn begin_shutdown(state: &ServerState, grace: Duration) {
+    state.shutting_down.store(true, Ordering::Relaxed);
+    
+    let downloads = state.download_manager.stop_all().await;
+    let uploads = state.throughput_manager.running_tests().await;
+    info!(
+        "Shutting down: refusing new tests, stopped {} download streams, waiting up to {}s for {} upload tests",
+        downloads,
+        grace.as_secs(),
+        uploads
+    );
+}
+
+/// Log what the server did, since sessions and counters are lost on exit
+async fn log_final_stats(state: &ServerState) {
+    let now = Instant::now();
+    for session in state.session_manager.sessions().await {
+        info!(
+            "Session {} (client {}, {}): {} packets, {} bytes received, {} bytes sent in {}s, {} bytes of daily quota used",
+            session.session_id,
+            session.client_id,
+            session.client_addr,
+            session.packets_received,
+            session.bytes_received,
+            session.bytes_sent,
+            now.duration_since(session.authenticated_at).as_secs(),
+            state.quotas.bytes_today(session.client_id)
+        );
+    }
+    
+    let (knocks_accepted, knocks_rejected) = state.metrics.knocks();
+    let rate_stats = state.rate_limiter.stats();
+    let replay_stats = state.replay_guard.stats();
+    info!(
+        "Totals: {} packets, {} bytes received, {} bytes sent, {} KNOCKs accepted, {} rejected, {} decryption failures, {} packets rate limited, {} replays rejected",
+        state.metrics.packets_received(),
+        state.metrics.bytes_received(),
+        state.metrics.bytes_sent(),
+        knocks_accepted,
+        knocks_rejected,
+        rate_stats.decrypt_failures.load(Ordering::Relaxed),
+        rate_stats.dropped_blocked.load(Ordering::Relaxed)
+            + rate_stats.dropped_ip_rate.load(Ordering::Relaxed)
+            + rate_stats.dropped_client_rate.load(Ordering::Relaxed),
+        replay_stats.rejected_stale.load(Ordering::Relaxed)
+            + replay_stats.rejected_duplicate.load(Ordering::Relaxed)
+    );
+}
+
+/// Load the per-client keys and the shared secret of a config
+fn load_keys(config: &config::Config) -> Result<ServerKeys> {
+    let shared_secret = if config.security.shared_secret.is_empty() {
+        None
+    } else {
+        Some(
+            crypto::parse_shared_secret(&config.security.shared_secret)
+                .map_err(|e| anyhow::anyhow!("Invalid shared secret in configuration: {}", e))?,
+        )
+    };
+    
+    let key_store = match &config.security.key_file {
+        Some(path) => KeyStore::load(path).context("Failed to load key store")?,
+        None => KeyStore::default(),
+    };
+    ServerKeys::new(&key_store, shared_secret)
+}
+
+/// Rate cap for server-sent streams in kbps (0 = unlimited)
+fn max_download_kbps(config: &config::Config) -> u32 {
+    if config.security.enable_rate_limiting {
+        (config.rate_limiting.max_bandwidth_mbps * 1000) as u32
+    } else {
+        0
+    }
+}
+
+/// Session admission settings of a config
+fn admission(config: &config::Config) -> Admission {
+    Admission {
+        max_clients: config.general.max_concurrent_clients,
+        policy: config.general.admission_policy,
+    }
+}
+
+fn log_quotas(config: &config::Config) {
+    let quotas = &config.quotas;
+    info!(
+        "Quotas per client: {} concurrent tests, {} MB per day (0 = unlimited)",
+        quotas.max_tests_per_client, quotas.daily_mb_per_client
+    );
+}
+
+fn log_rate_limits(config: &config::Config) {
+    if config.security.enable_rate_limiting {
+        info!(
+            "Rate limiting: {} packets/s (burst {}), {} Mbps per IP and client",
+            config.rate_limiting.max_packets_per_second,
+            config.rate_limiting.burst_size,
+            config.rate_limiting.max_bandwidth_mbps
+        );
+    } else {
+        warn!("Rate limiting disabled");
+    }
+}
+
+/// Log filter for the configured level, unless RUST_LOG is set
+fn log_level_filter(level: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level))
+}
+
+/// Bind a UDP socket for the service or a knock port
+///
+/// An IPv6 socket also accepts IPv4 (as IPv4-mapped addresses) whatever
+/// net.ipv6.bindv6only says, so "::" listens dual-stack.
+fn bind_udp(addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Track datagrams arriving on one knock port
+///
+/// Nothing is ever sent back, the contents are ignored.
+async fn listen_knock_port(socket: UdpSocket, port: u16, guard: Arc<PortKnockGuard>) {
+    let mut buf = [0u8; 64];
     loop {
         match socket.recv_from(&mut buf).await {
-            Ok((len, client_addr)) => {
-                let data = buf[..len].to_vec();
-                let socket_clone = socket.clone();
-                let session_manager_clone = session_manager.clone();
-                let shared_secret_clone = shared_secret;
-                
-                // Spawn task to handle packet
-                tokio::spawn(async move {
-                    if let Some(response) = handle_packet(
-                        &data,
-                        client_addr,
-                        shared_secret_clone,
-                        session_manager_clone,
-                    )
-                    .await
-                    {
-                        if let Err(e) = socket_clone.send_to(&response, client_addr).await {
-                            error!("Failed to send response to {}: {}", client_addr, e);
-                        }
-                    }
-                });
+            Ok((_, addr)) => {
+                if guard.record_knock(addr.ip(), port) {
+                    debug!("{} completed the knock sequence", addr.ip());
+                }
             }
             Err(e) => {
-                error!("Error receiving packet: {}", e);
+                error!("Error receiving on knock port {}: {}", port, e);
             }
         }
     }
 }
 
-/// Handle a received packet
+/// Handle a received packet and return the packets to send back
+///
+/// The header has already been parsed and rate limited by the main loop.
 async fn handle_packet(
     data: &[u8],
+    header: PacketHeader,
     client_addr: SocketAddr,
-    shared_secret: [u8; 32],
-    session_manager: Arc<SessionManager>,
-) -> Option<Vec<u8>> {
-    // Parse packet header
-    let header = match PacketHeader::from_bytes(data) {
-        Ok(h) => h,
+    state: &ServerState,
+) -> Vec<Vec<u8>> {
+    let is_knock = header.packet_type == PacketType::Knock;
+    let (shared_secret, payload) = match open_packet(data, &header, client_addr, state).await {
+        Ok(opened) => opened,
+        Err(reason) => {
+            if is_knock {
+                state.knock_audit.rejected(client_addr, header.client_id, &reason);
+            }
+            return Vec::new();
+        }
+    };
+    let session_manager = &state.session_manager;
+    
+    // Build an ERROR reply; the peer proved it has the key, so it may learn
+    // why its request failed
+    let error_reply = |error: ErrorPayload| {
+        handlers::seal(&error, &header, &shared_secret)
+            .map(|packet| vec![packet])
+            .unwrap_or_else(|e| {
+                warn!("Failed to build ERROR for {}: {}", client_addr, e);
+                Vec::new()
+            })
+    };
+    
+    // The client_id can only be charged now that the packet is authenticated
+    let class = state.registry.rate_class(header.packet_type);
+    if let Err(reason) = state.rate_limiter.charge_client(header.client_id, class, data.len()) {
+        trace!("Dropped {:?} from {}: {:?}", header.packet_type, client_addr, reason);
+        if state.registry.requires_session(header.packet_type) && state.rate_limiter.notice_due(header.client_id) {
+            return error_reply(
+                ErrorPayload::new(ErrorCode::RateLimited, header.packet_type)
+                    .with_retry_after(rate_limit::NOTICE_INTERVAL),
+            );
+        }
+        return Vec::new();
+    }
+    
+    // Everything but KNOCK has to carry a live session of this client
+    if state.registry.requires_session(header.packet_type) {
+        if !session_manager.is_valid(header.session_id, header.client_id).await {
+            debug!(
+                "{:?} from {} with invalid session {}",
+                header.packet_type, client_addr, header.session_id
+            );
+            return error_reply(ErrorPayload::new(ErrorCode::InvalidSession, header.packet_type));
+        }
+        
+        session_manager.update_last_seen(header.session_id).await;
+    }
+    
+    let Some(handler) = state.registry.get(header.packet_type) else {
+        debug!("Unsupported packet type: {:?}", header.packet_type);
+        return error_reply(ErrorPayload::new(ErrorCode::UnsupportedRequest, header.packet_type));
+    };
+    
+    if handler.starts_test(header.packet_type) {
+        if state.shutting_down.load(Ordering::Relaxed) {
+            debug!("Refusing {:?} from {}: shutting down", header.packet_type, client_addr);
+            return error_reply(ErrorPayload::new(ErrorCode::ServerOverloaded, header.packet_type));
+        }
+        
+        let running = state.throughput_manager.running_tests_of(header.client_id).await
+            + state.download_manager.is_running(header.client_id).await as usize;
+        if let Err(exceeded) = state.quotas.check_new_test(header.client_id, running) {
+            debug!(
+                "Refusing {:?} from client {} ({}): quota exceeded: {:?}",
+                header.packet_type, header.client_id, client_addr, exceeded
+            );
+            let error = ErrorPayload::new(ErrorCode::ServerOverloaded, header.packet_type);
+            return error_reply(match exceeded {
+                QuotaExceeded::Bytes(until_reset) => error.with_retry_after(until_reset),
+                QuotaExceeded::Tests(_) => error,
+            });
+        }
+    }
+    
+    let ctx = PacketContext {
+        payload: &payload,
+        header: &header,
+        client_addr,
+        key: &shared_secret,
+    };
+    let started = Instant::now();
+    let result = handler.handle(ctx).await;
+    state.metrics.record_handler(header.packet_type, started.elapsed(), result.is_ok());
+    
+    let responses = match result {
+        Ok(responses) => responses,
         Err(e) => {
-            debug!("Invalid packet header from {}: {}", client_addr, e);
-            return None; // Silent drop
+            if is_knock {
+                state.knock_audit.rejected(client_addr, header.client_id, &e);
+            } else {
+                debug!("{:?} failed from {}: {}", header.packet_type, client_addr, e);
+            }
+            match handler.reject_with(header.packet_type) {
+                Some(code) => error_reply(ErrorPayload::new(code, header.packet_type)),
+                None => Vec::new(),
+            }
         }
     };
     
+    if handler.requires_session() {
+        let sent = responses.iter().map(|response| response.len() as u64).sum();
+        session_manager.update_stats(header.session_id, data.len() as u64, sent).await;
+        state.quotas.record_bytes(header.client_id, data.len() as u64 + sent);
+    }
+    
+    responses
+}
+
+/// Authenticate and decrypt a received packet
+///
+/// Returns the key the packet was sealed with and the plaintext payload, or
+/// why the packet has to be dropped silently.
+async fn open_packet(
+    data: &[u8],
+    header: &PacketHeader,
+    client_addr: SocketAddr,
+    state: &ServerState,
+) -> Result<([u8; 32], Vec<u8>), String> {
+    let rate_limiter = &state.rate_limiter;
     // Check payload length
     if data.len() < PacketHeader::SIZE + header.payload_len as usize {
         debug!("Incomplete packet from {}", client_addr);
-        return None; // Silent drop
-    }
-    
-    let payload = &data[PacketHeader::SIZE..PacketHeader::SIZE + header.payload_len as usize];
-    
-    // Dispatch based on packet type
-    match header.packet_type {
-        PacketType::Knock => {
-            // KNOCK always allowed (authentication)
-            match handlers::handle_knock(
-                payload,
-                &header,
-                client_addr,
-                &shared_secret,
-                session_manager,
-            )
-            .await
-            {
-                Ok(response) => Some(response),
-                Err(e) => {
-                    warn!("KNOCK failed from {}: {}", client_addr, e);
-                    None // Silent drop on authentication failure
-                }
+        return Err("incomplete packet".to_string()); // Silent drop
+    }
+    
+    // KNOCK is encrypted with the client's long-term key, everything else
+    // with the key of its session
+    let key = if header.packet_type == PacketType::Knock {
+        state.keys().lookup(header.client_id)
+    } else {
+        match state.session_manager.session_key(header.session_id, header.client_id).await {
+            Some(key) => Some(key),
+            None => {
+                // Can't be authenticated, so no error reply either; the
+                // client re-knocks when its packets go unanswered. Not counted
+                // as a failure since a server restart causes this as well.
+                debug!(
+                    "{:?} from {} for unknown session {}",
+                    header.packet_type, client_addr, header.session_id
+                );
+                return Err(format!("unknown session {}", header.session_id)); // Silent drop
             }
         }
-        
-        PacketType::EchoRequest => {
-            // ECHO_REQUEST requires valid session
-            // For MVP, we'll allow it without strict session validation
-            match handlers::handle_echo_request(
-                payload,
-                &header,
-                client_addr,
-                &shared_secret,
-                session_manager,
-            )
-            .await
-            {
-                Ok(response) => Some(response),
-                Err(e) => {
-                    debug!("ECHO_REQUEST failed from {}: {}", client_addr, e);
-                    None
-                }
+    };
+    
+    // Decrypt payload - repeated failures (including unknown, revoked or
+    // expired client keys) get the source IP blocked
+    let decrypted = match key {
+        Some(key) => Packet::open_with_header(*header, data, Direction::ClientToServer, &key)
+            .map(|packet| (key, packet.payload))
+            .map_err(|e| e.to_string()),
+        None => Err(format!("no valid key for client {}", header.client_id)),
+    };
+    let (key, payload) = match decrypted {
+        Ok(decrypted) => {
+            rate_limiter.record_decrypt_success(client_addr.ip());
+            decrypted
+        }
+        Err(e) => {
+            debug!("{:?} failed from {}: {}", header.packet_type, client_addr, e);
+            if rate_limiter.record_decrypt_failure(client_addr.ip()) {
+                warn!("Blocking {} after repeated decryption failures", client_addr.ip());
             }
+            return Err(e); // Silent drop
+        }
+    };
+    
+    // Reject replayed or stale packets (only authenticated ones get here,
+    // so the window can't be filled with forged timestamps)
+    if let Err(reason) = state.replay_guard.check(header.client_id, header.nonce_timestamp) {
+        debug!(
+            "{:?} from {} rejected as replay: {:?} (nonce_timestamp={})",
+            header.packet_type, client_addr, reason, header.nonce_timestamp
+        );
+        return Err(format!("replay rejected: {:?}", reason)); // Silent drop
+    }
+    
+    Ok((key, payload))
+}
+
+/// Run a `keys` subcommand against the configured key store
+fn run_keys_command(config_path: &str, action: KeysAction) -> Result<()> {
+    let config = config::Config::load(config_path)
+        .context("Failed to load configuration")?;
+    let key_file = config.security.key_file
+        .context("No key_file configured in [security]")?;
+    
+    let mut store = KeyStore::load(&key_file)?;
+    let now = chrono::Utc::now().timestamp();
+    
+    match action {
+        KeysAction::Add { name, client_id, expires_days } => {
+            let expires = expires_days.map(|days| now + days as i64 * 86400);
+            let key = store.add(&name, client_id, expires, now)?.clone();
+            store.save(&key_file)?;
+            
+            println!("Added key for client {} ({})", key.client_id, key.name);
+            println!();
+            println!("Put this into the [server] section of the client's client.conf:");
+            println!("client_id = {}", key.client_id);
+            println!("shared_secret = \"{}\"", key.secret);
         }
         
-        PacketType::ThroughputStart => {
-            // Throughput test
-            match handlers::handle_throughput(
-                payload,
-                &header,
-                client_addr,
-                &shared_secret,
-                session_manager,
-            )
-            .await
-            {
-                Ok(response) => response,
-                Err(e) => {
-                    debug!("THROUGHPUT_START failed from {}: {}", client_addr, e);
-                    None
-                }
-            }
+        KeysAction::Revoke { client_id } => {
+            let key = store.revoke(client_id, now)?.clone();
+            store.save(&key_file)?;
+            
+            println!("Revoked key for client {} ({})", key.client_id, key.name);
+            println!("Reload the server (SIGHUP) to apply the change");
         }
         
-        _ => {
-            debug!("Unsupported packet type: {:?}", header.packet_type);
-            None // Silent drop
+        KeysAction::List => {
+            if store.clients.is_empty() {
+                println!("No client keys in {}", key_file);
+                return Ok(());
+            }
+            
+            println!("{:<20}  {:<20}  {:<8}  {:<16}  {:<16}", "CLIENT ID", "NAME", "STATUS", "CREATED", "EXPIRES");
+            for key in &store.clients {
+                println!(
+                    "{:<20}  {:<20}  {:<8}  {:<16}  {:<16}",
+                    key.client_id,
+                    key.name,
+                    key.status(now),
+                    format_timestamp(key.created),
+                    key.expires.map(format_timestamp).unwrap_or_else(|| "never".to_string())
+                );
+            }
         }
     }
+    
+    Ok(())
 }
 
+/// Format a unix timestamp for `keys list`
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::codec::PacketIds;
+    use protocol::packets::{EchoReplyPayload, EchoRequestPayload};
+    
+    const SESSION_KEY: [u8; 32] = [5; 32];
+    
+    /// Server state without rate limiting, with session 7 of client 42
+    async fn state(session_timeout_sec: u64) -> ServerState {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let logging = config::LoggingConfig {
+            level: "info".to_string(),
+            log_successful_knocks: false,
+            log_failed_knocks: true,
+            log_echo_requests: false,
+            audit_log: None,
+        };
+        let metrics = Arc::new(Metrics::default());
+        let quotas = Arc::new(Quotas::new(config::QuotaConfig::default()));
+        let session_manager = Arc::new(SessionManager::new(session_timeout_sec, Admission::default()));
+        let throughput_manager = Arc::new(ThroughputManager::default());
+        let download_manager = Arc::new(DownloadManager::new(0, metrics.clone(), quotas.clone()));
+        let knock_audit = Arc::new(KnockAudit::new(&logging, metrics.clone()).unwrap());
+        let log_echo_requests = Arc::new(AtomicBool::new(false));
+        let registry = HandlerRegistry::new(
+            session_manager.clone(),
+            throughput_manager.clone(),
+            download_manager.clone(),
+            socket.clone(),
+            knock_audit.clone(),
+            log_echo_requests.clone(),
+        );
+        let rate_limiting = config::RateLimitingConfig {
+            max_packets_per_second: 100,
+            max_bandwidth_mbps: 0,
+            burst_size: 100,
+            decrypt_failure_threshold: 0,
+            decrypt_failure_window_sec: 60,
+            decrypt_failure_block_sec: 60,
+        };
+        
+        session_manager
+            .create_session(7, 42, SESSION_KEY, "127.0.0.1:5000".parse().unwrap())
+            .await
+            .unwrap();
+        
+        ServerState {
+            socket,
+            keys: RwLock::new(Arc::new(ServerKeys::new(&KeyStore::default(), None).unwrap())),
+            registry,
+            session_manager,
+            throughput_manager,
+            download_manager,
+            rate_limiter: Arc::new(RateLimiter::new(false, rate_limiting)),
+            replay_guard: Arc::new(ReplayGuard::new(Duration::from_secs(60))),
+            port_knock: None,
+            quotas,
+            metrics,
+            knock_audit,
+            log_echo_requests,
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+    
+    /// Send an ECHO_REQUEST for a session and return the replies
+    async fn echo(state: &ServerState, client_id: u64, session_id: u64) -> Vec<Packet> {
+        let request = EchoRequestPayload::new(1);
+        let data = Packet::seal(
+            &request,
+            PacketIds::new(client_id, session_id),
+            Direction::ClientToServer,
+            &SESSION_KEY,
+        )
+        .unwrap();
+        let header = PacketHeader::from_bytes(&data).unwrap();
+        
+        handle_packet(&data, header, "127.0.0.1:5000".parse().unwrap(), state)
+            .await
+            .iter()
+            .map(|reply| Packet::open(reply, Direction::ServerToClient, &SESSION_KEY).unwrap())
+            .collect()
+    }
+    
+    #[tokio::test]
+    async fn test_live_session_is_served() {
+        let state = state(60).await;
+        let replies = echo(&state, 42, 7).await;
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].decode::<EchoReplyPayload>().is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_expired_session_gets_error_reply() {
+        // Expired at once, but its key is kept until the cleanup runs
+        let state = state(0).await;
+        let replies = echo(&state, 42, 7).await;
+        assert_eq!(replies.len(), 1);
+        
+        let error: ErrorPayload = replies[0].decode().unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidSession);
+        assert_eq!(error.request, PacketType::EchoRequest);
+        assert_eq!(replies[0].header.session_id, 7);
+    }
+    
+    #[tokio::test]
+    async fn test_unknown_session_is_dropped_silently() {
+        // Without a session key the packet can't be authenticated, so an
+        // ERROR would be an oracle for unauthenticated peers
+        let state = state(60).await;
+        assert!(echo(&state, 42, 8).await.is_empty());
+        
+        // Sessions of other clients are unknown as well
+        assert!(echo(&state, 43, 7).await.is_empty());
+    }
+}
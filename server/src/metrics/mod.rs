@@ -0,0 +1,391 @@
+//! Operational metrics ([monitoring] section)
+//!
+//! Counters are kept in atomics and updated on the packet path; figures the
+//! other components already track (rate limiter, replay guard, port
+//! knocking, sessions) are read from them when scraped. An optional HTTP
+//! listener serves them in the Prometheus text format on /metrics, plus a
+//! /healthz endpoint for uptime checks.
+
+use crate::handlers::DownloadManager;
+use crate::port_knock::PortKnockGuard;
+use crate::quota::Quotas;
+use crate::rate_limit::RateLimiter;
+use crate::replay::ReplayGuard;
+use crate::session::SessionManager;
+use protocol::packets::PacketType;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error};
+
+/// Upper bounds of the handler latency buckets (seconds)
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// Largest HTTP request head we read
+const MAX_REQUEST_BYTES: usize = 4096;
+
+/// Scrapers that don't finish their request within this time are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Latency histogram with fixed buckets
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket (not cumulative), the last one is +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_ns: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of one packet type
+#[derive(Debug, Default)]
+struct TypeMetrics {
+    received: AtomicU64,
+    handler_errors: AtomicU64,
+    handler_latency: Histogram,
+}
+
+/// Counters updated on the packet path
+pub struct Metrics {
+    types: HashMap<PacketType, TypeMetrics>,
+    invalid_packets: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    knocks_accepted: AtomicU64,
+    knocks_rejected: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            types: PacketType::ALL
+                .into_iter()
+                .map(|packet_type| (packet_type, TypeMetrics::default()))
+                .collect(),
+            invalid_packets: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            knocks_accepted: AtomicU64::new(0),
+            knocks_rejected: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a datagram with a valid header (before rate limiting)
+    pub fn record_received(&self, packet_type: PacketType, len: usize) {
+        self.types[&packet_type].received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+    
+    /// Count a datagram whose header didn't parse
+    pub fn record_invalid(&self, len: usize) {
+        self.invalid_packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+    
+    pub fn record_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+    
+    /// Record how long a handler took and whether it failed
+    pub fn record_handler(&self, packet_type: PacketType, duration: Duration, ok: bool) {
+        let metrics = &self.types[&packet_type];
+        metrics.handler_latency.observe(duration);
+        if !ok {
+            metrics.handler_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    
+    /// Datagrams received with a valid header
+    pub fn packets_received(&self) -> u64 {
+        self.types.values().map(|metrics| metrics.received.load(Ordering::Relaxed)).sum()
+    }
+    
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+    
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+    
+    /// KNOCKs accepted and rejected
+    pub fn knocks(&self) -> (u64, u64) {
+        (self.knocks_accepted.load(Ordering::Relaxed), self.knocks_rejected.load(Ordering::Relaxed))
+    }
+    
+    /// Count a KNOCK that created a session (or failed to)
+    pub fn record_knock(&self, accepted: bool) {
+        let counter = if accepted { &self.knocks_accepted } else { &self.knocks_rejected };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Everything the /metrics endpoint reports on
+pub struct MetricsSources {
+    pub metrics: Arc<Metrics>,
+    pub session_manager: Arc<SessionManager>,
+    pub download_manager: Arc<DownloadManager>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub replay_guard: Arc<ReplayGuard>,
+    pub port_knock: Option<Arc<PortKnockGuard>>,
+    pub quotas: Arc<Quotas>,
+}
+
+impl MetricsSources {
+    /// Render all metrics in the Prometheus text exposition format
+    pub async fn render(&self) -> String {
+        let mut out = String::new();
+        let metrics = &self.metrics;
+        
+        header(&mut out, "bufferbane_packets_received_total", "counter", "Datagrams with a valid header, by packet type");
+        for packet_type in PacketType::ALL {
+            let value = metrics.types[&packet_type].received.load(Ordering::Relaxed);
+            sample(&mut out, "bufferbane_packets_received_total", &[("type", label(packet_type))], value);
+        }
+        counter(&mut out, "bufferbane_packets_invalid_total", "Datagrams dropped for an invalid header", &metrics.invalid_packets);
+        counter(&mut out, "bufferbane_bytes_received_total", "UDP payload bytes received on the service port", &metrics.bytes_received);
+        counter(&mut out, "bufferbane_bytes_sent_total", "UDP payload bytes sent to clients", &metrics.bytes_sent);
+        
+        header(&mut out, "bufferbane_knocks_total", "counter", "KNOCKs that reached authentication, by outcome");
+        sample(&mut out, "bufferbane_knocks_total", &[("result", "accepted")], metrics.knocks_accepted.load(Ordering::Relaxed));
+        sample(&mut out, "bufferbane_knocks_total", &[("result", "rejected")], metrics.knocks_rejected.load(Ordering::Relaxed));
+        
+        let rate_stats = self.rate_limiter.stats();
+        counter(&mut out, "bufferbane_decrypt_failures_total", "Packets that failed authentication", &rate_stats.decrypt_failures);
+        header(&mut out, "bufferbane_rate_limit_dropped_total", "counter", "Packets dropped by the rate limiter, by reason");
+        for (reason, value) in [
+            ("blocked", &rate_stats.dropped_blocked),
+            ("ip_rate", &rate_stats.dropped_ip_rate),
+            ("client_rate", &rate_stats.dropped_client_rate),
+        ] {
+            sample(&mut out, "bufferbane_rate_limit_dropped_total", &[("reason", reason)], value.load(Ordering::Relaxed));
+        }
+        counter(&mut out, "bufferbane_ips_blocked_total", "Source IPs blocked after repeated decryption failures", &rate_stats.ips_blocked);
+        gauge(&mut out, "bufferbane_blocked_ips", "Source IPs currently blocked", self.rate_limiter.blocked_ips() as u64);
+        
+        let replay_stats = self.replay_guard.stats();
+        header(&mut out, "bufferbane_replay_rejected_total", "counter", "Authenticated packets rejected as replays, by reason");
+        sample(&mut out, "bufferbane_replay_rejected_total", &[("reason", "stale")], replay_stats.rejected_stale.load(Ordering::Relaxed));
+        sample(&mut out, "bufferbane_replay_rejected_total", &[("reason", "duplicate")], replay_stats.rejected_duplicate.load(Ordering::Relaxed));
+        
+        if let Some(guard) = &self.port_knock {
+            let knock_stats = guard.stats();
+            counter(&mut out, "bufferbane_port_knock_sequences_total", "Completed port knock sequences", &knock_stats.sequences_completed);
+            counter(&mut out, "bufferbane_port_knock_dropped_total", "KNOCKs dropped without a completed knock sequence", &knock_stats.dropped_knocks);
+        }
+        
+        gauge(&mut out, "bufferbane_active_sessions", "Sessions not yet cleaned up", self.session_manager.active_sessions().await as u64);
+        gauge(&mut out, "bufferbane_active_downloads", "Running download streams", self.download_manager.active_downloads().await as u64);
+        
+        header(&mut out, "bufferbane_client_bytes_today", "gauge", "Traffic counted against the daily quota, by client");
+        let mut usage = self.quotas.usage_today();
+        usage.sort_unstable();
+        for (client_id, bytes) in usage {
+            sample(&mut out, "bufferbane_client_bytes_today", &[("client_id", &client_id.to_string())], bytes);
+        }
+        gauge(&mut out, "bufferbane_client_bytes_daily_limit", "Daily quota per client (0 = unlimited)", self.quotas.daily_bytes());
+        
+        header(&mut out, "bufferbane_handler_errors_total", "counter", "Requests a handler refused or failed, by packet type");
+        for packet_type in PacketType::ALL {
+            let value = metrics.types[&packet_type].handler_errors.load(Ordering::Relaxed);
+            sample(&mut out, "bufferbane_handler_errors_total", &[("type", label(packet_type))], value);
+        }
+        
+        header(&mut out, "bufferbane_handler_duration_seconds", "histogram", "Time spent in packet handlers, by packet type");
+        for packet_type in PacketType::ALL {
+            let histogram = &metrics.types[&packet_type].handler_latency;
+            let count = histogram.count.load(Ordering::Relaxed);
+            if count == 0 {
+                continue;
+            }
+            let packet_type = label(packet_type);
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+                sample(
+                    &mut out,
+                    "bufferbane_handler_duration_seconds_bucket",
+                    &[("type", packet_type), ("le", &le)],
+                    cumulative,
+                );
+            }
+            let sum = histogram.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "bufferbane_handler_duration_seconds_sum{{type=\"{}\"}} {}", packet_type, sum);
+            sample(&mut out, "bufferbane_handler_duration_seconds_count", &[("type", packet_type)], count);
+        }
+        
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, v)).collect();
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Metric label of a packet type
+fn label(packet_type: PacketType) -> &'static str {
+    match packet_type {
+        PacketType::Knock => "knock",
+        PacketType::KnockAck => "knock_ack",
+        PacketType::EchoRequest => "echo_request",
+        PacketType::EchoReply => "echo_reply",
+        PacketType::ThroughputStart => "throughput_start",
+        PacketType::ThroughputData => "throughput_data",
+        PacketType::ThroughputEnd => "throughput_end",
+        PacketType::ThroughputStats => "throughput_stats",
+        PacketType::DownloadRequest => "download_request",
+        PacketType::DownloadData => "download_data",
+        PacketType::DownloadEnd => "download_end",
+        PacketType::BufferbloatStart => "bufferbloat_start",
+        PacketType::BufferbloatEnd => "bufferbloat_end",
+        PacketType::Error => "error",
+    }
+}
+
+/// Serve /metrics and /healthz until the process exits
+pub async fn serve(listener: TcpListener, sources: Arc<MetricsSources>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let sources = sources.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &sources)).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => debug!("Metrics request from {} failed: {}", addr, e),
+                        Err(_) => debug!("Metrics request from {} timed out", addr),
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Error accepting metrics connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Answer a single HTTP/1.x request and close the connection
+async fn respond(mut stream: TcpStream, sources: &MetricsSources) -> std::io::Result<()> {
+    let mut request = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_BYTES {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = path.split('?').next().unwrap_or(path);
+    
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", sources.render().await),
+        ("GET", "/healthz") => ("200 OK", "text/plain", "ok\n".to_string()),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_string()),
+    };
+    
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitingConfig;
+    use crate::session::Admission;
+    
+    #[tokio::test]
+    async fn test_render_prometheus_text() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.record_received(PacketType::EchoRequest, 100);
+        metrics.record_received(PacketType::EchoRequest, 100);
+        metrics.record_invalid(10);
+        metrics.record_sent(60);
+        metrics.record_knock(true);
+        metrics.record_handler(PacketType::EchoRequest, Duration::from_micros(200), true);
+        metrics.record_handler(PacketType::EchoRequest, Duration::from_secs(2), false);
+        
+        let sources = MetricsSources {
+            metrics,
+            session_manager: Arc::new(SessionManager::new(60, Admission::default())),
+            download_manager: Arc::new(DownloadManager::new(0, Arc::default(), Arc::default())),
+            rate_limiter: Arc::new(RateLimiter::new(false, RateLimitingConfig {
+                max_packets_per_second: 1,
+                max_bandwidth_mbps: 1,
+                burst_size: 1,
+                decrypt_failure_threshold: 1,
+                decrypt_failure_window_sec: 1,
+                decrypt_failure_block_sec: 1,
+            })),
+            replay_guard: Arc::new(ReplayGuard::new(Duration::from_secs(30))),
+            port_knock: None,
+            quotas: Arc::default(),
+        };
+        let text = sources.render().await;
+        
+        assert!(text.contains("bufferbane_packets_received_total{type=\"echo_request\"} 2\n"));
+        assert!(text.contains("bufferbane_packets_received_total{type=\"knock\"} 0\n"));
+        assert!(text.contains("bufferbane_packets_invalid_total 1\n"));
+        assert!(text.contains("bufferbane_bytes_received_total 210\n"));
+        assert!(text.contains("bufferbane_bytes_sent_total 60\n"));
+        assert!(text.contains("bufferbane_knocks_total{result=\"accepted\"} 1\n"));
+        assert!(text.contains("bufferbane_active_sessions 0\n"));
+        assert!(text.contains("bufferbane_handler_errors_total{type=\"echo_request\"} 1\n"));
+        
+        // Buckets are cumulative and end with +Inf
+        assert!(text.contains("bufferbane_handler_duration_seconds_bucket{type=\"echo_request\",le=\"0.0001\"} 0\n"));
+        assert!(text.contains("bufferbane_handler_duration_seconds_bucket{type=\"echo_request\",le=\"0.00025\"} 1\n"));
+        assert!(text.contains("bufferbane_handler_duration_seconds_bucket{type=\"echo_request\",le=\"1\"} 1\n"));
+        assert!(text.contains("bufferbane_handler_duration_seconds_bucket{type=\"echo_request\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("bufferbane_handler_duration_seconds_count{type=\"echo_request\"} 2\n"));
+        assert!(!text.contains("bufferbane_handler_duration_seconds_count{type=\"knock\"}"));
+        assert!(!text.contains("bufferbane_port_knock"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Port knocking in front of the service port ([security] port_knocking)
+//!
+//! The server listens on the knock_ports and tracks, per source IP, how far
+//! it got through the sequence. Datagrams to the knock ports carry nothing
+//! and are never answered; only the order of the ports counts. An IP that
+//! hits all ports in order within knock_timeout_sec may send a KNOCK to the
+//! service port for another knock_timeout_sec. KNOCKs from anywhere else are
+//! dropped before any other work is done, so the service port looks closed
+//! to scanners.
+//!
+//! Later packets need the session key negotiated in the KNOCK exchange, so
+//! they are not tied to the unlock.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Progress of one source IP through the sequence
+#[derive(Debug, Clone)]
+struct Progress {
+    /// Index of the port expected next
+    next: usize,
+    /// When the first port of the sequence was hit
+    started: Instant,
+}
+
+/// Counters reported by the cleanup task
+#[derive(Debug, Default)]
+pub struct PortKnockStats {
+    pub sequences_completed: AtomicU64,
+    pub dropped_knocks: AtomicU64,
+}
+
+/// Per-IP knock sequence tracker
+pub struct PortKnockGuard {
+    ports: Vec<u16>,
+    timeout: Duration,
+    progress: Mutex<HashMap<IpAddr, Progress>>,
+    /// IPs that completed the sequence, with the end of their KNOCK window
+    unlocked: Mutex<HashMap<IpAddr, Instant>>,
+    stats: PortKnockStats,
+}
+
+impl PortKnockGuard {
+    pub fn new(ports: Vec<u16>, timeout: Duration) -> Self {
+        Self {
+            ports,
+            timeout,
+            progress: Mutex::new(HashMap::new()),
+            unlocked: Mutex::new(HashMap::new()),
+            stats: PortKnockStats::default(),
+        }
+    }
+    
+    /// Ports of the sequence, in order
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+    
+    /// Record a datagram from `ip` to knock port `port`
+    ///
+    /// A port out of order resets the IP's progress (or restarts it if it is
+    /// the first port). Returns true if this completed the sequence.
+    pub fn record_knock(&self, ip: IpAddr, port: u16) -> bool {
+        let completed = self.record_knock_at(ip, port, Instant::now());
+        if completed {
+            self.stats.sequences_completed.fetch_add(1, Ordering::Relaxed);
+        }
+        completed
+    }
+    
+    fn record_knock_at(&self, ip: IpAddr, port: u16, now: Instant) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        let current = progress
+            .get(&ip)
+            .filter(|p| now.duration_since(p.started) < self.timeout)
+            .cloned();
+        
+        let next = match current {
+            Some(p) if self.ports[p.next] == port => Progress { next: p.next + 1, started: p.started },
+            _ if self.ports[0] == port => Progress { next: 1, started: now },
+            _ => {
+                progress.remove(&ip);
+                return false;
+            }
+        };
+        
+        if next.next < self.ports.len() {
+            progress.insert(ip, next);
+            return false;
+        }
+        
+        progress.remove(&ip);
+        self.unlocked.lock().unwrap().insert(ip, now + self.timeout);
+        true
+    }
+    
+    /// Check whether a KNOCK from `ip` may be processed
+    ///
+    /// Drops are counted in the stats.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let allowed = self.is_unlocked_at(ip, Instant::now());
+        if !allowed {
+            self.stats.dropped_knocks.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+    
+    fn is_unlocked_at(&self, ip: IpAddr, now: Instant) -> bool {
+        self.unlocked
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .is_some_and(|&until| now < until)
+    }
+    
+    /// Drop stale progress and expired unlocks
+    pub fn cleanup_expired(&self) {
+        let now = Instant::now();
+        self.progress
+            .lock()
+            .unwrap()
+            .retain(|_, p| now.duration_since(p.started) < self.timeout);
+        self.unlocked.lock().unwrap().retain(|_, &mut until| now < until);
+    }
+    
+    pub fn stats(&self) -> &PortKnockStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn guard() -> PortKnockGuard {
+        PortKnockGuard::new(vec![1001, 1002, 1003], Duration::from_secs(10))
+    }
+    
+    #[test]
+    fn test_sequence_unlocks_ip() {
+        let guard = guard();
+        let now = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        
+        assert!(!guard.is_unlocked_at(ip, now));
+        assert!(!guard.record_knock_at(ip, 1001, now));
+        assert!(!guard.record_knock_at(other_ip, 1001, now));
+        assert!(!guard.record_knock_at(ip, 1002, now));
+        assert!(guard.record_knock_at(ip, 1003, now));
+        
+        assert!(guard.is_unlocked_at(ip, now + Duration::from_secs(9)));
+        assert!(!guard.is_unlocked_at(ip, now + Duration::from_secs(10)));
+        assert!(!guard.is_unlocked_at(other_ip, now));
+    }
+    
+    #[test]
+    fn test_wrong_order_or_timeout_resets() {
+        let guard = guard();
+        let now = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        
+        // Out of order
+        assert!(!guard.record_knock_at(ip, 1001, now));
+        assert!(!guard.record_knock_at(ip, 1003, now));
+        assert!(!guard.record_knock_at(ip, 1002, now));
+        assert!(!guard.record_knock_at(ip, 1003, now));
+        
+        // Repeating the first port starts over
+        assert!(!guard.record_knock_at(ip, 1001, now));
+        assert!(!guard.record_knock_at(ip, 1001, now));
+        assert!(!guard.record_knock_at(ip, 1002, now));
+        assert!(guard.record_knock_at(ip, 1003, now));
+        
+        // Too slow
+        let ip: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(!guard.record_knock_at(ip, 1001, now));
+        assert!(!guard.record_knock_at(ip, 1002, now));
+        assert!(!guard.record_knock_at(ip, 1003, now + Duration::from_secs(11)));
+        assert!(!guard.is_unlocked_at(ip, now + Duration::from_secs(11)));
+    }
+}
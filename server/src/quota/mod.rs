@@ -0,0 +1,184 @@
+//! Per-client resource quotas ([quotas] section)
+//!
+//! Keeps one client from starving the others: it may only run
+//! max_tests_per_client tests at once, and once it has used
+//! daily_mb_per_client of traffic it can't start new tests until the next
+//! UTC day. Traffic is everything its sessions send and receive, including
+//! download streams. Running tests are not cut off when the quota runs out,
+//! except for download streams, which stop sending at that point.
+
+use crate::config::QuotaConfig;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// Traffic of one client on one day
+#[derive(Debug, Clone, Copy)]
+struct DailyUsage {
+    /// Days since the unix epoch (UTC)
+    day: i64,
+    bytes: u64,
+}
+
+/// Why a test was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaExceeded {
+    /// The client already runs this many tests
+    Tests(usize),
+    /// Daily traffic used up; resets after the given time
+    Bytes(Duration),
+}
+
+/// Quota usage of all clients
+#[derive(Default)]
+pub struct Quotas {
+    config: RwLock<QuotaConfig>,
+    usage: Mutex<HashMap<u64, DailyUsage>>,
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+    
+    /// Apply new quotas (config reload); usage so far is kept
+    pub fn reconfigure(&self, config: QuotaConfig) {
+        *self.config.write().unwrap() = config;
+    }
+    
+    /// Daily traffic limit in bytes (0 = unlimited)
+    pub fn daily_bytes(&self) -> u64 {
+        self.config.read().unwrap().daily_mb_per_client * 1_000_000
+    }
+    
+    /// Account traffic of a client
+    pub fn record_bytes(&self, client_id: u64, bytes: u64) {
+        self.record_bytes_at(client_id, bytes, now());
+    }
+    
+    fn record_bytes_at(&self, client_id: u64, bytes: u64, now: i64) {
+        let today = now.div_euclid(SECONDS_PER_DAY);
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(client_id).or_insert(DailyUsage { day: today, bytes: 0 });
+        if entry.day != today {
+            *entry = DailyUsage { day: today, bytes: 0 };
+        }
+        entry.bytes += bytes;
+    }
+    
+    /// Traffic of a client today
+    pub fn bytes_today(&self, client_id: u64) -> u64 {
+        self.bytes_today_at(client_id, now())
+    }
+    
+    fn bytes_today_at(&self, client_id: u64, now: i64) -> u64 {
+        let today = now.div_euclid(SECONDS_PER_DAY);
+        self.usage
+            .lock()
+            .unwrap()
+            .get(&client_id)
+            .filter(|usage| usage.day == today)
+            .map_or(0, |usage| usage.bytes)
+    }
+    
+    /// Traffic a client may still use today (None = unlimited)
+    pub fn bytes_left(&self, client_id: u64) -> Option<u64> {
+        self.bytes_left_at(client_id, now())
+    }
+    
+    fn bytes_left_at(&self, client_id: u64, now: i64) -> Option<u64> {
+        let daily_bytes = self.daily_bytes();
+        (daily_bytes > 0).then(|| daily_bytes.saturating_sub(self.bytes_today_at(client_id, now)))
+    }
+    
+    /// Traffic of every client seen today
+    pub fn usage_today(&self) -> Vec<(u64, u64)> {
+        let today = now().div_euclid(SECONDS_PER_DAY);
+        self.usage
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, usage)| usage.day == today)
+            .map(|(&client_id, usage)| (client_id, usage.bytes))
+            .collect()
+    }
+    
+    /// Check whether a client running `running_tests` may start another
+    pub fn check_new_test(&self, client_id: u64, running_tests: usize) -> Result<(), QuotaExceeded> {
+        self.check_new_test_at(client_id, running_tests, now())
+    }
+    
+    fn check_new_test_at(&self, client_id: u64, running_tests: usize, now: i64) -> Result<(), QuotaExceeded> {
+        let max_tests = self.config.read().unwrap().max_tests_per_client;
+        if max_tests > 0 && running_tests >= max_tests {
+            return Err(QuotaExceeded::Tests(running_tests));
+        }
+        
+        if self.bytes_left_at(client_id, now) == Some(0) {
+            let until_reset = SECONDS_PER_DAY - now.rem_euclid(SECONDS_PER_DAY);
+            return Err(QuotaExceeded::Bytes(Duration::from_secs(until_reset as u64)));
+        }
+        
+        Ok(())
+    }
+    
+    /// Forget usage of earlier days
+    pub fn cleanup_expired(&self) {
+        let today = now().div_euclid(SECONDS_PER_DAY);
+        self.usage.lock().unwrap().retain(|_, usage| usage.day == today);
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_test_and_daily_byte_quotas() {
+        let quotas = Quotas::new(QuotaConfig {
+            max_tests_per_client: 2,
+            daily_mb_per_client: 1,
+        });
+        let day = 20_000 * SECONDS_PER_DAY;
+        let now = day + 3600;
+        
+        assert_eq!(quotas.check_new_test_at(1, 1, now), Ok(()));
+        assert_eq!(quotas.check_new_test_at(1, 2, now), Err(QuotaExceeded::Tests(2)));
+        
+        quotas.record_bytes_at(1, 600_000, now);
+        assert_eq!(quotas.bytes_left_at(1, now), Some(400_000));
+        quotas.record_bytes_at(1, 400_000, now);
+        quotas.record_bytes_at(2, 10, now);
+        assert_eq!(quotas.bytes_left_at(1, now), Some(0));
+        assert_eq!(
+            quotas.check_new_test_at(1, 0, now),
+            Err(QuotaExceeded::Bytes(Duration::from_secs(23 * 3600)))
+        );
+        assert_eq!(quotas.check_new_test_at(2, 0, now), Ok(()));
+        
+        // A new day starts from zero
+        let tomorrow = day + SECONDS_PER_DAY;
+        assert_eq!(quotas.bytes_today_at(1, tomorrow), 0);
+        assert_eq!(quotas.check_new_test_at(1, 0, tomorrow), Ok(()));
+        quotas.record_bytes_at(1, 5, tomorrow);
+        assert_eq!(quotas.bytes_today_at(1, tomorrow), 5);
+        
+        // 0 = unlimited
+        quotas.reconfigure(QuotaConfig {
+            max_tests_per_client: 0,
+            daily_mb_per_client: 0,
+        });
+        quotas.record_bytes_at(1, 10_000_000, tomorrow);
+        assert_eq!(quotas.bytes_left_at(1, tomorrow), None);
+        assert_eq!(quotas.check_new_test_at(1, 100, tomorrow), Ok(()));
+    }
+}
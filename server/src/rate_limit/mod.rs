@@ -0,0 +1,474 @@
+//! Rate limiting for incoming packets ([rate_limiting] section)
+//!
+//! Every datagram is checked here before a task is spawned for it or any
+//! decryption is attempted:
+//! - Token buckets per source IP and per client_id. Control packets are
+//!   limited by packet rate, bulk test data by bandwidth. The client_id is
+//!   only charged once the packet has been authenticated, so spoofed packets
+//!   can't use up another client's budget.
+//! - Source IPs that repeatedly send packets failing decryption are blocked
+//!   for a while, so they cannot make the server do AEAD work.
+
+use crate::config::RateLimitingConfig;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+/// Size assumed per packet when converting burst_size to a byte budget
+const MAX_PACKET_BYTES: f64 = 1500.0;
+
+/// Buckets idle for this long are forgotten
+const IDLE_EXPIRY: Duration = Duration::from_secs(60);
+
+/// A client is told about dropped packets at most this often; also the
+/// retry-after hint, since buckets refill a full second's rate by then
+pub const NOTICE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which budget a packet is charged against (see `PacketHandler::rate_class`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateClass {
+    /// Authentication, echo and test control packets (max_packets_per_second)
+    Control,
+    /// Throughput test data (max_bandwidth_mbps)
+    Bulk,
+}
+
+/// Why a packet was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Source IP is blocked after repeated decryption failures
+    Blocked,
+    /// Source IP exceeded its budget
+    IpRate,
+    /// client_id exceeded its budget
+    ClientRate,
+}
+
+/// Classic token bucket
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    /// Tokens added per second
+    rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        Self {
+            tokens: capacity,
+            capacity,
+            rate,
+            last_refill: now,
+        }
+    }
+    
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+    
+    /// Check for `amount` tokens without taking them
+    fn has(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount
+    }
+    
+    fn try_take(&mut self, amount: f64, now: Instant) -> bool {
+        if self.has(amount, now) {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Budgets of one source (IP or client_id)
+#[derive(Debug, Clone)]
+struct SourceBuckets {
+    packets: TokenBucket,
+    /// None if bandwidth is unlimited
+    bytes: Option<TokenBucket>,
+    last_seen: Instant,
+}
+
+/// Decryption failures of one source IP
+#[derive(Debug, Clone)]
+struct FailureRecord {
+    count: u32,
+    window_start: Instant,
+    blocked_until: Option<Instant>,
+}
+
+/// Counters of dropped packets, reported by the cleanup task
+#[derive(Debug, Default)]
+pub struct RateLimitStats {
+    pub dropped_blocked: AtomicU64,
+    pub dropped_ip_rate: AtomicU64,
+    pub dropped_client_rate: AtomicU64,
+    pub decrypt_failures: AtomicU64,
+    pub ips_blocked: AtomicU64,
+}
+
+/// Per-IP and per-client rate limiter
+pub struct RateLimiter {
+    enabled: AtomicBool,
+    config: RwLock<RateLimitingConfig>,
+    ips: Mutex<HashMap<IpAddr, SourceBuckets>>,
+    clients: Mutex<HashMap<u64, SourceBuckets>>,
+    failures: Mutex<HashMap<IpAddr, FailureRecord>>,
+    /// Last RATE_LIMITED notice per client_id
+    notices: Mutex<HashMap<u64, Instant>>,
+    stats: RateLimitStats,
+}
+
+impl RateLimiter {
+    pub fn new(enabled: bool, config: RateLimitingConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            config: RwLock::new(config),
+            ips: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            notices: Mutex::new(HashMap::new()),
+            stats: RateLimitStats::default(),
+        }
+    }
+    
+    /// Apply new limits (config reload)
+    ///
+    /// Budgets start over with the new rates; blocked IPs stay blocked.
+    pub fn reconfigure(&self, enabled: bool, config: RateLimitingConfig) {
+        *self.config.write().unwrap() = config;
+        self.enabled.store(enabled, Ordering::Relaxed);
+        self.ips.lock().unwrap().clear();
+        self.clients.lock().unwrap().clear();
+    }
+    
+    fn config(&self) -> RwLockReadGuard<'_, RateLimitingConfig> {
+        self.config.read().unwrap()
+    }
+    
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+    
+    /// Check whether a packet may be processed
+    ///
+    /// Charges the packet against the source IP if the client_id has budget
+    /// left as well; the client_id is charged by `charge_client` once the
+    /// packet is authenticated. Drops are counted in the stats.
+    pub fn check(&self, ip: IpAddr, client_id: u64, class: RateClass, len: usize) -> Result<(), DropReason> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        
+        let verdict = self.check_at(ip, client_id, class, len, Instant::now());
+        self.count_drop(verdict)
+    }
+    
+    /// Charge an authenticated packet against its client_id
+    pub fn charge_client(&self, client_id: u64, class: RateClass, len: usize) -> Result<(), DropReason> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        
+        let verdict = self.charge_client_at(client_id, class, len, Instant::now());
+        self.count_drop(verdict)
+    }
+    
+    fn count_drop(&self, verdict: Result<(), DropReason>) -> Result<(), DropReason> {
+        let counter = match verdict {
+            Ok(()) => return Ok(()),
+            Err(DropReason::Blocked) => &self.stats.dropped_blocked,
+            Err(DropReason::IpRate) => &self.stats.dropped_ip_rate,
+            Err(DropReason::ClientRate) => &self.stats.dropped_client_rate,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        verdict
+    }
+    
+    fn check_at(&self, ip: IpAddr, client_id: u64, class: RateClass, len: usize, now: Instant) -> Result<(), DropReason> {
+        if let Some(record) = self.failures.lock().unwrap().get(&ip)
+            && record.blocked_until.is_some_and(|until| now < until)
+        {
+            return Err(DropReason::Blocked);
+        }
+        
+        // Nothing is taken from the IP for a packet the client_id's budget
+        // would drop anyway. Unknown client_ids have a full budget.
+        if let Some(buckets) = self.clients.lock().unwrap().get_mut(&client_id)
+            && !Self::bucket(buckets, class).is_none_or(|bucket| bucket.has(Self::cost(class, len), now))
+        {
+            return Err(DropReason::ClientRate);
+        }
+        
+        if !self.take(&self.ips, ip, class, len, now) {
+            return Err(DropReason::IpRate);
+        }
+        
+        Ok(())
+    }
+    
+    fn charge_client_at(&self, client_id: u64, class: RateClass, len: usize, now: Instant) -> Result<(), DropReason> {
+        if !self.take(&self.clients, client_id, class, len, now) {
+            return Err(DropReason::ClientRate);
+        }
+        Ok(())
+    }
+    
+    /// Charge a packet against one source's budget
+    fn take<K: Eq + Hash>(
+        &self,
+        sources: &Mutex<HashMap<K, SourceBuckets>>,
+        key: K,
+        class: RateClass,
+        len: usize,
+        now: Instant,
+    ) -> bool {
+        let mut sources = sources.lock().unwrap();
+        let buckets = sources.entry(key).or_insert_with(|| self.new_buckets(now));
+        buckets.last_seen = now;
+        
+        match Self::bucket(buckets, class) {
+            Some(bucket) => bucket.try_take(Self::cost(class, len), now),
+            None => true,
+        }
+    }
+    
+    /// Bucket a packet class is charged against, None if unlimited
+    fn bucket(buckets: &mut SourceBuckets, class: RateClass) -> Option<&mut TokenBucket> {
+        match class {
+            RateClass::Control => Some(&mut buckets.packets),
+            RateClass::Bulk => buckets.bytes.as_mut(),
+        }
+    }
+    
+    /// Tokens a packet of `len` bytes costs
+    fn cost(class: RateClass, len: usize) -> f64 {
+        match class {
+            RateClass::Control => 1.0,
+            RateClass::Bulk => len as f64,
+        }
+    }
+    
+    fn new_buckets(&self, now: Instant) -> SourceBuckets {
+        let config = self.config();
+        let burst = config.burst_size.max(1) as f64;
+        let packets = TokenBucket::new(config.max_packets_per_second as f64, burst, now);
+        
+        let bytes = (config.max_bandwidth_mbps > 0).then(|| {
+            let bytes_per_sec = config.max_bandwidth_mbps as f64 * 1_000_000.0 / 8.0;
+            TokenBucket::new(bytes_per_sec, burst * MAX_PACKET_BYTES, now)
+        });
+        
+        SourceBuckets {
+            packets,
+            bytes,
+            last_seen: now,
+        }
+    }
+    
+    /// Check whether a client may be told about a dropped packet now
+    ///
+    /// Limits the notices to one per NOTICE_INTERVAL and client_id. Only
+    /// called for authenticated packets, so the map holds known clients.
+    pub fn notice_due(&self, client_id: u64) -> bool {
+        let now = Instant::now();
+        let mut notices = self.notices.lock().unwrap();
+        if notices.get(&client_id).is_some_and(|&last| now.duration_since(last) < NOTICE_INTERVAL) {
+            return false;
+        }
+        notices.insert(client_id, now);
+        true
+    }
+    
+    /// Record a packet from `ip` that failed decryption
+    ///
+    /// Once an IP reaches decrypt_failure_threshold failures within
+    /// decrypt_failure_window_sec it is blocked for decrypt_failure_block_sec.
+    /// Returns true if this failure got the IP blocked.
+    pub fn record_decrypt_failure(&self, ip: IpAddr) -> bool {
+        self.stats.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+        let config = self.config();
+        if !self.enabled() || config.decrypt_failure_threshold == 0 {
+            return false;
+        }
+        
+        let now = Instant::now();
+        let window = Duration::from_secs(config.decrypt_failure_window_sec);
+        
+        let mut failures = self.failures.lock().unwrap();
+        let record = failures.entry(ip).or_insert(FailureRecord {
+            count: 0,
+            window_start: now,
+            blocked_until: None,
+        });
+        
+        if now.duration_since(record.window_start) >= window {
+            record.count = 0;
+            record.window_start = now;
+        }
+        
+        record.count += 1;
+        if record.count >= config.decrypt_failure_threshold
+            && record.blocked_until.is_none_or(|until| now >= until)
+        {
+            record.blocked_until = Some(now + Duration::from_secs(config.decrypt_failure_block_sec));
+            record.count = 0;
+            self.stats.ips_blocked.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        
+        false
+    }
+    
+    /// Forget the failures of an IP after it sent a valid packet
+    pub fn record_decrypt_success(&self, ip: IpAddr) {
+        if !self.enabled() {
+            return;
+        }
+        
+        let mut failures = self.failures.lock().unwrap();
+        if failures.get(&ip).is_some_and(|record| record.blocked_until.is_none()) {
+            failures.remove(&ip);
+        }
+    }
+    
+    /// Drop idle buckets and expired failure records
+    pub fn cleanup_expired(&self) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config().decrypt_failure_window_sec);
+        
+        self.ips.lock().unwrap().retain(|_, b| now.duration_since(b.last_seen) < IDLE_EXPIRY);
+        self.clients.lock().unwrap().retain(|_, b| now.duration_since(b.last_seen) < IDLE_EXPIRY);
+        self.notices.lock().unwrap().retain(|_, &mut last| now.duration_since(last) < NOTICE_INTERVAL);
+        self.failures.lock().unwrap().retain(|_, record| match record.blocked_until {
+            Some(until) => now < until,
+            None => now.duration_since(record.window_start) < window,
+        });
+    }
+    
+    /// Get number of currently blocked IPs
+    pub fn blocked_ips(&self) -> usize {
+        let now = Instant::now();
+        self.failures
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|record| record.blocked_until.is_some_and(|until| now < until))
+            .count()
+    }
+    
+    pub fn stats(&self) -> &RateLimitStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn limiter(max_packets_per_second: usize, max_bandwidth_mbps: usize, burst_size: usize) -> RateLimiter {
+        RateLimiter::new(true, RateLimitingConfig {
+            max_packets_per_second,
+            max_bandwidth_mbps,
+            burst_size,
+            decrypt_failure_threshold: 3,
+            decrypt_failure_window_sec: 60,
+            decrypt_failure_block_sec: 300,
+        })
+    }
+    
+    #[test]
+    fn test_token_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0, start);
+        assert!(bucket.try_take(1.0, start));
+        assert!(bucket.try_take(1.0, start));
+        assert!(!bucket.try_take(1.0, start));
+        
+        // 10 tokens/s -> one token after 100ms
+        assert!(bucket.try_take(1.0, start + Duration::from_millis(100)));
+        assert!(!bucket.try_take(1.0, start + Duration::from_millis(100)));
+    }
+    
+    #[test]
+    fn test_ip_and_client_budgets() {
+        let limiter = limiter(10, 0, 2);
+        let now = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        
+        for _ in 0..2 {
+            assert_eq!(limiter.check_at(ip, 1, RateClass::Control, 100, now), Ok(()));
+            assert_eq!(limiter.charge_client_at(1, RateClass::Control, 100, now), Ok(()));
+        }
+        assert_eq!(limiter.check_at(ip, 1, RateClass::Control, 100, now), Err(DropReason::ClientRate));
+        assert_eq!(limiter.check_at(ip, 2, RateClass::Control, 100, now), Err(DropReason::IpRate));
+        
+        // Same client from another IP is still out of budget, and the IP
+        // keeps its tokens
+        assert_eq!(limiter.check_at(other_ip, 1, RateClass::Control, 100, now), Err(DropReason::ClientRate));
+        assert_eq!(limiter.check_at(other_ip, 2, RateClass::Control, 100, now), Ok(()));
+        assert_eq!(limiter.check_at(other_ip, 2, RateClass::Control, 100, now), Ok(()));
+        
+        // Unlimited bandwidth - bulk data is not charged
+        assert_eq!(limiter.check_at(other_ip, 2, RateClass::Bulk, 1200, now), Ok(()));
+        assert_eq!(limiter.charge_client_at(2, RateClass::Bulk, 1200, now), Ok(()));
+    }
+    
+    #[test]
+    fn test_unauthenticated_packets_leave_client_budget() {
+        let limiter = limiter(10, 0, 2);
+        let now = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let spoofer: IpAddr = "192.0.2.66".parse().unwrap();
+        
+        // Packets claiming client 1 that never get authenticated
+        assert_eq!(limiter.check_at(spoofer, 1, RateClass::Control, 100, now), Ok(()));
+        assert_eq!(limiter.check_at(spoofer, 1, RateClass::Control, 100, now), Ok(()));
+        assert_eq!(limiter.check_at(spoofer, 1, RateClass::Control, 100, now), Err(DropReason::IpRate));
+        
+        assert_eq!(limiter.check_at(ip, 1, RateClass::Control, 100, now), Ok(()));
+        assert_eq!(limiter.charge_client_at(1, RateClass::Control, 100, now), Ok(()));
+    }
+    
+    #[test]
+    fn test_reconfigure_resets_budgets() {
+        let limiter = limiter(10, 0, 1);
+        let now = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        
+        assert_eq!(limiter.check_at(ip, 1, RateClass::Control, 100, now), Ok(()));
+        assert_eq!(limiter.check_at(ip, 1, RateClass::Control, 100, now), Err(DropReason::IpRate));
+        
+        let mut config = limiter.config().clone();
+        config.burst_size = 3;
+        limiter.reconfigure(true, config);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(ip, 1, RateClass::Control, 100, now), Ok(()));
+        }
+        assert_eq!(limiter.check_at(ip, 1, RateClass::Control, 100, now), Err(DropReason::IpRate));
+    }
+    
+    #[test]
+    fn test_decrypt_failures_block_ip() {
+        let limiter = limiter(100, 10, 200);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        
+        assert!(!limiter.record_decrypt_failure(ip));
+        assert!(!limiter.record_decrypt_failure(ip));
+        assert!(limiter.record_decrypt_failure(ip));
+        
+        assert_eq!(limiter.check(ip, 1, RateClass::Control, 100), Err(DropReason::Blocked));
+        assert_eq!(limiter.stats().dropped_blocked.load(Ordering::Relaxed), 1);
+        assert_eq!(limiter.blocked_ips(), 1);
+    }
+}
@@ -0,0 +1,146 @@
+//! Replay protection based on the header's nonce_timestamp
+//!
+//! A packet is only accepted if its nonce_timestamp is within the clock
+//! window around the server's time and has not been seen before from the
+//! same client. Timestamps older than the window are rejected anyway, so
+//! only the ones inside it have to be remembered.
+//!
+//! The seen timestamps live in memory; right after a server restart a
+//! packet captured within the last window could be replayed once.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Why a packet was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayReject {
+    /// nonce_timestamp is outside the accepted clock window
+    Stale,
+    /// nonce_timestamp was already used by this client
+    Duplicate,
+}
+
+/// Counters of rejected packets, reported by the cleanup task
+#[derive(Debug, Default)]
+pub struct ReplayStats {
+    pub rejected_stale: AtomicU64,
+    pub rejected_duplicate: AtomicU64,
+}
+
+/// Per-client sliding windows of seen nonce timestamps
+pub struct ReplayGuard {
+    /// Accepted difference between nonce_timestamp and server time (ns)
+    window_ns: u64,
+    seen: Mutex<HashMap<u64, BTreeSet<u64>>>,
+    stats: ReplayStats,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_ns: window.as_nanos() as u64,
+            seen: Mutex::new(HashMap::new()),
+            stats: ReplayStats::default(),
+        }
+    }
+    
+    /// Check a decrypted packet's nonce_timestamp and remember it
+    ///
+    /// Must only be called for packets that passed authentication, otherwise
+    /// forged timestamps could fill the window.
+    pub fn check(&self, client_id: u64, nonce_timestamp: u64) -> Result<(), ReplayReject> {
+        let verdict = self.check_at(client_id, nonce_timestamp, now_ns());
+        
+        match verdict {
+            Ok(()) => {}
+            Err(ReplayReject::Stale) => {
+                self.stats.rejected_stale.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(ReplayReject::Duplicate) => {
+                self.stats.rejected_duplicate.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        verdict
+    }
+    
+    fn check_at(&self, client_id: u64, nonce_timestamp: u64, now: u64) -> Result<(), ReplayReject> {
+        if nonce_timestamp.abs_diff(now) > self.window_ns {
+            return Err(ReplayReject::Stale);
+        }
+        
+        let mut seen = self.seen.lock().unwrap();
+        let timestamps = seen.entry(client_id).or_default();
+        
+        // Forget what the clock window rejects by itself
+        let cutoff = now.saturating_sub(self.window_ns);
+        if timestamps.first().is_some_and(|&oldest| oldest < cutoff) {
+            *timestamps = timestamps.split_off(&cutoff);
+        }
+        
+        if !timestamps.insert(nonce_timestamp) {
+            return Err(ReplayReject::Duplicate);
+        }
+        
+        Ok(())
+    }
+    
+    /// Drop windows of clients that have been quiet for longer than the window
+    pub fn cleanup_expired(&self) {
+        let cutoff = now_ns().saturating_sub(self.window_ns);
+        self.seen
+            .lock()
+            .unwrap()
+            .retain(|_, timestamps| timestamps.last().is_some_and(|&newest| newest >= cutoff));
+    }
+    
+    pub fn stats(&self) -> &ReplayStats {
+        &self.stats
+    }
+}
+
+/// Current time in nanoseconds since UNIX_EPOCH (same clock as nonce_timestamp)
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const SECOND: u64 = 1_000_000_000;
+    
+    #[test]
+    fn test_rejects_stale_and_duplicate() {
+        let guard = ReplayGuard::new(Duration::from_secs(30));
+        let now = 1_000 * SECOND;
+        
+        assert_eq!(guard.check_at(1, now - 5 * SECOND, now), Ok(()));
+        assert_eq!(guard.check_at(1, now - 5 * SECOND, now), Err(ReplayReject::Duplicate));
+        
+        // Same timestamp from another client is fine
+        assert_eq!(guard.check_at(2, now - 5 * SECOND, now), Ok(()));
+        
+        // Outside the clock window in either direction
+        assert_eq!(guard.check_at(1, now - 31 * SECOND, now), Err(ReplayReject::Stale));
+        assert_eq!(guard.check_at(1, now + 31 * SECOND, now), Err(ReplayReject::Stale));
+    }
+    
+    #[test]
+    fn test_window_slides() {
+        let guard = ReplayGuard::new(Duration::from_secs(30));
+        let start = 1_000 * SECOND;
+        
+        assert_eq!(guard.check_at(1, start, start), Ok(()));
+        
+        // Later the old timestamp is forgotten but still rejected as stale
+        let later = start + 40 * SECOND;
+        assert_eq!(guard.check_at(1, later, later), Ok(()));
+        assert_eq!(guard.seen.lock().unwrap()[&1].len(), 1);
+        assert_eq!(guard.check_at(1, start, later), Err(ReplayReject::Stale));
+    }
+}
@@ -1,56 +1,132 @@
 //! Session management for authenticated clients
+//!
+//! At most max_concurrent_clients clients can have a live session at once.
+//! A client that already has one may always KNOCK again; a new client is
+//! either turned away or gets the place of the client seen least recently,
+//! depending on admission_policy.
 
+use crate::config::AdmissionPolicy;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
+use tracing::{debug, info};
 
 /// Client session information
 #[derive(Debug, Clone)]
 pub struct Session {
-    #[allow(dead_code)]
     pub session_id: u64,
-    #[allow(dead_code)]
     pub client_id: u64,
-    #[allow(dead_code)]
+    /// Key derived in the KNOCK handshake, used for all later packets
+    pub key: [u8; 32],
     pub client_addr: SocketAddr,
-    #[allow(dead_code)]
     pub authenticated_at: Instant,
     pub last_seen: Instant,
     pub packets_received: u64,
-    #[allow(dead_code)]
     pub bytes_received: u64,
-    #[allow(dead_code)]
     pub bytes_sent: u64,
 }
 
+/// Session admission settings ([general] section)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Admission {
+    /// 0 = unlimited
+    pub max_clients: usize,
+    pub policy: AdmissionPolicy,
+}
+
 /// Session manager
 pub struct SessionManager {
     sessions: Arc<RwLock<HashMap<u64, Session>>>,
     session_timeout: Duration,
+    admission: std::sync::RwLock<Admission>,
 }
 
 impl SessionManager {
-    pub fn new(session_timeout_sec: u64) -> Self {
+    pub fn new(session_timeout_sec: u64, admission: Admission) -> Self {
         Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
             session_timeout: Duration::from_secs(session_timeout_sec),
+            admission: std::sync::RwLock::new(admission),
         }
     }
     
+    /// Change the admission settings (config reload)
+    ///
+    /// Clients over a lowered limit keep their sessions.
+    pub fn set_admission(&self, admission: Admission) {
+        *self.admission.write().unwrap() = admission;
+    }
+    
+    /// Generate an ID for a new session
+    ///
+    /// The ID is needed before the session exists because it goes into the
+    /// session key derivation.
+    pub fn new_session_id(&self) -> u64 {
+        rand::random()
+    }
+    
     /// Create a new session
+    ///
+    /// The new session replaces the ones the client had from the same IP
+    /// (a re-KNOCK); sessions of its other interfaces are kept. Fails if the
+    /// server is full and the admission policy turns new clients away.
     pub async fn create_session(
         &self,
+        session_id: u64,
         client_id: u64,
+        key: [u8; 32],
         client_addr: SocketAddr,
-    ) -> u64 {
-        // Generate random session ID (Send-safe)
-        let session_id: u64 = rand::random();
+    ) -> Result<(), String> {
+        let admission = *self.admission.read().unwrap();
+        let mut sessions = self.sessions.write().await;
+        let now = Instant::now();
+        
+        // Most recent activity of every client with a live session
+        let mut clients: HashMap<u64, Instant> = HashMap::new();
+        for session in sessions.values() {
+            if now.duration_since(session.last_seen) < self.session_timeout {
+                let last_seen = clients.entry(session.client_id).or_insert(session.last_seen);
+                *last_seen = (*last_seen).max(session.last_seen);
+            }
+        }
+        
+        if admission.max_clients > 0
+            && !clients.contains_key(&client_id)
+            && clients.len() >= admission.max_clients
+        {
+            match admission.policy {
+                AdmissionPolicy::Reject => {
+                    return Err(format!("server full ({} clients)", clients.len()));
+                }
+                AdmissionPolicy::EvictLru => {
+                    if let Some((&evicted, &last_seen)) = clients.iter().min_by_key(|&(_, &last_seen)| last_seen) {
+                        sessions.retain(|_, session| session.client_id != evicted);
+                        info!(
+                            "Evicted client {} (idle for {}s) to admit client {}",
+                            evicted,
+                            now.duration_since(last_seen).as_secs(),
+                            client_id
+                        );
+                    }
+                }
+            }
+        }
+        
+        let replaced = sessions.len();
+        sessions.retain(|_, session| {
+            session.client_id != client_id || session.client_addr.ip() != client_addr.ip()
+        });
+        let replaced = replaced - sessions.len();
+        if replaced > 0 {
+            debug!("Client {} replaced {} session(s) from {}", client_id, replaced, client_addr.ip());
+        }
         
         let session = Session {
             session_id,
             client_id,
+            key,
             client_addr,
             authenticated_at: Instant::now(),
             last_seen: Instant::now(),
@@ -59,10 +135,20 @@ impl SessionManager {
             bytes_sent: 0,
         };
         
-        let mut sessions = self.sessions.write().await;
         sessions.insert(session_id, session);
-        
-        session_id
+        Ok(())
+    }
+    
+    /// Get the key of a session belonging to `client_id`
+    ///
+    /// Expired sessions still have their key until they are cleaned up, so
+    /// their packets can be authenticated and answered with an error.
+    pub async fn session_key(&self, session_id: u64, client_id: u64) -> Option<[u8; 32]> {
+        let sessions = self.sessions.read().await;
+        sessions
+            .get(&session_id)
+            .filter(|session| session.client_id == client_id)
+            .map(|session| session.key)
     }
     
     /// Get a session by session_id
@@ -82,7 +168,6 @@ impl SessionManager {
     }
     
     /// Update statistics
-    #[allow(dead_code)]
     pub async fn update_stats(&self, session_id: u64, bytes_received: u64, bytes_sent: u64) {
         let mut sessions = self.sessions.write().await;
         if let Some(session) = sessions.get_mut(&session_id) {
@@ -101,22 +186,97 @@ impl SessionManager {
         });
     }
     
+    /// Remove the sessions of every client `keep` returns false for
+    ///
+    /// Returns the removed clients.
+    pub async fn retain_clients(&self, keep: impl Fn(u64) -> bool) -> Vec<u64> {
+        let mut sessions = self.sessions.write().await;
+        let mut removed = Vec::new();
+        sessions.retain(|_, session| {
+            let retained = keep(session.client_id);
+            if !retained && !removed.contains(&session.client_id) {
+                removed.push(session.client_id);
+            }
+            retained
+        });
+        removed
+    }
+    
+    /// Snapshot of all sessions
+    pub async fn sessions(&self) -> Vec<Session> {
+        self.sessions.read().await.values().cloned().collect()
+    }
+    
     /// Get number of active sessions
     pub async fn active_sessions(&self) -> usize {
         let sessions = self.sessions.read().await;
         sessions.len()
     }
     
-    /// Check if a session exists and is valid
-    #[allow(dead_code)]
-    pub async fn is_valid(&self, session_id: u64) -> bool {
+    /// Check if a session exists, belongs to `client_id` and is valid
+    pub async fn is_valid(&self, session_id: u64, client_id: u64) -> bool {
         let sessions = self.sessions.read().await;
         if let Some(session) = sessions.get(&session_id) {
             let now = Instant::now();
-            now.duration_since(session.last_seen) < self.session_timeout
+            session.client_id == client_id
+                && now.duration_since(session.last_seen) < self.session_timeout
         } else {
             false
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn manager(max_clients: usize, policy: AdmissionPolicy) -> SessionManager {
+        SessionManager::new(60, Admission { max_clients, policy })
+    }
+    
+    #[tokio::test]
+    async fn test_admission_rejects_or_evicts() {
+        let addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        
+        let sessions = manager(2, AdmissionPolicy::Reject);
+        sessions.create_session(1, 100, [0; 32], addr).await.unwrap();
+        sessions.create_session(2, 200, [0; 32], addr).await.unwrap();
+        assert!(sessions.create_session(3, 300, [0; 32], addr).await.is_err());
+        
+        // Known clients may always re-knock, which replaces their session
+        sessions.create_session(4, 100, [0; 32], addr).await.unwrap();
+        assert_eq!(sessions.active_sessions().await, 2);
+        assert!(!sessions.is_valid(1, 100).await);
+        assert!(sessions.is_valid(4, 100).await);
+        
+        // Sessions of another interface of the same client are kept
+        let other: SocketAddr = "198.51.100.1:5000".parse().unwrap();
+        sessions.create_session(5, 100, [0; 32], other).await.unwrap();
+        assert!(sessions.is_valid(4, 100).await);
+        assert!(sessions.is_valid(5, 100).await);
+        
+        let sessions = manager(2, AdmissionPolicy::EvictLru);
+        sessions.create_session(1, 100, [0; 32], addr).await.unwrap();
+        sessions.create_session(2, 200, [0; 32], addr).await.unwrap();
+        sessions.update_last_seen(1).await;
+        sessions.create_session(3, 300, [0; 32], addr).await.unwrap();
+        assert!(sessions.is_valid(1, 100).await);
+        assert!(!sessions.is_valid(2, 200).await);
+        assert!(sessions.is_valid(3, 300).await);
+    }
+    
+    #[tokio::test]
+    async fn test_retain_clients() {
+        let addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let other: SocketAddr = "198.51.100.1:5000".parse().unwrap();
+        let sessions = manager(0, AdmissionPolicy::Reject);
+        sessions.create_session(1, 100, [0; 32], addr).await.unwrap();
+        sessions.create_session(2, 100, [0; 32], other).await.unwrap();
+        sessions.create_session(3, 200, [0; 32], addr).await.unwrap();
+        
+        assert_eq!(sessions.retain_clients(|client_id| client_id != 100).await, vec![100]);
+        assert_eq!(sessions.active_sessions().await, 1);
+        assert!(sessions.is_valid(3, 200).await);
+    }
+}